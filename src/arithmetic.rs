@@ -0,0 +1,229 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// flag bits of the flags hidden register
+pub const FLAG_ZERO: i32 = 1;
+pub const FLAG_NEGATIVE: i32 = 2;
+pub const FLAG_CARRY: i32 = 4;
+pub const FLAG_OVERFLOW: i32 = 8;
+
+/// what arithmetic opcodes do when the result does not fit in an i32
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ArithmeticMode {
+    /// the result wraps around (two's complement)
+    #[default]
+    Wrapping,
+
+    /// the result is clamped to i32::MIN or i32::MAX
+    Saturating,
+
+    /// the VM faults with ArithmeticOverflow
+    Trapping,
+}
+
+impl FromStr for ArithmeticMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "wrapping" => return Ok(ArithmeticMode::Wrapping),
+            "saturating" => return Ok(ArithmeticMode::Saturating),
+            "trapping" => return Ok(ArithmeticMode::Trapping),
+            _ => return Err(format!("unknown arithmetic mode `{}`", s)),
+        }
+    }
+}
+
+impl fmt::Display for ArithmeticMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArithmeticMode::Wrapping => write!(f, "wrapping"),
+            ArithmeticMode::Saturating => write!(f, "saturating"),
+            ArithmeticMode::Trapping => write!(f, "trapping"),
+        }
+    }
+}
+
+/// result of an arithmetic operation in every mode along with its carry and overflow
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Outcome {
+    pub wrapped: i32,
+    pub saturated: i32,
+
+    /// unsigned carry or borrow, only set by addition and subtraction
+    pub carry: bool,
+
+    /// the signed result does not fit in an i32
+    pub overflow: bool,
+}
+
+impl Outcome {
+    pub fn add(a: i32, b: i32) -> Outcome {
+        let (wrapped, overflow) = a.overflowing_add(b);
+        return Outcome {
+            wrapped,
+            saturated: a.saturating_add(b),
+            carry: (a as u32).overflowing_add(b as u32).1,
+            overflow,
+        };
+    }
+
+    pub fn sub(a: i32, b: i32) -> Outcome {
+        let (wrapped, overflow) = a.overflowing_sub(b);
+        return Outcome {
+            wrapped,
+            saturated: a.saturating_sub(b),
+            carry: (a as u32) < (b as u32),
+            overflow,
+        };
+    }
+
+    pub fn mul(a: i32, b: i32) -> Outcome {
+        let (wrapped, overflow) = a.overflowing_mul(b);
+        return Outcome {
+            wrapped,
+            saturated: a.saturating_mul(b),
+            carry: false,
+            overflow,
+        };
+    }
+
+    /// b must not be 0, i32::MIN / -1 is the only division that overflows
+    pub fn div(a: i32, b: i32) -> Outcome {
+        let (wrapped, overflow) = a.overflowing_div(b);
+        return Outcome {
+            wrapped,
+            saturated: a.saturating_div(b),
+            carry: false,
+            overflow,
+        };
+    }
+
+    /// b must not be 0, the remainder always fits so i32::MIN % -1 is 0 without overflowing
+    pub fn rem(a: i32, b: i32) -> Outcome {
+        return Outcome::exact(a.wrapping_rem(b));
+    }
+
+    pub fn neg(a: i32) -> Outcome {
+        let (wrapped, overflow) = a.overflowing_neg();
+        return Outcome {
+            wrapped,
+            saturated: a.saturating_neg(),
+            carry: false,
+            overflow,
+        };
+    }
+
+    pub fn abs(a: i32) -> Outcome {
+        let (wrapped, overflow) = a.overflowing_abs();
+        return Outcome {
+            wrapped,
+            saturated: a.saturating_abs(),
+            carry: false,
+            overflow,
+        };
+    }
+
+    /// shifts left, amounts of 32 or more (including negative amounts) shift every bit out
+    pub fn shl(a: i32, amount: i32) -> Outcome {
+        let shifted = (a as u32).checked_shl(amount as u32).unwrap_or(0);
+        return Outcome::exact(shifted as i32);
+    }
+
+    /// shifts right filling with zeros, amounts of 32 or more shift every bit out
+    pub fn shr(a: i32, amount: i32) -> Outcome {
+        let shifted = (a as u32).checked_shr(amount as u32).unwrap_or(0);
+        return Outcome::exact(shifted as i32);
+    }
+
+    /// shifts right filling with the sign bit, amounts of 32 or more leave only the sign
+    pub fn sar(a: i32, amount: i32) -> Outcome {
+        let amount = (amount as u32).min(31);
+        return Outcome::exact(a >> amount);
+    }
+
+    /// a result that can't overflow
+    pub fn exact(value: i32) -> Outcome {
+        return Outcome {
+            wrapped: value,
+            saturated: value,
+            carry: false,
+            overflow: false,
+        };
+    }
+
+    /// returns the result in the given mode, None if the mode traps and the result overflowed
+    pub fn result(&self, mode: ArithmeticMode) -> Option<i32> {
+        match mode {
+            ArithmeticMode::Wrapping => return Some(self.wrapped),
+            ArithmeticMode::Saturating => return Some(self.saturated),
+            ArithmeticMode::Trapping if self.overflow => return None,
+            ArithmeticMode::Trapping => return Some(self.wrapped),
+        }
+    }
+
+    /// returns the flags for the result that was written
+    pub fn flags(&self, result: i32) -> i32 {
+        let mut flags = 0;
+        if result == 0 {
+            flags |= FLAG_ZERO;
+        }
+        if result < 0 {
+            flags |= FLAG_NEGATIVE;
+        }
+        if self.carry {
+            flags |= FLAG_CARRY;
+        }
+        if self.overflow {
+            flags |= FLAG_OVERFLOW;
+        }
+        return flags;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_outcome_modes() {
+        let outcome = Outcome::add(i32::MAX, 1);
+        assert_eq!(outcome.result(ArithmeticMode::Wrapping), Some(i32::MIN));
+        assert_eq!(outcome.result(ArithmeticMode::Saturating), Some(i32::MAX));
+        assert_eq!(outcome.result(ArithmeticMode::Trapping), None);
+        assert_eq!(outcome.flags(i32::MIN), FLAG_NEGATIVE | FLAG_OVERFLOW);
+
+        let outcome = Outcome::div(i32::MIN, -1);
+        assert_eq!(outcome.result(ArithmeticMode::Saturating), Some(i32::MAX));
+        assert!(outcome.overflow);
+        assert_eq!(Outcome::rem(i32::MIN, -1).result(ArithmeticMode::Trapping), Some(0));
+    }
+
+    #[test]
+    fn test_outcome_carry() {
+        let outcome = Outcome::add(-1, 1);
+        assert_eq!(outcome.flags(outcome.wrapped), FLAG_ZERO | FLAG_CARRY);
+        let outcome = Outcome::sub(1, 2);
+        assert_eq!(outcome.flags(outcome.wrapped), FLAG_NEGATIVE | FLAG_CARRY);
+        let outcome = Outcome::sub(2, 1);
+        assert_eq!(outcome.flags(outcome.wrapped), 0);
+    }
+
+    #[test]
+    fn test_shifts() {
+        assert_eq!(Outcome::shl(1, 31).wrapped, i32::MIN);
+        assert_eq!(Outcome::shl(1, 32).wrapped, 0);
+        assert_eq!(Outcome::shr(-8, 1).wrapped, 0x7FFF_FFFC);
+        assert_eq!(Outcome::shr(-8, -1).wrapped, 0);
+        assert_eq!(Outcome::sar(-8, 1).wrapped, -4);
+        assert_eq!(Outcome::sar(-8, 40).wrapped, -1);
+        assert_eq!(Outcome::sar(8, 40).wrapped, 0);
+    }
+
+    #[test]
+    fn test_arithmetic_mode_from_str() {
+        assert_eq!("Saturating".parse(), Ok(ArithmeticMode::Saturating));
+        assert_eq!(ArithmeticMode::Trapping.to_string(), "trapping");
+        assert!("checked".parse::<ArithmeticMode>().is_err());
+    }
+}
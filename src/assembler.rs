@@ -0,0 +1,738 @@
+use crate::image::{DebugInfo, Image};
+use crate::instructions::{
+    Opcode, Operand, INSTRUCTION_SIZE, MODE_DIRECT, MODE_IMMEDIATE, MODE_INDIRECT, TARGET_LABEL,
+    TARGET_SET_INDEX,
+};
+use std::collections::HashMap;
+use std::fmt;
+
+/// an error found while assembling, positioned at the offending token
+#[derive(Debug, Clone, PartialEq)]
+pub struct AssembleError {
+    /// line of the error, starting from 1
+    pub line: usize,
+
+    /// column of the error, starting from 1
+    pub column: usize,
+
+    pub message: String,
+}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl std::error::Error for AssembleError {}
+
+#[derive(Debug, Clone, Copy)]
+struct Token<'a> {
+    text: &'a str,
+    line: usize,
+    column: usize,
+}
+
+impl<'a> Token<'a> {
+    fn error<T>(&self, message: String) -> Result<T, AssembleError> {
+        return Err(AssembleError {
+            line: self.line,
+            column: self.column,
+            message,
+        });
+    }
+}
+
+/// directives that add to the data section
+const DATA_DIRECTIVES: [&str; 2] = [".string", ".space"];
+
+/// a single instruction along with the tokens it was parsed from
+struct Statement<'a> {
+    opcode: Opcode,
+    mnemonic: Token<'a>,
+    operands: Vec<Token<'a>>,
+}
+
+/// symbols collected by the first pass
+#[derive(Default)]
+struct Symbols<'a> {
+    /// set index of every `name:` definition and symbolic LABEL
+    addresses: HashMap<&'a str, i32>,
+
+    /// label id assigned to every symbolic LABEL
+    label_ids: HashMap<&'a str, u8>,
+
+    /// length in bytes of every named data directive, read as `name.len`
+    lengths: HashMap<&'a str, i32>,
+}
+
+/// assembles PASM source into perling byte code
+///
+/// Every instruction is emitted as a 4 byte slot of opcode followed by its operand bytes, padded
+/// with zeros, the 32 bit operand of LOADW is emitted as a second slot. Operands can be numbers
+/// (`12`, `0x0c`, `0b1100`), registers (`r12`) or symbols. `name:` defines a symbol holding the
+/// set index of the next instruction and `LABEL name` allocates a label id for `name` that GOTO
+/// can refer to. `#` and `;` start a comment. Register operands read through the register they
+/// name unless they are written as an immediate (`$5`) or a direct register (`%r5`), which emits
+/// a MODE slot in front.
+pub fn assemble(source: &str) -> Result<Vec<u8>, AssembleError> {
+    return Ok(assemble_image(source)?.code);
+}
+
+/// assembles PASM source into an image, `.entry <symbol|set index>` sets its entry point and
+/// `.registers <count>` the number of registers it needs
+pub fn assemble_image(source: &str) -> Result<Image, AssembleError> {
+    let mut image = assemble_with_debug_info(source, "")?;
+    image.debug_info = None;
+    return Ok(image);
+}
+
+/// assembles PASM source into an image holding the line of every instruction, source_path is
+/// stored as the path of the source so debuggers can show it
+pub fn assemble_with_debug_info(source: &str, source_path: &str) -> Result<Image, AssembleError> {
+    let mut statements = vec![];
+    let mut symbols = Symbols::default();
+    let mut set_index = 0;
+    let mut entry: Option<Token> = None;
+    let mut register_count: Option<Token> = None;
+    let mut data = vec![];
+    let mut lines = vec![];
+
+    // first pass, collects instructions and data and the address of every symbol
+    for (line_index, line) in source.lines().enumerate() {
+        let mut tokens = tokenize(line, line_index + 1)?;
+        let mut names = vec![];
+        while !tokens.is_empty() && tokens[0].text.ends_with(':') {
+            let token = tokens.remove(0);
+            let name = &token.text[..token.text.len() - 1];
+            check_symbol_name(&token, name)?;
+            names.push((token, name));
+        }
+        // names in front of data are bound to its address in the data section
+        let is_data = matches!(tokens.first(), Some(token) if DATA_DIRECTIVES.contains(&token.text));
+        let address = if is_data { data.len() as i32 } else { set_index };
+        for (token, name) in names.iter() {
+            if symbols.addresses.insert(name, address).is_some() {
+                return token.error(format!("symbol `{}` is defined more than once", name));
+            }
+        }
+        if tokens.is_empty() {
+            continue;
+        }
+        if tokens[0].text.starts_with('.') {
+            let directive = tokens.remove(0);
+            match directive.text {
+                ".entry" => {
+                    if tokens.len() != 1 {
+                        return directive.error(".entry takes a single operand".to_string());
+                    }
+                    if entry.is_some() {
+                        return directive.error(".entry is given more than once".to_string());
+                    }
+                    entry = Some(tokens[0]);
+                }
+                ".registers" => {
+                    if tokens.len() != 1 {
+                        return directive.error(".registers takes a single operand".to_string());
+                    }
+                    if register_count.is_some() {
+                        return directive.error(".registers is given more than once".to_string());
+                    }
+                    register_count = Some(tokens[0]);
+                }
+                ".string" | ".space" => {
+                    if tokens.len() != 1 {
+                        return directive.error(format!("{} takes a single operand", directive.text));
+                    }
+                    let bytes = match directive.text {
+                        ".string" => parse_string(&tokens[0])?,
+                        _ => match parse_number(tokens[0].text) {
+                            Some(size) if (0..=u16::MAX as i64).contains(&size) => vec![0; size as usize],
+                            _ => return tokens[0].error(format!("invalid size `{}`", tokens[0].text)),
+                        },
+                    };
+                    for (_, name) in names.iter() {
+                        symbols.lengths.insert(name, bytes.len() as i32);
+                    }
+                    data.extend_from_slice(&bytes);
+                }
+                _ => return directive.error(format!("unknown directive `{}`", directive.text)),
+            }
+            continue;
+        }
+
+        let mnemonic = tokens.remove(0);
+        let opcode = match Opcode::from_mnemonic(mnemonic.text) {
+            Some(opcode) => opcode,
+            None => return mnemonic.error(format!("unknown mnemonic `{}`", mnemonic.text)),
+        };
+        if opcode == Opcode::LABEL && tokens.len() == 1 && parse_number(tokens[0].text).is_none() {
+            let name = tokens[0].text;
+            check_symbol_name(&tokens[0], name)?;
+            match symbols.addresses.get(name) {
+                Some(&address) if address != set_index => {
+                    return tokens[0].error(format!("symbol `{}` is defined more than once", name));
+                }
+                _ => symbols.addresses.insert(name, set_index),
+            };
+        }
+        lines.push((set_index, line_index as u32 + 1));
+        // operands with an addressing mode need a MODE slot in front of the instruction
+        if tokens.iter().any(|token| split_mode(token).0 != MODE_INDIRECT) {
+            set_index += 1;
+        }
+        statements.push(Statement {
+            opcode,
+            mnemonic,
+            operands: tokens,
+        });
+        set_index += opcode.slots() as i32;
+    }
+    allocate_label_ids(&statements, &mut symbols)?;
+
+    // second pass, encodes the instructions now that every symbol is known
+    let mut program = Vec::with_capacity(statements.len() * INSTRUCTION_SIZE);
+    for statement in statements.iter() {
+        encode_statement(statement, &symbols, &mut program)?;
+    }
+    let entry = match entry {
+        Some(token) => fit_in_bits(&token, resolve_value(&token, &symbols)?, 31)? as i32,
+        None => 0,
+    };
+    let register_count = match register_count {
+        Some(token) => match resolve_value(&token, &symbols)? {
+            count @ 1..=0xFFFF => count as u16,
+            count => return token.error(format!("invalid register count {}", count)),
+        },
+        None => 0,
+    };
+    return Ok(Image {
+        code: program,
+        data,
+        entry,
+        register_count,
+        debug_info: Some(DebugInfo {
+            source: source_path.to_string(),
+            lines,
+        }),
+        ..Image::default()
+    });
+}
+
+/// splits a line into tokens, dropping comments. A token starting with `"` runs to the closing
+/// quote so strings can hold spaces, commas and comment characters
+fn tokenize(line: &str, line_number: usize) -> Result<Vec<Token<'_>>, AssembleError> {
+    let mut tokens = vec![];
+    let mut start = None;
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut end = line.len();
+    let token = |token_start: usize, token_end: usize| Token {
+        text: &line[token_start..token_end],
+        line: line_number,
+        column: line[..token_start].chars().count() + 1,
+    };
+    for (index, c) in line.char_indices() {
+        if in_string {
+            match (escaped, c) {
+                (true, _) => escaped = false,
+                (false, '\\') => escaped = true,
+                (false, '"') => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        if c == '#' || c == ';' {
+            end = index;
+            break;
+        }
+        let is_separator = c.is_whitespace() || c == ',';
+        match (start, is_separator) {
+            (None, false) => {
+                start = Some(index);
+                in_string = c == '"';
+            }
+            (Some(token_start), true) => {
+                tokens.push(token(token_start, index));
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(token_start) = start {
+        if in_string {
+            return token(token_start, end).error("unterminated string".to_string());
+        }
+        tokens.push(token(token_start, end));
+    }
+    return Ok(tokens);
+}
+
+/// parses a string literal into its UTF-8 bytes, supporting the escapes
+/// `\n`, `\r`, `\t`, `\0`, `\\` and `\"`
+fn parse_string(token: &Token) -> Result<Vec<u8>, AssembleError> {
+    let text = token.text;
+    if text.len() < 2 || !text.starts_with('"') || !text.ends_with('"') {
+        return token.error(format!("expected a string, found `{}`", text));
+    }
+    let mut bytes = vec![];
+    let mut chars = text[1..text.len() - 1].chars();
+    while let Some(c) = chars.next() {
+        let c = match c {
+            '\\' => match chars.next() {
+                Some('n') => '\n',
+                Some('r') => '\r',
+                Some('t') => '\t',
+                Some('0') => '\0',
+                Some('\\') => '\\',
+                Some('"') => '"',
+                Some(other) => return token.error(format!("unknown escape `\\{}`", other)),
+                None => return token.error("unterminated string".to_string()),
+            },
+            c => c,
+        };
+        let mut buffer = [0; 4];
+        bytes.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes());
+    }
+    return Ok(bytes);
+}
+
+/// checks that a symbol name is an identifier that can't be mistaken for a register or number
+fn check_symbol_name(token: &Token, name: &str) -> Result<(), AssembleError> {
+    let valid = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid || parse_register(name).is_some() {
+        return token.error(format!("`{}` is not a valid symbol name", name));
+    }
+    return Ok(());
+}
+
+/// gives every symbolic LABEL an id that is not used by a numeric LABEL
+fn allocate_label_ids<'a>(
+    statements: &[Statement<'a>],
+    symbols: &mut Symbols<'a>,
+) -> Result<(), AssembleError> {
+    let labels = statements.iter().filter(|s| s.opcode == Opcode::LABEL && s.operands.len() == 1);
+    let used: Vec<i64> = labels.clone().filter_map(|s| parse_number(s.operands[0].text)).collect();
+    let mut next_id: i64 = 1;
+    for statement in labels {
+        let token = &statement.operands[0];
+        if parse_number(token.text).is_some() {
+            continue;
+        }
+        if symbols.label_ids.contains_key(token.text) {
+            return token.error(format!("label `{}` is declared more than once", token.text));
+        }
+        while used.contains(&next_id) {
+            next_id += 1;
+        }
+        if next_id > u8::MAX as i64 {
+            return token.error("too many labels, label ids must fit in 8 bits".to_string());
+        }
+        symbols.label_ids.insert(token.text, next_id as u8);
+        next_id += 1;
+    }
+    return Ok(());
+}
+
+/// appends the slots of a statement to the program
+fn encode_statement(
+    statement: &Statement,
+    symbols: &Symbols,
+    program: &mut Vec<u8>,
+) -> Result<(), AssembleError> {
+    let layout = statement.opcode.operands();
+    let byte_width: usize = layout.iter().map(|operand| operand.width()).sum();
+    // the kind byte of a jump target is not written, it is derived from the target
+    let written: Vec<Operand> =
+        layout.iter().copied().filter(|operand| *operand != Operand::TargetKind).collect();
+    let has_target = written.len() != layout.len();
+    let operands = &statement.operands;
+    let mut modes = [MODE_INDIRECT; 3];
+    let mut tokens = Vec::with_capacity(operands.len());
+    if operands.len() == written.len() {
+        let mut position = 1;
+        let mut remaining = operands.iter();
+        for operand in layout.iter() {
+            if *operand != Operand::TargetKind && *operand != Operand::Long {
+                let token = remaining.next().unwrap();
+                let (mode, inner) = split_mode(token);
+                if !statement.opcode.supports_mode(position, mode) {
+                    return token.error(format!(
+                        "operand {} of {} can't be written as `{}`",
+                        tokens.len() + 1,
+                        statement.opcode,
+                        token.text
+                    ));
+                }
+                modes[position - 1] = mode;
+                tokens.push((inner, mode));
+            } else if *operand == Operand::Long {
+                tokens.push((*remaining.next().unwrap(), MODE_INDIRECT));
+            }
+            position += operand.width();
+        }
+    }
+    if modes != [MODE_INDIRECT; 3] {
+        program.push(Opcode::MODE.code());
+        program.extend_from_slice(&modes);
+    }
+    let start = program.len();
+    program.push(statement.opcode.code());
+
+    if operands.len() == written.len() {
+        for ((token, mode), operand) in tokens.iter().zip(written.iter()) {
+            if *mode == MODE_IMMEDIATE {
+                let value = resolve_value(token, symbols)?;
+                program.push(fit_in_bits(token, value, 8)? as u8);
+                continue;
+            }
+            if *operand == Operand::Long && has_target {
+                let (kind, target) = resolve_target(token, symbols)?;
+                program.push(kind);
+                program.resize(start + INSTRUCTION_SIZE, 0);
+                program.extend_from_slice(&target.to_be_bytes());
+                continue;
+            }
+            if *operand == Operand::Long {
+                program.resize(start + INSTRUCTION_SIZE, 0);
+            }
+            encode_operand(token, *operand, symbols, program)?;
+        }
+    } else if operands.len() == byte_width && statement.opcode.slots() == 1 {
+        // raw form, every operand is written as a single byte (e.g. `LOAD 01 00 02`)
+        for token in operands.iter() {
+            let value = resolve_value(token, symbols)?;
+            program.push(fit_in_bits(token, value, 8)? as u8);
+        }
+    } else {
+        return statement.mnemonic.error(format!(
+            "{} takes {} operand(s) but {} were given",
+            statement.opcode,
+            written.len(),
+            operands.len()
+        ));
+    }
+    program.resize(start + statement.opcode.slots() * INSTRUCTION_SIZE, 0);
+    return Ok(());
+}
+
+/// appends the encoded bytes of a single operand
+fn encode_operand(
+    token: &Token,
+    operand: Operand,
+    symbols: &Symbols,
+    program: &mut Vec<u8>,
+) -> Result<(), AssembleError> {
+    match operand {
+        Operand::Indirect | Operand::Pointer | Operand::Register => {
+            let register = match parse_register(token.text).or_else(|| parse_number(token.text)) {
+                Some(register) => register,
+                None => return token.error(format!("expected a register, found `{}`", token.text)),
+            };
+            program.push(fit_in_bits(token, register, 8)? as u8);
+        }
+        Operand::Byte | Operand::TargetKind => {
+            let value = resolve_value(token, symbols)?;
+            program.push(fit_in_bits(token, value, 8)? as u8);
+        }
+        Operand::Word => {
+            let value = fit_in_bits(token, resolve_value(token, symbols)?, 16)?;
+            program.push((value >> 8) as u8);
+            program.push(value as u8);
+        }
+        Operand::Label => {
+            let id = match parse_number(token.text) {
+                Some(id) => id,
+                None => match symbols.label_ids.get(token.text) {
+                    Some(&id) => id as i64,
+                    None => return token.error(format!("unknown label `{}`", token.text)),
+                },
+            };
+            program.push(fit_in_bits(token, id, 8)? as u8);
+        }
+        Operand::Long => {
+            let value = resolve_value(token, symbols)?;
+            if value < i32::MIN as i64 || value > u32::MAX as i64 {
+                return token.error(format!("value {} does not fit in 32 bits", value));
+            }
+            program.extend_from_slice(&(value as u32).to_be_bytes());
+        }
+    }
+    return Ok(());
+}
+
+/// splits the addressing mode prefix off an operand, `$` is immediate and `%` is direct
+fn split_mode<'a>(token: &Token<'a>) -> (u8, Token<'a>) {
+    let (mode, text) = if let Some(text) = token.text.strip_prefix('$') {
+        (MODE_IMMEDIATE, text)
+    } else if let Some(text) = token.text.strip_prefix('%') {
+        (MODE_DIRECT, text)
+    } else {
+        return (MODE_INDIRECT, *token);
+    };
+    let inner = Token {
+        text,
+        line: token.line,
+        column: token.column + 1,
+    };
+    return (mode, inner);
+}
+
+/// resolves a number or a symbol to its value
+fn resolve_value(token: &Token, symbols: &Symbols) -> Result<i64, AssembleError> {
+    if let Some(value) = parse_number(token.text) {
+        return Ok(value);
+    }
+    if let Some(name) = token.text.strip_suffix(".len") {
+        if let Some(&length) = symbols.lengths.get(name) {
+            return Ok(length as i64);
+        }
+    }
+    match symbols.addresses.get(token.text) {
+        Some(&address) => return Ok(address as i64),
+        None => return token.error(format!("unknown symbol `{}`", token.text)),
+    }
+}
+
+/// resolves a jump target to its kind byte and value. `@id` and symbolic labels are label ids,
+/// numbers and other symbols are set indices
+fn resolve_target(token: &Token, symbols: &Symbols) -> Result<(u8, i32), AssembleError> {
+    if let Some(label) = token.text.strip_prefix('@') {
+        let id = match parse_number(label) {
+            Some(id) => id,
+            None => match symbols.label_ids.get(label) {
+                Some(&id) => id as i64,
+                None => return token.error(format!("unknown label `{}`", label)),
+            },
+        };
+        return Ok((TARGET_LABEL, fit_in_bits(token, id, 8)? as i32));
+    }
+    if let Some(&id) = symbols.label_ids.get(token.text) {
+        return Ok((TARGET_LABEL, id as i32));
+    }
+    let set_index = fit_in_bits(token, resolve_value(token, symbols)?, 31)?;
+    return Ok((TARGET_SET_INDEX, set_index as i32));
+}
+
+/// checks that an unsigned value fits in the given number of bits
+fn fit_in_bits(token: &Token, value: i64, bits: u32) -> Result<i64, AssembleError> {
+    if value < 0 || value >= (1 << bits) {
+        return token.error(format!("value {} does not fit in {} bits", value, bits));
+    }
+    return Ok(value);
+}
+
+/// parses a register name such as `r3` or `R12` into its index
+fn parse_register(text: &str) -> Option<i64> {
+    let index = text.strip_prefix('r').or_else(|| text.strip_prefix('R'))?;
+    if index.is_empty() || !index.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    return index.parse::<i64>().ok();
+}
+
+/// parses a decimal, `0x` hexadecimal or `0b` binary number
+fn parse_number(text: &str) -> Option<i64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, text),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if let Some(bin) = digits.strip_prefix("0b").or_else(|| digits.strip_prefix("0B")) {
+        i64::from_str_radix(bin, 2).ok()?
+    } else if !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit()) {
+        digits.parse::<i64>().ok()?
+    } else {
+        return None;
+    };
+    return Some(if negative { -value } else { value });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_assemble_print_example() {
+        let source = include_str!("../examples/print.pasm");
+        let expected = include_bytes!("../examples/print.perling.bin");
+        assert_eq!(assemble_image(source).unwrap().encode().unwrap(), expected.to_vec());
+    }
+
+    #[test]
+    fn test_assemble_raw_form() {
+        let program = assemble("LOAD 01 00 02 # load 2 to register 1\nADD 02 02 04").unwrap();
+        assert_eq!(program, vec![1, 1, 0, 2, 2, 2, 2, 4]);
+    }
+
+    #[test]
+    fn test_assemble_operands() {
+        let program = assemble("load r1, 0x1F4\n; comment only\n\nget R2 r3\nhlt").unwrap();
+        assert_eq!(program, vec![1, 1, 1, 244, 17, 2, 3, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_assemble_symbols() {
+        let source = "LOAD r0 end\nstart: LABEL loop\nGOTO loop\nLABEL 1\nend:\nGOTO 1\nLOAD r1 start";
+        let program = assemble(source).unwrap();
+        assert_eq!(
+            program,
+            vec![1, 0, 0, 4, 22, 2, 0, 0, 23, 2, 0, 0, 22, 1, 0, 0, 23, 1, 0, 0, 1, 1, 0, 1]
+        );
+    }
+
+    #[test]
+    fn test_assemble_wide_load() {
+        let program = assemble("LOADW r1 -2\nnext: LOADW r2 0xFFFFFFFF\nLOAD r3 next").unwrap();
+        assert_eq!(
+            program,
+            vec![24, 1, 0, 0, 255, 255, 255, 254, 24, 2, 0, 0, 255, 255, 255, 255, 1, 3, 0, 2]
+        );
+        let err = assemble("LOADW r1 0x100000000").unwrap_err();
+        assert_eq!(err.message, "value 4294967296 does not fit in 32 bits");
+    }
+
+    #[test]
+    fn test_assemble_branches() {
+        let program = assemble("top: BLT r1 r2 top\nLABEL end\nBGEU r1 r2 end\nBNE r1 r2 @9").unwrap();
+        assert_eq!(
+            program,
+            vec![
+                48, 1, 2, 0, 0, 0, 0, 0, 22, 1, 0, 0, 55, 1, 2, 1, 0, 0, 0, 1, 47, 1, 2, 1, 0, 0, 0, 9
+            ]
+        );
+        let err = assemble("BEQ r1 r2 @nowhere").unwrap_err();
+        assert_eq!(err.message, "unknown label `nowhere`");
+    }
+
+    #[test]
+    fn test_assemble_call() {
+        let source = "CALL twice\nCALL @7\nHLT\ntwice: RET";
+        assert_eq!(
+            assemble(source).unwrap(),
+            vec![56, 0, 0, 0, 0, 0, 0, 5, 56, 1, 0, 0, 0, 0, 0, 7, 0, 0, 0, 0, 57, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn test_assemble_counting_loop() {
+        // adds 1 to r10 until it reaches 5
+        let source = "LOAD r0 0\nLOAD r1 10\nLOAD r2 11\nLOAD r11 5\n\
+                      LABEL loop\nINC r1\nBLT r1 r2 loop";
+        let mut vm = crate::vm::VM::with_program(assemble(source).unwrap());
+        vm.run().unwrap();
+        assert_eq!(vm.registers[10].content, 5);
+    }
+
+    #[test]
+    fn test_assemble_data() {
+        let source = r#"msg: .string "a, b # \"c\"\n" ; comment
+                        buf: .space 3
+                        LOAD r1 buf
+                        LOAD r2 msg.len"#;
+        let image = assemble_image(source).unwrap();
+        assert_eq!(image.data, b"a, b # \"c\"\n\0\0\0".to_vec());
+        assert_eq!(image.code, vec![1, 1, 0, 11, 1, 2, 0, 11]);
+
+        let err = assemble_image(".string \"open").unwrap_err();
+        assert_eq!((err.column, err.message.as_str()), (9, "unterminated string"));
+        let err = assemble_image(".string \"\\q\"").unwrap_err();
+        assert_eq!(err.message, "unknown escape `\\q`");
+        let err = assemble_image(".string 5").unwrap_err();
+        assert_eq!(err.message, "expected a string, found `5`");
+    }
+
+    #[test]
+    fn test_hello_example() {
+        let image = assemble_image(include_str!("../examples/hello.pasm")).unwrap();
+        let output = crate::streams::SharedBuffer::default();
+        let mut vm = crate::vm::VM::new();
+        vm.set_stdout(output.clone());
+        vm.load_image(&image).unwrap();
+        vm.run().unwrap();
+        assert_eq!(output.to_string_lossy(), "hello, world\n411\n");
+    }
+
+    #[test]
+    fn test_assemble_forward_goto() {
+        let source = "GOTO skip\nLOAD r0 5\nLABEL skip\nLOAD r1 7";
+        let mut vm = crate::vm::VM::with_program(assemble(source).unwrap());
+        vm.run().unwrap();
+        assert_eq!(vm.registers[0].content, 0);
+        assert_eq!(vm.registers[1].content, 7);
+    }
+
+    #[test]
+    fn test_assemble_image_entry() {
+        let image = assemble_image("HLT\nmain: LOAD r1 1\n.entry main").unwrap();
+        assert_eq!(image.entry, 1);
+        assert_eq!(image.code.len(), 8);
+        assert_eq!(assemble_image("HLT").unwrap().entry, 0);
+
+        let err = assemble_image(".entry 0\n.entry 1").unwrap_err();
+        assert_eq!((err.line, err.column), (2, 1));
+        let image = assemble_image(".registers 64\nHLT").unwrap();
+        assert_eq!(image.register_count, 64);
+        assert_eq!(image.debug_info, None);
+        let source = "start:\n  LOAD r1 1\n\nADD $1 %r1 %r1\nLOADW r2 70000\nHLT";
+        let debug_info = assemble_with_debug_info(source, "start.pasm").unwrap().debug_info.unwrap();
+        assert_eq!(debug_info.source, "start.pasm");
+        assert_eq!(debug_info.lines, vec![(0, 2), (1, 4), (3, 5), (5, 6)]);
+        let err = assemble_image(".registers 0").unwrap_err();
+        assert_eq!(err.message, "invalid register count 0");
+
+        let err = assemble_image(".start 0").unwrap_err();
+        assert_eq!(err.message, "unknown directive `.start`");
+    }
+
+    #[test]
+    fn test_assemble_addressing_modes() {
+        let source = "ADD $5, %r1, %r2\nSUB r1 r2 r3\nBLT %r1 $10 end\nend: HLT";
+        let program = assemble(source).unwrap();
+        assert_eq!(
+            program,
+            vec![
+                64, 1, 2, 2, 2, 5, 1, 2, 3, 1, 2, 3, 64, 2, 1, 0, 48, 1, 10, 0, 0, 0, 0, 6, 0, 0, 0,
+                0
+            ]
+        );
+        let mut vm = crate::vm::VM::with_program(assemble("LOAD r1 7\nADD $5 %r1 %r2").unwrap());
+        vm.run().unwrap();
+        assert_eq!(vm.registers[2].content, 12);
+
+        let err = assemble("ADD r1 r2 $3").unwrap_err();
+        assert_eq!(err.message, "operand 3 of ADD can't be written as `$3`");
+        assert_eq!(err.column, 11);
+        let err = assemble("LOAD %r1 5").unwrap_err();
+        assert_eq!(err.message, "operand 1 of LOAD can't be written as `%r1`");
+        let err = assemble("ADD $256 r1 r2").unwrap_err();
+        assert_eq!((err.column, err.message.as_str()), (6, "value 256 does not fit in 8 bits"));
+    }
+
+    #[test]
+    fn test_assemble_errors() {
+        let err = assemble("LOAD r1 1\n  MOVE r1 r2").unwrap_err();
+        assert_eq!((err.line, err.column), (2, 3));
+        assert_eq!(err.to_string(), "2:3: unknown mnemonic `MOVE`");
+
+        let err = assemble("LOAD r1 70000").unwrap_err();
+        assert_eq!((err.line, err.column), (1, 9));
+
+        let err = assemble("ADD r1 r2").unwrap_err();
+        assert_eq!(err.message, "ADD takes 3 operand(s) but 2 were given");
+
+        let err = assemble("LOAD r1 nowhere").unwrap_err();
+        assert_eq!(err.message, "unknown symbol `nowhere`");
+
+        let err = assemble("GOTO nowhere").unwrap_err();
+        assert_eq!(err.message, "unknown label `nowhere`");
+
+        let err = assemble("a: HLT\na: HLT").unwrap_err();
+        assert_eq!((err.line, err.column), (2, 1));
+
+        let err = assemble("SWP x r1").unwrap_err();
+        assert_eq!(err.message, "expected a register, found `x`");
+    }
+}
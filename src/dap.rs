@@ -0,0 +1,1017 @@
+use crate::debug::{Action, DebugEngine, StopReason};
+use crate::disassembler::disassemble;
+use crate::error::VmError;
+use crate::image::{DebugInfo, Image};
+use crate::instructions::INSTRUCTION_SIZE;
+use crate::streams::SharedBuffer;
+use crate::vm::VM;
+use crate::watch::{parse_register, parse_value, Condition};
+use serde_json::{json, Value};
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+/// number of instructions executed between checks for a pause request and program output
+const REQUEST_CHECK_INTERVAL: u64 = 1024;
+
+/// the program runs on a single thread
+const THREAD_ID: i64 = 1;
+
+/// variablesReference of every scope
+const REGISTERS_REFERENCE: i64 = 1;
+const HIDDEN_REFERENCE: i64 = 2;
+const STACK_REFERENCE: i64 = 3;
+
+/// largest message accepted from the client, requests are a few hundred bytes
+const MAX_MESSAGE_SIZE: usize = 4 * 1024 * 1024;
+
+/// sourceReference of the disassembly shown for programs without debug info
+const DISASSEMBLY_REFERENCE: i64 = 1;
+
+/// how far execution goes before it stops again
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Resume {
+    Continue,
+
+    /// stepIn, a single instruction
+    Step,
+
+    /// next, a single instruction or a whole CALL
+    Next,
+
+    /// stepOut, until the current CALL returns
+    Out,
+}
+
+/// what happens once the response to a request was sent
+enum After {
+    Nothing,
+    Initialized,
+    Stopped(&'static str),
+    Resume(Resume),
+    Terminated,
+    Disconnect,
+}
+
+/// a program launched by the client
+struct Session {
+    vm: VM,
+    debugger: DebugEngine,
+
+    /// output of debugger commands run from the console
+    console: SharedBuffer,
+    stdout: SharedBuffer,
+    stderr: SharedBuffer,
+
+    debug_info: Option<DebugInfo>,
+
+    /// set index of every line of the disassembly
+    disassembly: Vec<i32>,
+
+    /// source reference (0 for the source file) every breakpoint set by the client is in
+    breakpoint_sources: HashMap<usize, i64>,
+
+    stop_on_entry: bool,
+
+    /// the fault the program stopped at, it can't run any further
+    fault: Option<VmError>,
+    exit_code: Option<i32>,
+}
+
+/// Debug Adapter Protocol server, lets editors launch and debug a perling bin file
+///
+/// Programs assembled with debug info are shown in their PASM source, other programs in their
+/// disassembly. The variables are the registers, hidden registers and stack, output of the
+/// program goes to the console, which also takes every command of the debugger (e.g. `watch r3`).
+pub struct DapServer<W: Write> {
+    out: W,
+    seq: i64,
+    session: Option<Session>,
+
+    /// requests received while the program was running
+    pending: VecDeque<Value>,
+}
+
+impl<W: Write> DapServer<W> {
+    pub fn new(out: W) -> DapServer<W> {
+        DapServer {
+            out,
+            seq: 1,
+            session: None,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// serves requests until the client disconnects. Returns the exit code of the program or
+    /// None if it did not finish
+    pub fn serve<R: BufRead + Send + 'static>(&mut self, input: R) -> io::Result<Option<i32>> {
+        let requests = spawn_reader(input);
+        loop {
+            let message = match self.pending.pop_front() {
+                Some(message) => message,
+                None => match requests.recv() {
+                    Ok(message) => message?,
+                    Err(_) => break,
+                },
+            };
+            if message["type"] != "request" {
+                continue;
+            }
+            if !self.handle_request(&message, &requests)? {
+                break;
+            }
+        }
+        return Ok(self.session.as_ref().and_then(|session| session.exit_code));
+    }
+
+    /// answers a request, returns false once the client disconnected
+    fn handle_request(
+        &mut self,
+        request: &Value,
+        requests: &Receiver<io::Result<Value>>,
+    ) -> io::Result<bool> {
+        let command = request["command"].as_str().unwrap_or_default();
+        let arguments = &request["arguments"];
+        let mut after = After::Nothing;
+        let result = match command {
+            "initialize" => Ok(json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsConditionalBreakpoints": true,
+                "supportsSetVariable": true,
+                "supportsTerminateRequest": true,
+            })),
+            "launch" => self.launch(arguments).map(|_| {
+                after = After::Initialized;
+                Value::Null
+            }),
+            "disconnect" => {
+                after = After::Disconnect;
+                Ok(Value::Null)
+            }
+            "terminate" => {
+                after = After::Terminated;
+                Ok(Value::Null)
+            }
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "main" }] })),
+            _ => match self.session.as_mut() {
+                Some(session) => session.handle_request(command, arguments, &mut after),
+                None => Err("no program was launched".to_string()),
+            },
+        };
+        self.respond(request, result)?;
+        match after {
+            After::Nothing => {}
+            After::Initialized => self.send_event("initialized", Value::Null)?,
+            After::Stopped(reason) => self.send_stopped(json!({ "reason": reason }))?,
+            After::Resume(resume) => self.resume(resume, requests)?,
+            After::Terminated => self.send_event("terminated", Value::Null)?,
+            After::Disconnect => return Ok(false),
+        }
+        return Ok(true);
+    }
+
+    /// loads the program given as `program`, `stopOnEntry` pauses it before its first instruction
+    fn launch(&mut self, arguments: &Value) -> Result<(), String> {
+        let program = match arguments["program"].as_str() {
+            Some(program) => program,
+            None => return Err("launch needs the path of a program".to_string()),
+        };
+        let bytes = fs_read(program)?;
+        let image =
+            Image::load(bytes, true).map_err(|e| format!("unable to load {}: {}", program, e))?;
+        let mut vm = VM::new();
+        vm.load_image(&image)
+            .map_err(|e| format!("unable to load {}: {}", program, e))?;
+        vm.resolve_labels().map_err(|e| e.to_string())?;
+        vm.debugger_attached = true;
+        let (stdout, stderr, console) = (
+            SharedBuffer::default(),
+            SharedBuffer::default(),
+            SharedBuffer::default(),
+        );
+        vm.set_stdout(stdout.clone());
+        vm.set_stderr(stderr.clone());
+        // stdin carries the protocol, so the program reads an empty input
+        vm.set_stdin(io::empty());
+        let disassembly = disassemble(&vm.program)
+            .iter()
+            .map(|instruction| instruction.set_index)
+            .collect();
+        self.session = Some(Session {
+            vm,
+            debugger: DebugEngine::with_output(console.clone()),
+            console,
+            stdout,
+            stderr,
+            debug_info: image.debug_info,
+            disassembly,
+            breakpoint_sources: HashMap::new(),
+            stop_on_entry: arguments["stopOnEntry"].as_bool().unwrap_or(false),
+            fault: None,
+            exit_code: None,
+        });
+        return Ok(());
+    }
+
+    /// runs the program and reports why it stopped, a pause request stops it early
+    fn resume(&mut self, resume: Resume, requests: &Receiver<io::Result<Value>>) -> io::Result<()> {
+        let mut executed: u64 = 0;
+        let depth = self.session.as_ref().unwrap().vm.call_stack.len();
+        let reason = loop {
+            let session = self.session.as_mut().unwrap();
+            let reason = session.debugger.step(&mut session.vm);
+            let finished = match resume {
+                Resume::Continue => false,
+                Resume::Step => true,
+                Resume::Next => session.vm.call_stack.len() <= depth,
+                Resume::Out => session.vm.call_stack.len() < depth,
+            };
+            if reason != StopReason::Step || finished {
+                break Some(reason);
+            }
+            executed += 1;
+            if !executed.is_multiple_of(REQUEST_CHECK_INTERVAL) {
+                continue;
+            }
+            self.send_output()?;
+            match requests.try_recv() {
+                Ok(Ok(request)) if request["command"] == "pause" => {
+                    self.respond(&request, Ok(Value::Null))?;
+                    break None;
+                }
+                // the program stops without an event, the client is going away
+                Ok(Ok(request))
+                    if request["command"] == "disconnect" || request["command"] == "terminate" =>
+                {
+                    self.pending.push_back(request);
+                    return self.send_output();
+                }
+                Ok(Ok(request)) => self.pending.push_back(request),
+                Ok(Err(e)) => return Err(e),
+                Err(_) => {}
+            }
+        };
+        self.send_output()?;
+        let session = self.session.as_mut().unwrap();
+        match reason {
+            None => return self.send_stopped(json!({ "reason": "pause" })),
+            Some(StopReason::Step) => return self.send_stopped(json!({ "reason": "step" })),
+            Some(StopReason::Breakpoint(id)) => {
+                return self
+                    .send_stopped(json!({ "reason": "breakpoint", "hitBreakpointIds": [id] }))
+            }
+            Some(StopReason::Watchpoint { id, old, new }) => {
+                let watchpoint = session
+                    .debugger
+                    .watchpoints
+                    .iter()
+                    .find(|w| w.id == id)
+                    .unwrap();
+                let text = format!(
+                    "watchpoint {} {}: {} -> {}",
+                    id,
+                    watchpoint.target,
+                    describe(old),
+                    describe(new)
+                );
+                return self
+                    .send_stopped(json!({ "reason": "data breakpoint", "description": text }));
+            }
+            Some(StopReason::Break) => {
+                return self.send_stopped(json!({ "reason": "breakpoint", "description": "BREAK" }))
+            }
+            Some(StopReason::Exit(code)) => {
+                session.exit_code = Some(code);
+                self.send_console(&format!("process exited with code: {}\n", code))?;
+                self.send_event("exited", json!({ "exitCode": code }))?;
+                return self.send_event("terminated", Value::Null);
+            }
+            Some(StopReason::Fault(e)) => {
+                let text = e.to_string();
+                session.fault = Some(e);
+                self.send_console(&format!("{}\n", text))?;
+                return self.send_stopped(json!({ "reason": "exception", "text": text }));
+            }
+        }
+    }
+
+    fn respond(&mut self, request: &Value, result: Result<Value, String>) -> io::Result<()> {
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": result.is_ok(),
+        });
+        match result {
+            Ok(Value::Null) => {}
+            Ok(body) => response["body"] = body,
+            Err(message) => response["message"] = Value::String(message),
+        }
+        return self.send(response);
+    }
+
+    fn send_event(&mut self, event: &str, body: Value) -> io::Result<()> {
+        let mut message = json!({ "type": "event", "event": event });
+        if !body.is_null() {
+            message["body"] = body;
+        }
+        return self.send(message);
+    }
+
+    fn send_stopped(&mut self, mut body: Value) -> io::Result<()> {
+        body["threadId"] = json!(THREAD_ID);
+        body["allThreadsStopped"] = json!(true);
+        return self.send_event("stopped", body);
+    }
+
+    fn send_console(&mut self, text: &str) -> io::Result<()> {
+        return self.send_event("output", json!({ "category": "console", "output": text }));
+    }
+
+    /// sends what the program wrote since the last call
+    fn send_output(&mut self) -> io::Result<()> {
+        let session = self.session.as_mut().unwrap();
+        let _ = session.vm.flush_streams();
+        let output = [
+            ("stdout", session.stdout.take()),
+            ("stderr", session.stderr.take()),
+        ];
+        for (category, bytes) in output.iter() {
+            if !bytes.is_empty() {
+                let text = String::from_utf8_lossy(bytes);
+                self.send_event("output", json!({ "category": category, "output": text }))?;
+            }
+        }
+        return Ok(());
+    }
+
+    fn send(&mut self, mut message: Value) -> io::Result<()> {
+        message["seq"] = json!(self.seq);
+        self.seq += 1;
+        let content = message.to_string();
+        write!(
+            self.out,
+            "Content-Length: {}\r\n\r\n{}",
+            content.len(),
+            content
+        )?;
+        return self.out.flush();
+    }
+}
+
+impl Session {
+    /// answers a request about the launched program
+    fn handle_request(
+        &mut self,
+        command: &str,
+        arguments: &Value,
+        after: &mut After,
+    ) -> Result<Value, String> {
+        match command {
+            "setBreakpoints" => return Ok(self.set_breakpoints(arguments)),
+            "setExceptionBreakpoints" => return Ok(Value::Null),
+            "configurationDone" => {
+                *after = match self.stop_on_entry {
+                    true => After::Stopped("entry"),
+                    false => After::Resume(Resume::Continue),
+                };
+                return Ok(Value::Null);
+            }
+            "stackTrace" => return Ok(self.stack_trace()),
+            "scopes" => {
+                let scope = |name: &str, reference: i64| json!({ "name": name, "variablesReference": reference, "expensive": false });
+                return Ok(json!({
+                    "scopes": [
+                        scope("Registers", REGISTERS_REFERENCE),
+                        scope("Hidden registers", HIDDEN_REFERENCE),
+                        scope("Stack", STACK_REFERENCE),
+                    ]
+                }));
+            }
+            "variables" => {
+                return Ok(
+                    json!({ "variables": self.variables(arguments["variablesReference"].as_i64()) }),
+                )
+            }
+            "setVariable" => {
+                let value = self.set_variable(arguments)?;
+                self.refresh_watchpoints();
+                return Ok(json!({ "value": value.to_string() }));
+            }
+            "source" => {
+                let reference = arguments["sourceReference"]
+                    .as_i64()
+                    .or_else(|| arguments["source"]["sourceReference"].as_i64());
+                if reference != Some(DISASSEMBLY_REFERENCE) {
+                    return Err("unknown source".to_string());
+                }
+                let listing: Vec<String> = disassemble(&self.vm.program)
+                    .iter()
+                    .map(|i| i.to_string())
+                    .collect();
+                return Ok(
+                    json!({ "content": listing.join("\n") + "\n", "mimeType": "text/x-perling-disassembly" }),
+                );
+            }
+            "continue" | "next" | "stepIn" | "stepOut" => {
+                self.check_running()?;
+                *after = After::Resume(match command {
+                    "continue" => Resume::Continue,
+                    "next" => Resume::Next,
+                    "stepIn" => Resume::Step,
+                    _ => Resume::Out,
+                });
+                match command {
+                    "continue" => return Ok(json!({ "allThreadsContinued": true })),
+                    _ => return Ok(Value::Null),
+                }
+            }
+            // the program is already stopped when a pause is read outside of a run
+            "pause" => {
+                *after = After::Stopped("pause");
+                return Ok(Value::Null);
+            }
+            "evaluate" => return self.evaluate(arguments, after),
+            _ => return Err(format!("unsupported request {}", command)),
+        }
+    }
+
+    fn check_running(&self) -> Result<(), String> {
+        if let Some(code) = self.exit_code {
+            return Err(format!("the program exited with code {}", code));
+        }
+        if let Some(e) = &self.fault {
+            return Err(format!("the program faulted: {}", e));
+        }
+        return Ok(());
+    }
+
+    /// replaces the breakpoints of a source, lines of the source file map to the first
+    /// instruction on or after them, lines of the disassembly to their instruction
+    fn set_breakpoints(&mut self, arguments: &Value) -> Value {
+        let source = &arguments["source"];
+        let reference = source["sourceReference"].as_i64().unwrap_or(0);
+        let in_program = match (&self.debug_info, source["path"].as_str()) {
+            _ if reference == DISASSEMBLY_REFERENCE => true,
+            (Some(debug_info), Some(path)) if reference == 0 => same_path(&debug_info.source, path),
+            _ => false,
+        };
+        let requested = arguments["breakpoints"]
+            .as_array()
+            .cloned()
+            .unwrap_or_default();
+        if !in_program {
+            let breakpoints: Vec<Value> = requested
+                .iter()
+                .map(|_| json!({ "verified": false, "message": "not part of the program" }))
+                .collect();
+            return json!({ "breakpoints": breakpoints });
+        }
+
+        let replaced: Vec<usize> = self
+            .breakpoint_sources
+            .iter()
+            .filter(|(_, r)| **r == reference)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in replaced {
+            self.breakpoint_sources.remove(&id);
+            self.debugger.delete_breakpoint(id);
+        }
+        let mut breakpoints = vec![];
+        for breakpoint in requested.iter() {
+            let line = breakpoint["line"].as_u64().unwrap_or(0) as u32;
+            let location = match reference {
+                DISASSEMBLY_REFERENCE => self
+                    .disassembly
+                    .get((line as usize).wrapping_sub(1))
+                    .map(|set_index| (*set_index, line)),
+                _ => self.debug_info.as_ref().unwrap().set_index_of(line),
+            };
+            let (set_index, line) = match location {
+                Some(location) => location,
+                None => {
+                    breakpoints.push(json!({ "verified": false, "message": "no instruction on or after this line" }));
+                    continue;
+                }
+            };
+            let condition = match breakpoint["condition"]
+                .as_str()
+                .filter(|c| !c.trim().is_empty())
+            {
+                Some(condition) => {
+                    let words: Vec<&str> = condition.split_whitespace().collect();
+                    match Condition::parse(&words, &self.vm) {
+                        Ok(condition) => Some(condition),
+                        Err(message) => {
+                            breakpoints.push(json!({ "verified": false, "message": message }));
+                            continue;
+                        }
+                    }
+                }
+                None => None,
+            };
+            let id = self.debugger.add_breakpoint(set_index, condition).id;
+            self.breakpoint_sources.insert(id, reference);
+            breakpoints
+                .push(json!({ "id": id, "verified": true, "line": line, "source": self.source() }));
+        }
+        return json!({ "breakpoints": breakpoints });
+    }
+
+    /// the source the program is shown in
+    fn source(&self) -> Value {
+        match &self.debug_info {
+            Some(debug_info) => {
+                let name = Path::new(&debug_info.source)
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned());
+                return json!({ "name": name.unwrap_or_default(), "path": debug_info.source });
+            }
+            None => {
+                return json!({ "name": "disassembly", "sourceReference": DISASSEMBLY_REFERENCE })
+            }
+        }
+    }
+
+    /// returns the line a set index is shown at
+    fn line_of(&self, set_index: i32) -> u32 {
+        let line = match &self.debug_info {
+            Some(debug_info) => debug_info.line_of(set_index),
+            None => {
+                let next = self
+                    .disassembly
+                    .partition_point(|index| *index <= set_index);
+                next.checked_sub(1).map(|entry| entry as u32 + 1)
+            }
+        };
+        return line.unwrap_or(1);
+    }
+
+    /// the current location followed by the location of every active CALL, innermost first
+    fn stack_trace(&self) -> Value {
+        let mut frames = vec![];
+        let mut location = self.vm.program_set_counter;
+        for (depth, frame) in self.vm.call_stack.iter().rev().enumerate() {
+            frames.push(self.frame(
+                depth,
+                location,
+                &format!("set {} in {}", location, frame.target),
+            ));
+            location = frame.call_location;
+        }
+        frames.push(self.frame(
+            self.vm.call_stack.len(),
+            location,
+            &format!("set {} in entry", location),
+        ));
+        return json!({ "stackFrames": frames, "totalFrames": frames.len() });
+    }
+
+    fn frame(&self, id: usize, set_index: i32, name: &str) -> Value {
+        return json!({
+            "id": id,
+            "name": name,
+            "source": self.source(),
+            "line": self.line_of(set_index),
+            "column": 1,
+            "instructionPointerReference": (set_index.max(0) as usize * INSTRUCTION_SIZE).to_string(),
+        });
+    }
+
+    fn variables(&self, reference: Option<i64>) -> Vec<Value> {
+        let variable = |name: String, value: String| json!({ "name": name, "value": value, "variablesReference": 0 });
+        match reference {
+            Some(REGISTERS_REFERENCE) => {
+                return self
+                    .vm
+                    .registers
+                    .iter()
+                    .enumerate()
+                    .map(|(i, register)| {
+                        let lock = if register.locked { " (locked)" } else { "" };
+                        variable(format!("r{}", i), format!("{}{}", register.content, lock))
+                    })
+                    .collect()
+            }
+            Some(HIDDEN_REFERENCE) => {
+                return vec![
+                    variable("h0".to_string(), self.vm.remainder.to_string()),
+                    variable("h1".to_string(), self.vm.input_status.to_string()),
+                    variable("h2".to_string(), self.vm.flags.to_string()),
+                ]
+            }
+            Some(STACK_REFERENCE) => {
+                return self
+                    .vm
+                    .stack
+                    .content
+                    .iter()
+                    .enumerate()
+                    .map(|(i, value)| variable(i.to_string(), value.to_string()))
+                    .collect()
+            }
+            _ => return vec![],
+        }
+    }
+
+    /// writes a register, hidden register or stack value, registers are written even when locked
+    fn set_variable(&mut self, arguments: &Value) -> Result<i32, String> {
+        let name = arguments["name"].as_str().unwrap_or_default();
+        let value = parse_value(arguments["value"].as_str().unwrap_or_default().trim())?;
+        let slot = match arguments["variablesReference"].as_i64() {
+            Some(REGISTERS_REFERENCE) => {
+                let index = parse_register(name, &self.vm)?;
+                &mut self.vm.registers[index].content
+            }
+            Some(HIDDEN_REFERENCE) => match name {
+                "h0" => &mut self.vm.remainder,
+                "h1" => &mut self.vm.input_status,
+                "h2" => &mut self.vm.flags,
+                _ => return Err(format!("there is no hidden register {}", name)),
+            },
+            Some(STACK_REFERENCE) => match name
+                .parse::<usize>()
+                .ok()
+                .and_then(|i| self.vm.stack.content.get_mut(i))
+            {
+                Some(slot) => slot,
+                None => return Err(format!("there is no stack value {}", name)),
+            },
+            _ => return Err("unknown variable".to_string()),
+        };
+        *slot = value;
+        return Ok(value);
+    }
+
+    /// takes the current values as the ones watchpoints compare with, so edits made by the
+    /// client don't trigger them
+    fn refresh_watchpoints(&mut self) {
+        for watchpoint in self.debugger.watchpoints.iter_mut() {
+            watchpoint.value = watchpoint.target.value(&self.vm);
+        }
+    }
+
+    /// runs a debugger command typed in the console, other contexts (watch, hover) read a
+    /// register (`r3`) or hidden register (`h0`)
+    fn evaluate(&mut self, arguments: &Value, after: &mut After) -> Result<Value, String> {
+        let expression = arguments["expression"].as_str().unwrap_or_default().trim();
+        if arguments["context"] != "repl" {
+            let value = match expression {
+                "h0" => self.vm.remainder,
+                "h1" => self.vm.input_status,
+                "h2" => self.vm.flags,
+                _ => self.vm.registers[parse_register(expression, &self.vm)?].content,
+            };
+            return Ok(json!({ "result": value.to_string(), "variablesReference": 0 }));
+        }
+        let action = self.debugger.handle_command(expression, &mut self.vm);
+        let result = String::from_utf8_lossy(&self.console.take())
+            .trim_end()
+            .to_string();
+        match action {
+            Action::Stay => {}
+            Action::Quit => *after = After::Terminated,
+            _ => {
+                self.check_running()?;
+                *after = After::Resume(match action {
+                    Action::Step => Resume::Step,
+                    Action::Next => Resume::Next,
+                    _ => Resume::Continue,
+                });
+            }
+        }
+        return Ok(json!({ "result": result, "variablesReference": 0 }));
+    }
+}
+
+/// reads messages on another thread so pause requests can be read while the program runs
+fn spawn_reader<R: BufRead + Send + 'static>(mut input: R) -> Receiver<io::Result<Value>> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || loop {
+        let message = match read_message(&mut input) {
+            Ok(Some(message)) => Ok(message),
+            Ok(None) => break,
+            Err(e) => Err(e),
+        };
+        let failed = message.is_err();
+        if sender.send(message).is_err() || failed {
+            break;
+        }
+    });
+    return receiver;
+}
+
+/// reads a message framed by a Content-Length header, None at the end of the input
+fn read_message<R: BufRead>(input: &mut R) -> io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+    let length = match length {
+        Some(length) if length <= MAX_MESSAGE_SIZE => length,
+        Some(length) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("message of {} bytes is too large", length),
+            ))
+        }
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "message without a Content-Length",
+            ))
+        }
+    };
+    let mut content = vec![0; length];
+    input.read_exact(&mut content)?;
+    return serde_json::from_slice(&content)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e));
+}
+
+fn fs_read(path: &str) -> Result<Vec<u8>, String> {
+    return std::fs::read(path).map_err(|e| format!("unable to read {}: {}", path, e));
+}
+
+/// compares two paths, resolving them when they exist
+fn same_path(a: &str, b: &str) -> bool {
+    match (Path::new(a).canonicalize(), Path::new(b).canonicalize()) {
+        (Ok(a), Ok(b)) => return a == b,
+        _ => return a == b,
+    }
+}
+
+fn describe(value: Option<i32>) -> String {
+    match value {
+        Some(value) => return value.to_string(),
+        None => return "out of bounds".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::{assemble, assemble_with_debug_info};
+    use std::io::Cursor;
+
+    /// frames requests the way a client sends them
+    fn requests(requests: &[(&str, Value)]) -> Cursor<Vec<u8>> {
+        let mut bytes = vec![];
+        for (seq, (command, arguments)) in requests.iter().enumerate() {
+            let request = json!({ "seq": seq + 1, "type": "request", "command": command, "arguments": arguments });
+            let content = request.to_string();
+            bytes.extend_from_slice(
+                format!("Content-Length: {}\r\n\r\n{}", content.len(), content).as_bytes(),
+            );
+        }
+        return Cursor::new(bytes);
+    }
+
+    /// serves the requests and returns the result along with every message sent to the client
+    fn dap_session(requests: Cursor<Vec<u8>>) -> (io::Result<Option<i32>>, Vec<Value>) {
+        let output = SharedBuffer::default();
+        let result = DapServer::new(output.clone()).serve(requests);
+        let mut messages = vec![];
+        let mut output = Cursor::new(output.contents());
+        while let Some(message) = read_message(&mut output).unwrap() {
+            messages.push(message);
+        }
+        return (result, messages);
+    }
+
+    /// writes a program to a file of the temporary directory, launch reads it from there
+    fn program_file(name: &str, bytes: Vec<u8>) -> String {
+        let path = std::env::temp_dir().join(format!(
+            "perling-dap-{}-{}.perling.bin",
+            name,
+            std::process::id()
+        ));
+        std::fs::write(&path, bytes).unwrap();
+        return path.to_string_lossy().into_owned();
+    }
+
+    fn response<'a>(messages: &'a [Value], command: &str) -> Vec<&'a Value> {
+        return messages
+            .iter()
+            .filter(|m| m["type"] == "response" && m["command"] == command)
+            .collect();
+    }
+
+    fn events<'a>(messages: &'a [Value], event: &str) -> Vec<&'a Value> {
+        return messages
+            .iter()
+            .filter(|m| m["type"] == "event" && m["event"] == event)
+            .collect();
+    }
+
+    #[test]
+    fn test_dap_source_session() {
+        let source = "# prints 7\nLOAD r0 7\nLOAD r1 0\n\nLOAD r2 0\nLOAD r3 2\nLOAD r4 1\nLOAD r5 4\nVMCALL r3 r5 r1\nHLT";
+        let image = assemble_with_debug_info(source, "print7.pasm").unwrap();
+        let program = program_file("source", image.encode().unwrap());
+        let (result, messages) = dap_session(requests(&[
+            ("initialize", json!({ "adapterID": "perling" })),
+            ("launch", json!({ "program": program })),
+            (
+                "setBreakpoints",
+                json!({ "source": { "path": "print7.pasm" }, "breakpoints": [{ "line": 4 }, { "line": 30 }] }),
+            ),
+            (
+                "setBreakpoints",
+                json!({ "source": { "path": "other.pasm" }, "breakpoints": [{ "line": 1 }] }),
+            ),
+            ("configurationDone", json!({})),
+            ("stackTrace", json!({ "threadId": 1 })),
+            ("variables", json!({ "variablesReference": 1 })),
+            (
+                "setVariable",
+                json!({ "variablesReference": 1, "name": "r0", "value": "0x2a" }),
+            ),
+            ("next", json!({ "threadId": 1 })),
+            ("stackTrace", json!({ "threadId": 1 })),
+            (
+                "evaluate",
+                json!({ "expression": "print_registers_non_zero", "context": "repl" }),
+            ),
+            (
+                "evaluate",
+                json!({ "expression": "r2", "context": "watch" }),
+            ),
+            ("continue", json!({ "threadId": 1 })),
+            ("next", json!({ "threadId": 1 })),
+            ("disconnect", json!({})),
+        ]));
+        assert_eq!(result.unwrap(), Some(0));
+        assert!(messages
+            .iter()
+            .filter(|m| m["type"] == "response")
+            .all(|m| m["success"] == true || m["command"] == "next"));
+        assert_eq!(
+            response(&messages, "initialize")[0]["body"]["supportsConditionalBreakpoints"],
+            true
+        );
+        assert_eq!(events(&messages, "initialized").len(), 1);
+
+        let breakpoints = &response(&messages, "setBreakpoints")[0]["body"]["breakpoints"];
+        // the empty line 4 maps to the LOAD on line 5
+        assert_eq!(breakpoints[0]["verified"], true);
+        assert_eq!(breakpoints[0]["line"], 5);
+        assert_eq!(breakpoints[0]["source"]["name"], "print7.pasm");
+        assert_eq!(breakpoints[1]["verified"], false);
+        let breakpoints = &response(&messages, "setBreakpoints")[1]["body"]["breakpoints"];
+        assert_eq!(breakpoints[0]["verified"], false);
+
+        let stopped = events(&messages, "stopped");
+        assert_eq!(stopped[0]["body"]["reason"], "breakpoint");
+        assert_eq!(
+            stopped[0]["body"]["hitBreakpointIds"],
+            json!([breakpoints_id(&messages)])
+        );
+        assert_eq!(stopped[1]["body"]["reason"], "step");
+        let traces = response(&messages, "stackTrace");
+        assert_eq!(traces[0]["body"]["stackFrames"][0]["line"], 5);
+        assert_eq!(
+            traces[0]["body"]["stackFrames"][0]["name"],
+            "set 2 in entry"
+        );
+        assert_eq!(traces[1]["body"]["stackFrames"][0]["line"], 6);
+        assert_eq!(
+            response(&messages, "variables")[0]["body"]["variables"][0],
+            json!({ "name": "r0", "value": "7", "variablesReference": 0 })
+        );
+        assert_eq!(response(&messages, "setVariable")[0]["body"]["value"], "42");
+        let evaluated = response(&messages, "evaluate");
+        assert_eq!(evaluated[0]["body"]["result"], "0:\t42\tlocked:false\nh0:\t0\tremainder register\nh1:\t0\tinput status register\nh2:\t0\tflags register");
+        assert_eq!(evaluated[1]["body"]["result"], "0");
+
+        let output = events(&messages, "output");
+        assert_eq!(
+            output[0]["body"],
+            json!({ "category": "stdout", "output": "42\n" })
+        );
+        assert_eq!(output[1]["body"]["output"], "process exited with code: 0\n");
+        assert_eq!(events(&messages, "exited")[0]["body"]["exitCode"], 0);
+        assert_eq!(events(&messages, "terminated").len(), 1);
+        assert_eq!(
+            response(&messages, "next")[1]["message"],
+            "the program exited with code 0"
+        );
+        std::fs::remove_file(program).unwrap();
+    }
+
+    fn breakpoints_id(messages: &[Value]) -> Value {
+        return response(messages, "setBreakpoints")[0]["body"]["breakpoints"][0]["id"].clone();
+    }
+
+    #[test]
+    fn test_dap_disassembly_and_pause() {
+        // LOAD r1 1, JMP to *r0 (0)
+        let program = program_file("disassembly", vec![1, 1, 0, 1, 5, 0, 1, 0]);
+        let (result, messages) = dap_session(requests(&[
+            ("initialize", json!({})),
+            ("launch", json!({ "program": program, "stopOnEntry": true })),
+            (
+                "setBreakpoints",
+                json!({ "source": { "sourceReference": 1 }, "breakpoints": [{ "line": 2, "condition": "r1 == 2" }] }),
+            ),
+            ("configurationDone", json!({})),
+            ("stackTrace", json!({ "threadId": 1 })),
+            ("source", json!({ "sourceReference": 1 })),
+            ("stepIn", json!({ "threadId": 1 })),
+            ("continue", json!({ "threadId": 1 })),
+            ("pause", json!({ "threadId": 1 })),
+            ("scopes", json!({ "frameId": 0 })),
+            ("disconnect", json!({})),
+        ]));
+        assert_eq!(result.unwrap(), None);
+        let stopped = events(&messages, "stopped");
+        let reasons: Vec<&str> = stopped
+            .iter()
+            .map(|m| m["body"]["reason"].as_str().unwrap())
+            .collect();
+        // the condition never holds, so only the pause stops the loop
+        assert_eq!(reasons, vec!["entry", "step", "pause"]);
+        let frame = &response(&messages, "stackTrace")[0]["body"]["stackFrames"][0];
+        assert_eq!(
+            frame["source"],
+            json!({ "name": "disassembly", "sourceReference": 1 })
+        );
+        assert_eq!(frame["line"], 1);
+        assert_eq!(
+            response(&messages, "source")[0]["body"]["content"],
+            "    0  01 01 00 01  LOAD r1 1\n    1  05 00 01 00  JMP r0               ; *r0\n"
+        );
+        assert_eq!(response(&messages, "pause")[0]["success"], true);
+        // the pause is answered before the program stops
+        let pause = messages
+            .iter()
+            .position(|m| m["command"] == "pause")
+            .unwrap();
+        assert!(
+            pause
+                < messages
+                    .iter()
+                    .position(|m| m["body"]["reason"] == "pause")
+                    .unwrap()
+        );
+        assert_eq!(
+            response(&messages, "scopes")[0]["body"]["scopes"][2]["name"],
+            "Stack"
+        );
+        std::fs::remove_file(program).unwrap();
+    }
+
+    #[test]
+    fn test_dap_fault() {
+        let program = program_file("fault", assemble("LOAD r1 1\nDIV r1 r0 r2").unwrap());
+        let (result, messages) = dap_session(requests(&[
+            ("launch", json!({ "program": program })),
+            ("configurationDone", json!({})),
+            ("continue", json!({ "threadId": 1 })),
+            ("launch", json!({ "program": "missing.perling.bin" })),
+        ]));
+        assert_eq!(result.unwrap(), None);
+        let stopped = events(&messages, "stopped");
+        assert_eq!(stopped[0]["body"]["reason"], "exception");
+        assert_eq!(
+            stopped[0]["body"]["text"],
+            "division by zero at program set: 1 program counter: 8"
+        );
+        assert_eq!(
+            response(&messages, "continue")[0]["message"],
+            "the program faulted: division by zero at program set: 1 program counter: 8"
+        );
+        let launch = response(&messages, "launch");
+        assert_eq!(launch[1]["success"], false);
+        assert!(launch[1]["message"]
+            .as_str()
+            .unwrap()
+            .starts_with("unable to read missing.perling.bin"));
+        std::fs::remove_file(program).unwrap();
+    }
+
+    #[test]
+    fn test_dap_read_message() {
+        let mut input = io::Cursor::new(b"Content-Length: 2\r\n\r\n{}".to_vec());
+        assert_eq!(read_message(&mut input).unwrap(), Some(json!({})));
+        assert_eq!(read_message(&mut input).unwrap(), None);
+
+        let mut input = io::Cursor::new(b"Content-Length: 99999999999\r\n\r\n{}".to_vec());
+        let error = read_message(&mut input).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(error.to_string(), "message of 99999999999 bytes is too large");
+    }
+}
@@ -1,51 +1,796 @@
-use std::io::{BufRead};
-use crate::VM;
+use crate::disassembler::disassemble;
+use crate::error::VmError;
+use crate::instructions::INSTRUCTION_SIZE;
+use crate::register::REGISTER;
+use crate::vm::{Step, VM};
+use crate::watch::{parse_register, parse_value, Condition, WatchTarget, Watchpoint};
+use std::io::{self, BufRead, Write};
+
+/// number of instructions `disasm` shows when no count is given
+const DEFAULT_DISASM_COUNT: usize = 5;
+
+/// a location execution stops at before the instruction there runs
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Breakpoint {
+    pub id: usize,
+
+    /// program_set_counter value of the breakpoint
+    pub set_index: i32,
+
+    /// the breakpoint only stops execution when the condition holds
+    pub condition: Option<Condition>,
+}
+
+/// what a debugger command asks the session to do next
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    /// wait for the next command
+    Stay,
+
+    /// execute a single instruction
+    Step,
+
+    /// execute a single instruction, running CALLs to completion
+    Next,
+
+    /// execute until a breakpoint, BREAK or the end of the program
+    Continue,
+
+    /// end the debugging session
+    Quit,
+}
+
+/// why execution under the debugger stopped
+#[derive(Debug, Clone, PartialEq)]
+pub enum StopReason {
+    /// a single step finished
+    Step,
+
+    /// execution reached the breakpoint with the given id
+    Breakpoint(usize),
+
+    /// the target of the watchpoint with the given id changed
+    Watchpoint {
+        id: usize,
+        old: Option<i32>,
+        new: Option<i32>,
+    },
+
+    /// the program executed BREAK
+    Break,
+
+    /// the program finished with the given exit code
+    Exit(i32),
+
+    /// the program faulted
+    Fault(VmError),
+}
+
+/// interactive debugger, either entered by BREAK or driving a whole run with `debug`
 pub struct DebugEngine {
+    pub breakpoints: Vec<Breakpoint>,
+    pub watchpoints: Vec<Watchpoint>,
 
+    /// next id given to a breakpoint or watchpoint, they share ids so `delete` works on both
+    next_breakpoint_id: usize,
+    out: Box<dyn Write + Send>,
+}
+
+impl Default for DebugEngine {
+    /// writes to the standard output of the process
+    fn default() -> Self {
+        DebugEngine::with_output(io::stdout())
+    }
 }
 
 impl DebugEngine {
+    /// creates a debugger writing its output to out
+    pub fn with_output<W: Write + Send + 'static>(out: W) -> DebugEngine {
+        DebugEngine {
+            breakpoints: vec![],
+            watchpoints: vec![],
+            next_breakpoint_id: 1,
+            out: Box::new(out),
+        }
+    }
+
+    /// returns the output the debugger writes to
+    pub fn into_output(self) -> Box<dyn Write + Send> {
+        return self.out;
+    }
+
+    /// handles commands until one of them resumes execution and returns it, used when BREAK is
+    /// hit without a debugger attached. Stepping can't be done from there so every resuming
+    /// command but `quit` continues, the end of the input continues as well
+    pub fn wait_for_commands(&mut self, vm: &mut VM, input: &mut dyn BufRead) -> Action {
+        while let Some(line) = self.read_command(input) {
+            let action = self.handle_command(line.trim(), vm);
+            if action != Action::Stay {
+                return action;
+            }
+        }
+        return Action::Continue;
+    }
+
+    /// reads the next command, a line that isn't UTF-8 is reported and skipped. Returns None at
+    /// the end of the input
+    fn read_command(&mut self, input: &mut dyn BufRead) -> Option<String> {
+        loop {
+            let mut line = String::new();
+            match input.read_line(&mut line) {
+                Ok(0) => return None,
+                Ok(_) => return Some(line),
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                    self.print("commands have to be valid UTF-8\n");
+                }
+                Err(e) => {
+                    self.print(&format!("unable to read a command: {}\n", e));
+                    return None;
+                }
+            }
+        }
+    }
+
+    /// runs the loaded program under the debugger, starting paused at the current location.
+    /// Returns the exit code of the program or None if the session was quit before it finished,
+    /// a fault can still be inspected and is returned when the session is quit.
+    /// Limits are enforced by step, the time limit is ignored
+    pub fn debug<R: BufRead>(&mut self, vm: &mut VM, input: R) -> Result<Option<i32>, VmError> {
+        vm.debugger_attached = true;
+        let result = self.session(vm, input);
+        vm.debugger_attached = false;
+        return result;
+    }
+
+    fn session<R: BufRead>(&mut self, vm: &mut VM, mut input: R) -> Result<Option<i32>, VmError> {
+        vm.resolve_labels()?;
+        vm.executed_instructions = 0;
+        let mut fault: Option<VmError> = None;
+        self.print(&location(vm));
+        loop {
+            self.print("(perling) ");
+            let _ = self.out.flush();
+            let line = match self.read_command(&mut input) {
+                Some(line) => line,
+                None => break,
+            };
+            let action = self.handle_command(line.trim(), vm);
+            let reason = match (action, &fault) {
+                (Action::Stay, _) => continue,
+                (Action::Quit, _) => break,
+                (_, Some(e)) => {
+                    self.print(&format!("the program faulted: {}\n", e));
+                    continue;
+                }
+                (Action::Step, None) => self.step(vm),
+                (Action::Next, None) => self.next(vm),
+                (Action::Continue, None) => self.resume(vm),
+            };
+            match reason {
+                StopReason::Step => {}
+                StopReason::Breakpoint(id) => {
+                    self.print(&format!("breakpoint {} at set {}\n", id, vm.program_set_counter))
+                }
+                StopReason::Watchpoint { id, old, new } => {
+                    let watchpoint = self.watchpoints.iter().find(|w| w.id == id).unwrap();
+                    self.print(&format!(
+                        "watchpoint {} {}: {} -> {}\n",
+                        id,
+                        watchpoint.target,
+                        describe_value(old),
+                        describe_value(new)
+                    ))
+                }
+                StopReason::Break => {
+                    self.print(&format!("hit BREAK at set {}\n", vm.program_set_counter))
+                }
+                StopReason::Exit(code) => {
+                    self.print(&format!("process exited with code: {}\n", code));
+                    return Ok(Some(code));
+                }
+                StopReason::Fault(e) => {
+                    self.print(&format!("{}\n", e));
+                    fault = Some(e);
+                    continue;
+                }
+            }
+            self.print(&location(vm));
+        }
+        match fault {
+            Some(e) => return Err(e),
+            None => return Ok(None),
+        }
+    }
+
+    /// executes a single instruction, stopping at a watchpoint whose target changed or a
+    /// breakpoint at the next location. The instruction budget and stack depth limit of the VM
+    /// are enforced, the time limit is not as the program spends most of its time paused
+    pub fn step(&mut self, vm: &mut VM) -> StopReason {
+        if let Err(e) = vm.check_instruction_budget() {
+            return StopReason::Fault(e);
+        }
+        let reason = match vm.run_once() {
+            Ok(Step::Continue) => StopReason::Step,
+            Ok(Step::Break) => StopReason::Break,
+            Ok(Step::Exit(code)) => match vm.flush_streams() {
+                Ok(()) => return StopReason::Exit(code),
+                Err(e) => return StopReason::Fault(e),
+            },
+            Err(e) => return StopReason::Fault(e),
+        };
+        vm.executed_instructions += 1;
+        if let Err(e) = vm.check_stack_depth() {
+            return StopReason::Fault(e);
+        }
+        // both lists are usually empty, so this costs next to nothing per instruction
+        for watchpoint in self.watchpoints.iter_mut() {
+            if let Some((old, new)) = watchpoint.check(vm) {
+                return StopReason::Watchpoint { id: watchpoint.id, old, new };
+            }
+        }
+        if reason != StopReason::Step || self.breakpoints.is_empty() {
+            return reason;
+        }
+        match self.breakpoint_at(vm.program_set_counter) {
+            Some(Breakpoint { condition: Some(condition), .. }) if !condition.holds(vm) => {
+                return StopReason::Step
+            }
+            Some(breakpoint) => return StopReason::Breakpoint(breakpoint.id),
+            None => return StopReason::Step,
+        }
+    }
 
-   pub fn wait_for_commands(&mut self, vm: &VM, stdin: std::io::Stdin) {
-        for line in stdin.lock().lines() {
-            let l = line.unwrap();
-            let stop = self.handle_command(l, vm);
-            if stop {
-                break;
+    /// executes a single instruction, when it is a CALL execution carries on until it returns
+    pub fn next(&mut self, vm: &mut VM) -> StopReason {
+        let depth = vm.call_stack.len();
+        let mut reason = self.step(vm);
+        while reason == StopReason::Step && vm.call_stack.len() > depth {
+            reason = self.step(vm);
+        }
+        return reason;
+    }
+
+    /// executes until a breakpoint, a watchpoint, BREAK or the end of the program, at least
+    /// one instruction is executed so resuming from a breakpoint does not stop at it again
+    pub fn resume(&mut self, vm: &mut VM) -> StopReason {
+        loop {
+            let reason = self.step(vm);
+            if reason != StopReason::Step {
+                return reason;
             }
         }
     }
 
-   pub fn handle_command(&mut self, command: String, vm: &VM) -> bool {
-        let command_data: Vec<&str> = command.split(" ").collect();
+    /// returns the breakpoint at a location
+    pub fn breakpoint_at(&self, set_index: i32) -> Option<Breakpoint> {
+        return self.breakpoints.iter().find(|b| b.set_index == set_index).copied();
+    }
+
+    /// adds a breakpoint and returns it, a location only holds a single breakpoint so adding
+    /// another one replaces its condition
+    pub fn add_breakpoint(&mut self, set_index: i32, condition: Option<Condition>) -> Breakpoint {
+        if let Some(breakpoint) = self.breakpoints.iter_mut().find(|b| b.set_index == set_index) {
+            breakpoint.condition = condition;
+            return *breakpoint;
+        }
+        let breakpoint = Breakpoint {
+            id: self.next_breakpoint_id,
+            set_index,
+            condition,
+        };
+        self.next_breakpoint_id += 1;
+        self.breakpoints.push(breakpoint);
+        return breakpoint;
+    }
+
+    /// adds a watchpoint on the current value of the target and returns it
+    pub fn add_watchpoint(&mut self, target: WatchTarget, vm: &VM) -> Watchpoint {
+        let watchpoint = Watchpoint {
+            id: self.next_breakpoint_id,
+            target,
+            value: target.value(vm),
+        };
+        self.next_breakpoint_id += 1;
+        self.watchpoints.push(watchpoint);
+        return watchpoint;
+    }
+
+    /// removes a breakpoint or watchpoint, returning false if there is none with that id
+    pub fn delete_breakpoint(&mut self, id: usize) -> bool {
+        let count = self.breakpoints.len() + self.watchpoints.len();
+        self.breakpoints.retain(|b| b.id != id);
+        self.watchpoints.retain(|w| w.id != id);
+        return self.breakpoints.len() + self.watchpoints.len() != count;
+    }
+
+    /// writes debugger output, the session carries on when the output can't be written
+    fn print(&mut self, text: &str) {
+        let _ = self.out.write_all(text.as_bytes());
+    }
+
+    /// handles a single command, commands that change the state of the VM take effect before
+    /// the next instruction runs
+    pub fn handle_command(&mut self, command: &str, vm: &mut VM) -> Action {
+        let command_data: Vec<&str> = command.split_whitespace().collect();
+        if command_data.is_empty() {
+            return Action::Stay;
+        }
         match command_data[0] {
             "print_registers" => {
-                for i in 0..vm.registers.len() {
-                    println!("{}:\t{}\tlocked:{}", i, vm.registers[i].content, vm.registers[i].locked)
+                let mut text = String::new();
+                for (i, register) in vm.registers.iter().enumerate() {
+                    text.push_str(&describe_register(i, register));
                 }
-                println!("h0:\t{}\tremainder register", vm.remainder)
-            },
+                text.push_str(&hidden_registers(vm));
+                self.print(&text);
+            }
             "print_registers_non_zero" => {
-                for i in 0..vm.registers.len() {
-                    if vm.registers[i].content == 0 {
+                let mut text = String::new();
+                for (i, register) in vm.registers.iter().enumerate() {
+                    if register.content == 0 {
                         continue;
                     }
-                    println!("{}:\t{}\tlocked:{}", i, vm.registers[i].content, vm.registers[i].locked)
+                    text.push_str(&describe_register(i, register));
+                }
+                text.push_str(&hidden_registers(vm));
+                self.print(&text);
+            }
+            "backtrace" | "bt" => {
+                self.print(&backtrace(vm));
+            }
+            "step" | "s" => return Action::Step,
+            "next" | "n" => return Action::Next,
+            "continue" | "c" | "run" | "r" => return Action::Continue,
+            "quit" | "q" => return Action::Quit,
+            "break" | "b" => match parse_breakpoint(&command_data[1..], vm) {
+                Ok((set_index, condition)) => {
+                    let breakpoint = self.add_breakpoint(set_index, condition);
+                    self.print(&describe_breakpoint(&breakpoint));
+                }
+                Err(message) => self.print(&format!("{}\n", message)),
+            },
+            "watch" | "w" => match WatchTarget::parse(&command_data[1..], vm) {
+                Ok(target) => {
+                    let watchpoint = self.add_watchpoint(target, vm);
+                    self.print(&describe_watchpoint(&watchpoint));
+                }
+                Err(message) => self.print(&format!("{}\n", message)),
+            },
+            "delete" | "d" => match command_data.get(1).map(|id| id.parse::<usize>()) {
+                None => {
+                    self.breakpoints.clear();
+                    self.watchpoints.clear();
+                    self.print("deleted every breakpoint and watchpoint\n");
+                }
+                Some(Ok(id)) if self.delete_breakpoint(id) => {
+                    self.print(&format!("deleted breakpoint {}\n", id))
+                }
+                _ => self.print(&format!("no breakpoint {}\n", command_data[1])),
+            },
+            "breakpoints" => {
+                let mut text = String::new();
+                for breakpoint in self.breakpoints.iter() {
+                    text.push_str(&describe_breakpoint(breakpoint));
                 }
-                println!("h0:\t{}\tremainder register", vm.remainder)
+                for watchpoint in self.watchpoints.iter() {
+                    text.push_str(&describe_watchpoint(watchpoint));
+                }
+                self.print(&text);
             }
-            "continue" => {
-                return true;
+            "set_register" | "toggle_lock" | "push" | "pop" | "set_stack" | "print_stack"
+            | "set_remainder" | "jump" => {
+                match edit_state(&command_data, vm) {
+                    Ok(text) | Err(text) => self.print(&text),
+                }
+                // changes made by the debugger don't trigger watchpoints
+                for watchpoint in self.watchpoints.iter_mut() {
+                    watchpoint.value = watchpoint.target.value(vm);
+                }
             }
+            "disasm" => match command_data.get(1).map(|count| count.parse::<usize>()) {
+                None => self.print(&disassemble_at(vm, DEFAULT_DISASM_COUNT)),
+                Some(Ok(count)) => self.print(&disassemble_at(vm, count)),
+                Some(Err(_)) => self.print("usage: disasm [count]\n"),
+            },
             "help" => {
-                println!(
+                self.print(
                     "print_registers\tprints register contents\n\
-                    print_register_non_zero\tprints register contents that are not 0\n\
-                    continue\tcontinues program execution\n"
+                    print_registers_non_zero\tprints register contents that are not 0\n\
+                    backtrace\tprints the active calls, innermost first\n\
+                    step\texecutes a single instruction\n\
+                    next\texecutes a single instruction, running CALLs to completion\n\
+                    continue\tcontinues program execution (also run)\n\
+                    break <set index|@label id> [if r3 == 10]\tstops before the instruction at a location\n\
+                    watch <r3|lock r3|mem address [width]|stack>\tstops when the value changes\n\
+                    delete [id]\tdeletes a breakpoint or watchpoint, or all of them without an id\n\
+                    breakpoints\tlists the breakpoints and watchpoints\n\
+                    disasm [count]\tdisassembles the instructions at the current location\n\
+                    set_register <register> <value>\tsets the content of a register, even when it is locked\n\
+                    toggle_lock <register>\tlocks or unlocks a register\n\
+                    print_stack\tprints the stack, bottom first\n\
+                    push <value>\tpushes a value to the stack\n\
+                    pop\tpops the top value of the stack\n\
+                    set_stack <index> <value>\tsets a value of the stack, counting from the bottom\n\
+                    set_remainder <value>\tsets the remainder register (h0)\n\
+                    jump <set index|@label id>\tmoves execution to a location\n\
+                    quit\tends the debugging session, at a BREAK it also ends the program\n",
                 )
             }
-            _ => println!("cant find command")
+            _ => self.print("cant find command\n"),
+        }
+        return Action::Stay;
+    }
+}
+
+/// runs a command that inspects or changes the registers, the stack or the location of the VM
+fn edit_state(command_data: &[&str], vm: &mut VM) -> Result<String, String> {
+    let argument = |index: usize| match command_data.get(index) {
+        Some(argument) => return Ok(*argument),
+        None => return Err(format!("{} is missing an operand, see help\n", command_data[0])),
+    };
+    match command_data[0] {
+        "set_register" => {
+            let index = parse_register(argument(1)?, vm).map_err(|e| e + "\n")?;
+            let value = parse_value(argument(2)?).map_err(|e| e + "\n")?;
+            vm.registers[index].content = value;
+            return Ok(describe_register(index, &vm.registers[index]));
+        }
+        "toggle_lock" => {
+            let index = parse_register(argument(1)?, vm).map_err(|e| e + "\n")?;
+            vm.registers[index].toggle_lock();
+            return Ok(describe_register(index, &vm.registers[index]));
+        }
+        "push" => {
+            vm.stack.content.push(parse_value(argument(1)?).map_err(|e| e + "\n")?);
+            return Ok(describe_stack(vm));
+        }
+        "pop" => match vm.stack.content.pop() {
+            Some(value) => return Ok(format!("popped {}\n", value)),
+            None => return Err("the stack is empty\n".to_string()),
+        },
+        "set_stack" => {
+            let index = argument(1)?;
+            let value = parse_value(argument(2)?).map_err(|e| e + "\n")?;
+            match index.parse::<usize>().ok().and_then(|i| vm.stack.content.get_mut(i)) {
+                Some(entry) => *entry = value,
+                None => return Err(format!("there is no stack entry {}\n", index)),
+            }
+            return Ok(describe_stack(vm));
+        }
+        "print_stack" => return Ok(describe_stack(vm)),
+        "set_remainder" => {
+            vm.remainder = parse_value(argument(1)?).map_err(|e| e + "\n")?;
+            return Ok(format!("h0:\t{}\tremainder register\n", vm.remainder));
+        }
+        "jump" => {
+            let set_index = parse_location(argument(1)?, vm).map_err(|e| e + "\n")?;
+            vm.program_set_counter = set_index;
+            vm.program_counter = (set_index as usize) * INSTRUCTION_SIZE;
+            return Ok(location(vm));
+        }
+        _ => return Err("cant find command\n".to_string()),
+    }
+}
+
+/// parses the operands of `break`, a location optionally followed by `if <condition>`
+fn parse_breakpoint(words: &[&str], vm: &VM) -> Result<(i32, Option<Condition>), String> {
+    match words {
+        [location] => return Ok((instruction_start(parse_location(location, vm)?, vm), None)),
+        [location, "if", condition @ ..] => {
+            let condition = Condition::parse(condition, vm)?;
+            return Ok((instruction_start(parse_location(location, vm)?, vm), Some(condition)));
+        }
+        _ => return Err("usage: break <set index|@label id> [if <condition>]".to_string()),
+    }
+}
+
+fn describe_breakpoint(breakpoint: &Breakpoint) -> String {
+    match breakpoint.condition {
+        Some(condition) => {
+            return format!(
+                "breakpoint {} at set {} if {}\n",
+                breakpoint.id, breakpoint.set_index, condition
+            )
         }
-       return false;
+        None => return format!("breakpoint {} at set {}\n", breakpoint.id, breakpoint.set_index),
+    }
+}
+
+fn describe_watchpoint(watchpoint: &Watchpoint) -> String {
+    return format!(
+        "watchpoint {} {} = {}\n",
+        watchpoint.id,
+        watchpoint.target,
+        describe_value(watchpoint.value)
+    );
+}
+
+fn describe_value(value: Option<i32>) -> String {
+    match value {
+        Some(value) => return value.to_string(),
+        None => return "out of bounds".to_string(),
+    }
+}
+
+fn describe_stack(vm: &VM) -> String {
+    let values: Vec<String> = vm.stack.content.iter().map(|value| value.to_string()).collect();
+    return format!("stack: [{}]\n", values.join(", "));
+}
+
+fn describe_register(index: usize, register: &REGISTER) -> String {
+    return format!("{}:\t{}\tlocked:{}\n", index, register.content, register.locked);
+}
+
+fn hidden_registers(vm: &VM) -> String {
+    return format!(
+        "h0:\t{}\tremainder register\nh1:\t{}\tinput status register\nh2:\t{}\tflags register\n",
+        vm.remainder, vm.input_status, vm.flags
+    );
+}
+
+/// parses a breakpoint location, a set index or `@id` for the location of a label. Label names
+/// are not kept in bin files, so only the ids `disasm` shows can be used
+fn parse_location(target: &str, vm: &VM) -> Result<i32, String> {
+    if let Some(id) = target.strip_prefix('@') {
+        let id = id.parse::<i32>().map_err(|_| {
+            format!("invalid label id `{}`, label names are not kept, use the id disasm shows", id)
+        })?;
+        match vm.labels.iter().find(|label| label.id == id) {
+            Some(label) => return Ok(label.location),
+            None => return Err(format!("label {} is not defined", id)),
+        }
+    }
+    match target.parse::<i32>() {
+        Ok(set_index) if set_index >= 0 => return Ok(set_index),
+        _ => return Err(format!("invalid set index `{}`", target)),
+    }
+}
+
+/// returns the set index of the MODE slot prefixing the instruction at set_index, or set_index
+/// itself. A prefixed instruction runs in the same step as its MODE slot, so execution never
+/// stops at its own slot
+pub fn instruction_start(set_index: i32, vm: &VM) -> i32 {
+    let prefixed = disassemble(&vm.program)
+        .iter()
+        .any(|instruction| instruction.modes.is_some() && instruction.set_index + 1 == set_index);
+    return if prefixed { set_index - 1 } else { set_index };
+}
+
+/// disassembles up to count instructions starting at the current location
+pub fn disassemble_at(vm: &VM, count: usize) -> String {
+    let start = (vm.program_set_counter.max(0) as usize) * INSTRUCTION_SIZE;
+    if start >= vm.program.len() {
+        return "=> end of program\n".to_string();
     }
-}
\ No newline at end of file
+    let mut text = String::new();
+    let instructions = disassemble(&vm.program[start..]);
+    for (index, mut instruction) in instructions.into_iter().take(count).enumerate() {
+        instruction.set_index += vm.program_set_counter;
+        let marker = if index == 0 { "=> " } else { "   " };
+        text.push_str(&format!("{}{}\n", marker, instruction));
+    }
+    return text;
+}
+
+/// describes the instruction that runs next, e.g. `=>     4  01 00 01 f4  LOAD r0 500`
+pub fn location(vm: &VM) -> String {
+    return disassemble_at(vm, 1);
+}
+
+/// describes the active calls, innermost first, e.g. `#0  set 12 in 8 (called from set 3)`
+pub fn backtrace(vm: &VM) -> String {
+    let mut text = String::new();
+    let mut location = vm.program_set_counter;
+    for (depth, frame) in vm.call_stack.iter().rev().enumerate() {
+        text.push_str(&format!(
+            "#{}  set {} in {} (called from set {})\n",
+            depth, location, frame.target, frame.call_location
+        ));
+        location = frame.call_location;
+    }
+    text.push_str(&format!("#{}  set {} in entry\n", vm.call_stack.len(), location));
+    return text;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::error::VmErrorKind;
+    use crate::frame::FRAME;
+    use crate::limits::{Limit, Limits};
+    use crate::streams::SharedBuffer;
+    use std::io::Cursor;
+
+    /// runs a debugging session over the PASM source with the given commands
+    fn debug_session(source: &str, commands: &str) -> (Result<Option<i32>, VmError>, String, VM) {
+        let output = SharedBuffer::default();
+        let mut debugger = DebugEngine::with_output(output.clone());
+        let mut vm = VM::with_program(assemble(source).unwrap());
+        let result = debugger.debug(&mut vm, Cursor::new(commands.to_string()));
+        return (result, output.to_string_lossy(), vm);
+    }
+
+    #[test]
+    fn test_backtrace() {
+        let mut vm = VM::new();
+        assert_eq!(backtrace(&vm), "#0  set 0 in entry\n");
+
+        vm.call_stack.push(FRAME { call_location: 3, return_location: 5, target: 8 });
+        vm.call_stack.push(FRAME { call_location: 9, return_location: 11, target: 20 });
+        vm.program_set_counter = 22;
+        assert_eq!(
+            backtrace(&vm),
+            "#0  set 22 in 20 (called from set 9)\n\
+             #1  set 9 in 8 (called from set 3)\n\
+             #2  set 3 in entry\n"
+        );
+    }
+
+    #[test]
+    fn test_debug_breakpoints() {
+        let source = "LOAD r1 1\nLABEL 3\nLOAD r2 2\nLOAD r3 3\nLOAD r4 4";
+        let (result, output, vm) = debug_session(source, "break 3\nbreak @3\nrun\nstep\n");
+        assert_eq!(result, Ok(None));
+        assert!(output.starts_with("=>     0  01 01 00 01  LOAD r1 1\n(perling) "));
+        assert!(output.contains("breakpoint 1 at set 3\n(perling) breakpoint 2 at set 1\n"));
+        assert!(output.contains("breakpoint 2 at set 1\n=>     1  16 03 00 00  LABEL 3\n"));
+        assert!(output.ends_with("=>     2  01 02 00 02  LOAD r2 2\n(perling) "));
+        assert_eq!(vm.registers[1].content, 1);
+        assert_eq!(vm.registers[2].content, 0);
+
+        let (result, output, vm) = debug_session(source, "b 3\nd 1\nc\n");
+        assert_eq!(result, Ok(Some(0)));
+        assert!(output.contains("deleted breakpoint 1\n"));
+        assert!(output.ends_with("process exited with code: 0\n"));
+        assert_eq!(vm.registers[4].content, 4);
+        assert!(!vm.debugger_attached);
+    }
+
+    #[test]
+    fn test_debug_next_steps_over_calls() {
+        let source = "CALL twice\nLOAD r2 2\nHLT\ntwice: LOAD r1 1\nRET";
+        let (_, output, vm) = debug_session(source, "next\n");
+        assert!(output.ends_with("=>     2  01 02 00 02  LOAD r2 2\n(perling) "));
+        assert_eq!(vm.registers[1].content, 1);
+
+        let (_, output, _) = debug_session(source, "step\nbacktrace\n");
+        assert!(output.contains("=>     4  01 01 00 01  LOAD r1 1\n"));
+        assert!(output.contains("#0  set 4 in 4 (called from set 0)\n#1  set 0 in entry\n"));
+
+        // breakpoints inside the CALL still stop next
+        let (_, output, _) = debug_session(source, "break 5\nnext\n");
+        assert!(output.contains("breakpoint 1 at set 5\n=>     5  39 00 00 00  RET\n"));
+    }
+
+    #[test]
+    fn test_debug_break_opcode_and_faults() {
+        let source = "BREAK\nLOAD r1 1\nDIV r1 r2 r3\nHLT";
+        let commands = "continue\ndisasm 2\ncontinue\nstep\nquit\n";
+        let (result, output, _) = debug_session(source, commands);
+        assert!(output.contains("hit BREAK at set 1\n=>     1  01 01 00 01  LOAD r1 1\n"));
+        assert!(output.contains("=>     1  01 01 00 01  LOAD r1 1\n       2  04 01 02 03  DIV"));
+        assert!(output.contains("division by zero at program set: 2"));
+        assert!(output.contains("the program faulted: division by zero"));
+        assert_eq!(result.unwrap_err().kind, crate::error::VmErrorKind::DivisionByZero);
+    }
+
+    #[test]
+    fn test_debug_edits_state() {
+        let source = "LOAD r1 1\nLOAD r2 2\nBREAK\nHLT";
+        let commands = "set_register r5 -7\ntoggle_lock 6\nset_register r6 0x10\npush 4\n\
+                        set_stack 0 9\npop\nset_remainder 3\njump 1\nstep\n";
+        let (result, output, vm) = debug_session(source, commands);
+        assert_eq!(result, Ok(None));
+        assert!(output.contains("5:\t-7\tlocked:false\n"));
+        assert!(output.contains("6:\t16\tlocked:true\n"));
+        assert!(output.contains("stack: [0, 4]\n(perling) stack: [9, 4]\n(perling) popped 4\n"));
+        assert!(output.contains("=>     1  01 02 00 02  LOAD r2 2\n(perling) =>     2  15"));
+        assert_eq!(vm.registers[1].content, 0);
+        assert_eq!(vm.registers[2].content, 2);
+        assert_eq!(vm.stack.content, vec![9]);
+        assert_eq!(vm.remainder, 3);
+
+        let commands = "set_register r40 1\nset_register r1 x\nset_stack 5 1\npop\npop\njump\n";
+        let (_, output, _) = debug_session(source, commands);
+        assert!(output.contains("there is no register r40\n"));
+        assert!(output.contains("invalid value `x`\n"));
+        assert!(output.contains("there is no stack entry 5\n"));
+        assert!(output.contains("the stack is empty\n"));
+        assert!(output.contains("jump is missing an operand, see help\n"));
+    }
+
+    #[test]
+    fn test_debug_watchpoints() {
+        let source = "LOAD r1 1\nLOAD r2 2\nLOAD r1 1\nLOAD r3 3\nLOCKR r4\nST8 r1 r5\nPUSHRTS r3";
+        let commands = "watch r2\nwatch lock r4\nwatch mem 0 1\nwatch stack\nbreakpoints\n\
+                        continue\ncontinue\nset_register r2 9\ncontinue\ncontinue\ncontinue\n";
+        let (_, output, vm) = debug_session(source, commands);
+        assert!(output.contains("watchpoint 1 r2 = 0\n"));
+        assert!(output.contains("watchpoint 3 mem 0 1 = 0\n"));
+        assert!(output.contains("watchpoint 1 r2: 0 -> 2\n=>     2  01 01 00 01  LOAD r1 1\n"));
+        // r4 holds 0, so LOCKR r4 locks r0 and the lock of r4 never changes
+        assert!(output.contains("watchpoint 3 mem 0 1: 0 -> 1\n"));
+        assert!(output.contains("watchpoint 4 stack: 1 -> 2\n"));
+        assert!(!output.contains("watchpoint 1 r2: 2 -> 9"));
+        assert_eq!(vm.registers[2].content, 9);
+
+        // watchpoints don't stop execution when nothing changes
+        let (result, _, _) = debug_session("LOAD r1 1\nHLT", "watch r2\ncontinue\n");
+        assert_eq!(result, Ok(Some(0)));
+    }
+
+    #[test]
+    fn test_debug_conditional_breakpoints() {
+        let source = "LOAD r0 0\nLOAD r1 10\nLOAD r2 11\nLOAD r11 5\nLABEL 1\nINC r1\nBLT r1 r2 @1";
+        let commands = "break 5 if r10 == 3\nbreakpoints\ncontinue\nprint_registers_non_zero\n";
+        let (_, output, vm) = debug_session(source, commands);
+        assert!(output.contains("breakpoint 1 at set 5 if r10 == 3\n(perling) breakpoint 1 at"));
+        assert!(output.contains("breakpoint 1 at set 5\n=>     5  1c 01 00 00  INC r1 "));
+        assert_eq!(vm.registers[10].content, 3);
+
+        let (_, output, _) = debug_session(source, "break 5 if r10 = 3\nbreak 5 r10\n");
+        assert!(output.contains("unknown comparison `=`\n"));
+        assert!(output.contains("usage: break <set index|@label id> [if <condition>]\n"));
+    }
+
+    #[test]
+    fn test_debug_mode_prefixed_breakpoints() {
+        // ADD is prefixed by a MODE slot at set 1
+        let source = "LOAD r1 1\nADD $2 r1 r2\nHLT";
+        let (_, output, vm) = debug_session(source, "break 2\ncontinue\n");
+        assert!(output.contains("breakpoint 1 at set 1\n(perling) breakpoint 1 at set 1\n"));
+        assert_eq!(vm.program_set_counter, 1);
+        assert_eq!(vm.registers[2].content, 0);
+    }
+
+    #[test]
+    fn test_debug_invalid_input() {
+        let output = SharedBuffer::default();
+        let mut debugger = DebugEngine::with_output(output.clone());
+        let mut vm = VM::with_program(assemble("LOAD r1 1\nHLT").unwrap());
+        let result = debugger.debug(&mut vm, Cursor::new(b"\xff\xfe\nstep\n".to_vec()));
+        assert_eq!(result, Ok(None));
+        assert!(output.to_string_lossy().contains("commands have to be valid UTF-8\n"));
+        assert_eq!(vm.registers[1].content, 1);
+    }
+
+    #[test]
+    fn test_debug_enforces_limits() {
+        // PUSHRTS r1, JMP r2 back to set 0
+        let mut vm = VM::with_program(vec![19, 1, 0, 0, 5, 2, 0, 0]);
+        vm.registers[2].content = 3;
+        vm.set_limits(Limits {
+            max_instructions: Some(50),
+            ..Limits::default()
+        });
+        let mut debugger = DebugEngine::with_output(SharedBuffer::default());
+        let err = debugger.debug(&mut vm, Cursor::new("continue\n")).unwrap_err();
+        assert_eq!(err.kind, VmErrorKind::LimitExceeded(Limit::Instructions(50)));
+
+        vm.reset_program();
+        vm.set_limits(Limits {
+            max_stack_depth: Some(4),
+            ..Limits::default()
+        });
+        let err = debugger.debug(&mut vm, Cursor::new("continue\n")).unwrap_err();
+        assert_eq!(err.kind, VmErrorKind::LimitExceeded(Limit::StackDepth(4)));
+    }
+
+    #[test]
+    fn test_debug_commands() {
+        let commands = "break x\nbreak @9\ndelete 4\nfoo\nbreakpoints\n";
+        let (_, output, _) = debug_session("HLT", commands);
+        assert!(output.contains("invalid set index `x`\n"));
+        assert!(output.contains("label 9 is not defined\n"));
+        assert!(output.contains("no breakpoint 4\n"));
+        assert!(output.contains("cant find command\n"));
+
+        let (_, output, _) = debug_session("LABEL loop\nHLT", "break @loop\n");
+        assert!(output.contains("invalid label id `loop`, label names are not kept"));
+
+        let mut vm = VM::with_program(vec![0, 0, 0, 0]);
+        vm.program_set_counter = 1;
+        assert_eq!(location(&vm), "=> end of program\n");
+    }
+}
@@ -0,0 +1,330 @@
+use crate::instructions::{
+    Opcode, Operand, INSTRUCTION_SIZE, MODE_DIRECT, MODE_IMMEDIATE, MODE_INDIRECT, TARGET_LABEL,
+};
+use crate::register::REGISTER;
+use std::convert::TryFrom;
+use std::fmt;
+
+/// an instruction slot decoded back into its opcode and operands
+#[derive(Debug, Clone, PartialEq)]
+pub struct DisassembledInstruction {
+    /// program_set_counter value of the slot
+    pub set_index: i32,
+
+    /// raw bytes of the instruction, wide instructions span more than one slot
+    pub bytes: Vec<u8>,
+
+    pub opcode: Opcode,
+
+    /// decoded operands in the order given by Opcode::operands
+    pub operands: Vec<(Operand, i32)>,
+
+    /// addressing modes of a MODE slot folded into the instruction, it is the first slot
+    pub modes: Option<[u8; 3]>,
+}
+
+impl DisassembledInstruction {
+    /// number of bytes the instruction takes up when it is complete
+    pub fn size(&self) -> usize {
+        let prefix = if self.modes.is_some() { INSTRUCTION_SIZE } else { 0 };
+        return prefix + self.opcode.slots() * INSTRUCTION_SIZE;
+    }
+
+    /// returns the addressing mode of every decoded operand
+    pub fn operand_modes(&self) -> Vec<u8> {
+        let modes = self.modes.unwrap_or([MODE_INDIRECT; 3]);
+        let mut position = 0;
+        let mut operand_modes = vec![];
+        for (operand, _) in self.operands.iter() {
+            operand_modes.push(modes.get(position).copied().unwrap_or(MODE_INDIRECT));
+            position += operand.width();
+        }
+        return operand_modes;
+    }
+
+    /// returns true when the program ends before the instruction is complete
+    pub fn is_partial(&self) -> bool {
+        return self.bytes.len() < self.size();
+    }
+
+    /// describes the registers the operands refer to, resolving indirect registers when
+    /// the register contents are given
+    pub fn annotation(&self, registers: Option<&[REGISTER]>) -> String {
+        let mut notes = vec![];
+        if self.opcode == Opcode::IGL {
+            notes.push(format!("illegal opcode {:#04x}", self.bytes[0]));
+        }
+        if self.is_partial() {
+            notes.push(format!(
+                "partial instruction, {} of {} bytes",
+                self.bytes.len(),
+                self.size()
+            ));
+        }
+
+        for (operand, value) in self.operands.iter() {
+            if *operand == Operand::TargetKind && *value > TARGET_LABEL as i32 {
+                notes.push(format!("invalid target kind {}", value));
+            }
+        }
+
+        let mut reads = vec![];
+        let mut writes = vec![];
+        for ((operand, value), mode) in self.operands.iter().zip(self.operand_modes()) {
+            let description = match (mode, registers) {
+                (MODE_IMMEDIATE, _) => continue,
+                (MODE_DIRECT, Some(registers)) => match registers.get(*value as usize) {
+                    Some(register) => format!("r{} ({})", value, register.content),
+                    None => format!("r{} (out of range)", value),
+                },
+                (MODE_DIRECT, None) => format!("r{}", value),
+                (_, Some(registers)) => describe_indirect(*value, registers),
+                (_, None) => format!("*r{}", value),
+            };
+            match operand {
+                Operand::Indirect => reads.push(description),
+                Operand::Pointer => writes.push(description),
+                _ => {}
+            }
+        }
+        if !reads.is_empty() || !writes.is_empty() {
+            let mut note = reads.join(", ");
+            if !writes.is_empty() {
+                if !note.is_empty() {
+                    note.push(' ');
+                }
+                note.push_str(&format!("-> {}", writes.join(", ")));
+            }
+            notes.push(note);
+        }
+        return notes.join("; ");
+    }
+}
+
+/// describes the register an indirect operand points to, e.g. `*r3 = r1 (500)`
+fn describe_indirect(register: i32, registers: &[REGISTER]) -> String {
+    let target = match registers.get(register as usize) {
+        Some(pointer) => pointer.content,
+        None => return format!("*r{} = out of range", register),
+    };
+    match usize::try_from(target).ok().and_then(|index| registers.get(index)) {
+        Some(value) => return format!("*r{} = r{} ({})", register, target, value.content),
+        None => return format!("*r{} = r{} (out of range)", register, target),
+    }
+}
+
+impl fmt::Display for DisassembledInstruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bytes: Vec<String> = self.bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        let mut text = self.opcode.to_string();
+        let mut target_kind = None;
+        for ((operand, value), mode) in self.operands.iter().zip(self.operand_modes()) {
+            match operand {
+                Operand::Indirect | Operand::Pointer if mode == MODE_IMMEDIATE => {
+                    text.push_str(&format!(" ${}", value))
+                }
+                Operand::Indirect | Operand::Pointer if mode == MODE_DIRECT => {
+                    text.push_str(&format!(" %r{}", value))
+                }
+                Operand::Indirect | Operand::Pointer | Operand::Register => {
+                    text.push_str(&format!(" r{}", value))
+                }
+                // printed along with the target, label ids are written as `@id`
+                Operand::TargetKind => target_kind = Some(*value as u8),
+                Operand::Long if target_kind == Some(TARGET_LABEL) => {
+                    text.push_str(&format!(" @{}", value))
+                }
+                _ => text.push_str(&format!(" {}", value)),
+            }
+        }
+        let annotation = self.annotation(None);
+        if annotation.is_empty() {
+            return write!(f, "{:>5}  {:<11}  {}", self.set_index, bytes.join(" "), text);
+        }
+        return write!(
+            f,
+            "{:>5}  {:<11}  {:<20} ; {}",
+            self.set_index,
+            bytes.join(" "),
+            text,
+            annotation
+        );
+    }
+}
+
+/// decodes a program into its instructions
+pub fn disassemble(program: &[u8]) -> Vec<DisassembledInstruction> {
+    let mut instructions = vec![];
+    let mut position = 0;
+    while position < program.len() {
+        let set_index = (position / INSTRUCTION_SIZE) as i32;
+        // a MODE slot is shown as part of the instruction it prefixes
+        let prefixed = position + INSTRUCTION_SIZE;
+        let is_mode = Opcode::from(program[position]) == Opcode::MODE;
+        let modes = match program.get(prefixed).map(|code| Opcode::from(*code)) {
+            Some(opcode) if is_mode && opcode != Opcode::MODE => {
+                Some([program[position + 1], program[position + 2], program[position + 3]])
+            }
+            _ => None,
+        };
+        let start = if modes.is_some() { prefixed } else { position };
+        let size = Opcode::from(program[start]).slots() * INSTRUCTION_SIZE;
+        let end = program.len().min(start + size);
+        let mut instruction = disassemble_slot(set_index, &program[start..end]);
+        if modes.is_some() {
+            instruction.bytes = program[position..end].to_vec();
+            instruction.modes = modes;
+        }
+        instructions.push(instruction);
+        position = end;
+    }
+    return instructions;
+}
+
+/// decodes the slots of a single instruction, only operands that are fully present are decoded
+fn disassemble_slot(set_index: i32, slot: &[u8]) -> DisassembledInstruction {
+    let opcode = Opcode::from(slot[0]);
+    let mut operands = vec![];
+    let mut position = 1;
+    for operand in opcode.operands() {
+        if *operand == Operand::Long {
+            position = INSTRUCTION_SIZE;
+        }
+        if position + operand.width() > slot.len() {
+            break;
+        }
+        let value = match operand {
+            Operand::Word => ((slot[position] as i32) << 8) | slot[position + 1] as i32,
+            Operand::Long => i32::from_be_bytes([
+                slot[position],
+                slot[position + 1],
+                slot[position + 2],
+                slot[position + 3],
+            ]),
+            _ => slot[position] as i32,
+        };
+        operands.push((*operand, value));
+        position += operand.width();
+    }
+    return DisassembledInstruction {
+        set_index,
+        bytes: slot.to_vec(),
+        opcode,
+        operands,
+        modes: None,
+    };
+}
+
+/// disassembles a program into a listing with one instruction per line
+pub fn listing(program: &[u8]) -> String {
+    let mut text = String::new();
+    for instruction in disassemble(program) {
+        text.push_str(&instruction.to_string());
+        text.push('\n');
+    }
+    return text;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::Image;
+
+    #[test]
+    fn test_disassemble_print_example() {
+        let image = Image::decode(include_bytes!("../examples/print.perling.bin")).unwrap();
+        let instructions = disassemble(&image.code);
+        assert_eq!(instructions.len(), 7);
+        assert_eq!(instructions[0].opcode, Opcode::LOAD);
+        assert_eq!(
+            instructions[0].operands,
+            vec![(Operand::Register, 0), (Operand::Word, 411)]
+        );
+        assert_eq!(instructions[6].set_index, 6);
+        assert_eq!(
+            instructions[6].to_string(),
+            "    6  08 03 05 01  VMCALL r3 r5 r1      ; *r3, *r5, *r1"
+        );
+    }
+
+    #[test]
+    fn test_disassemble_illegal_and_partial() {
+        let instructions = disassemble(&[200, 0, 0, 0, 17, 1, 3]);
+        assert_eq!(instructions[0].opcode, Opcode::IGL);
+        assert_eq!(instructions[0].annotation(None), "illegal opcode 0xc8");
+        assert!(instructions[1].is_partial());
+        assert_eq!(
+            instructions[1].annotation(None),
+            "partial instruction, 3 of 4 bytes; -> *r3"
+        );
+
+        let instructions = disassemble(&[1, 2, 0]);
+        assert_eq!(instructions[0].operands, vec![(Operand::Register, 2)]);
+    }
+
+    #[test]
+    fn test_disassemble_wide_load() {
+        let instructions = disassemble(&[24, 1, 0, 0, 255, 255, 255, 254, 0, 0, 0, 0, 24, 2, 0, 0, 1]);
+        assert_eq!(instructions.len(), 3);
+        assert_eq!(instructions[0].operands, vec![(Operand::Register, 1), (Operand::Long, -2)]);
+        assert_eq!(instructions[1].set_index, 2);
+        assert!(instructions[2].is_partial());
+        assert_eq!(instructions[2].operands, vec![(Operand::Register, 2)]);
+        assert_eq!(
+            instructions[2].annotation(None),
+            "partial instruction, 5 of 8 bytes"
+        );
+    }
+
+    #[test]
+    fn test_disassemble_branches() {
+        let instructions = disassemble(&[48, 1, 2, 1, 0, 0, 0, 3, 46, 1, 2, 0, 0, 0, 0, 9, 47, 1, 2, 5]);
+        assert_eq!(instructions.len(), 3);
+        assert_eq!(
+            instructions[0].to_string(),
+            "    0  30 01 02 01 00 00 00 03  BLT r1 r2 @3         ; *r1, *r2"
+        );
+        assert_eq!(
+            instructions[1].to_string(),
+            "    2  2e 01 02 00 00 00 00 09  BEQ r1 r2 9          ; *r1, *r2"
+        );
+        assert_eq!(
+            instructions[2].annotation(None),
+            "partial instruction, 4 of 8 bytes; invalid target kind 5; *r1, *r2"
+        );
+    }
+
+    #[test]
+    fn test_disassemble_addressing_modes() {
+        let program = [64, 1, 2, 2, 2, 5, 1, 2, 64, 2, 1, 0, 48, 1, 10, 0, 0, 0, 0, 6, 64, 0, 0, 0];
+        let instructions = disassemble(&program);
+        assert_eq!(instructions.len(), 3);
+        assert_eq!(instructions[0].modes, Some([1, 2, 2]));
+        assert_eq!(instructions[0].size(), 8);
+        assert_eq!(
+            instructions[0].to_string(),
+            "    0  40 01 02 02 02 05 01 02  ADD $5 %r1 %r2       ; r1 -> r2"
+        );
+        assert_eq!(instructions[1].set_index, 2);
+        assert!(instructions[1].to_string().contains("BLT %r1 $10 6"));
+        assert_eq!(instructions[2].opcode, Opcode::MODE);
+        assert_eq!(instructions[2].modes, None);
+
+        let mut registers = [REGISTER { content: 0, locked: false }; 32];
+        registers[1].content = 7;
+        assert_eq!(instructions[0].annotation(Some(&registers)), "r1 (7) -> r2 (0)");
+    }
+
+    #[test]
+    fn test_annotation_with_registers() {
+        let mut registers = [REGISTER { content: 0, locked: false }; 32];
+        registers[0].content = 500;
+        registers[2].content = 0;
+        registers[4].content = 40;
+        let instructions = disassemble(&[2, 2, 2, 4]);
+        assert_eq!(
+            instructions[0].annotation(Some(&registers)),
+            "*r2 = r0 (500), *r2 = r0 (500) -> *r4 = r40 (out of range)"
+        );
+    }
+}
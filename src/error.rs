@@ -0,0 +1,146 @@
+use crate::limits::Limit;
+use std::fmt;
+
+/// the kind of fault raised while executing a program
+#[derive(Debug, Clone, PartialEq)]
+pub enum VmErrorKind {
+    /// DIV or MOD was executed with a divisor of 0
+    DivisionByZero,
+
+    /// the result of an arithmetic opcode did not fit in an i32 while the VM traps on overflow
+    ArithmeticOverflow,
+
+    /// an operand referred to a register that does not exist
+    RegisterOutOfRange(i32),
+
+    /// a value was popped from an empty stack
+    StackUnderflow,
+
+    /// RET was executed outside of a CALL
+    CallStackUnderflow,
+
+    /// a load or store touched bytes outside of the memory
+    MemoryOutOfBounds { address: i32, width: usize },
+
+    /// an instruction tried to write to a locked register
+    LockedRegister(usize),
+
+    /// VMCALL was executed with an unknown call number
+    InvalidVmCall(i32),
+
+    /// the program ended in the middle of an instruction
+    TruncatedInstruction,
+
+    /// the opcode byte does not map to a known instruction
+    IllegalOpcode(u8),
+
+    /// MODE gave an operand an addressing mode it does not support
+    InvalidAddressingMode(u8),
+
+    /// GOTO or JMPTL referred to a label the program does not define
+    UnknownLabel(i32),
+
+    /// the program defines the same label id more than once
+    DuplicateLabel(i32),
+
+    /// a jump moved the program_set_counter before the start of the program
+    InvalidJumpTarget(i32),
+
+    /// the kind byte of a jump target is neither a set index nor a label id
+    InvalidTargetKind(u8),
+
+    /// a VM call registered by the host failed
+    CallFailed(String),
+
+    /// reading from or writing to a guest stream failed
+    Io(String),
+
+    /// the program ran into one of the limits set on the VM
+    LimitExceeded(Limit),
+}
+
+/// a fault raised by the VM along with the location it happened at
+#[derive(Debug, Clone, PartialEq)]
+pub struct VmError {
+    pub kind: VmErrorKind,
+
+    /// program_set_counter value of the faulting instruction
+    pub program_set_counter: i32,
+
+    /// program_counter value when the fault was raised
+    pub program_counter: usize,
+}
+
+impl VmError {
+    pub fn new(kind: VmErrorKind, program_set_counter: i32, program_counter: usize) -> VmError {
+        VmError {
+            kind,
+            program_set_counter,
+            program_counter,
+        }
+    }
+
+    /// returns true if the program was stopped by a limit rather than a fault of its own
+    pub fn is_limit_exceeded(&self) -> bool {
+        return matches!(self.kind, VmErrorKind::LimitExceeded(_));
+    }
+}
+
+impl fmt::Display for VmErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VmErrorKind::DivisionByZero => write!(f, "division by zero"),
+            VmErrorKind::ArithmeticOverflow => write!(f, "arithmetic overflow"),
+            VmErrorKind::RegisterOutOfRange(index) => {
+                write!(f, "register index {} is out of range", index)
+            }
+            VmErrorKind::StackUnderflow => write!(f, "pop from an empty stack"),
+            VmErrorKind::CallStackUnderflow => write!(f, "RET without a CALL"),
+            VmErrorKind::MemoryOutOfBounds { address, width } => write!(
+                f,
+                "{} byte access at address {} is out of bounds",
+                width, address
+            ),
+            VmErrorKind::LockedRegister(index) => write!(f, "R{} is locked", index),
+            VmErrorKind::InvalidVmCall(call) => write!(f, "invalid VM call {}", call),
+            VmErrorKind::TruncatedInstruction => write!(f, "truncated instruction"),
+            VmErrorKind::IllegalOpcode(code) => write!(f, "illegal opcode {}", code),
+            VmErrorKind::InvalidAddressingMode(mode) => {
+                write!(f, "invalid addressing mode {}", mode)
+            }
+            VmErrorKind::UnknownLabel(id) => write!(f, "label {} is not defined", id),
+            VmErrorKind::DuplicateLabel(id) => write!(f, "label {} is defined more than once", id),
+            VmErrorKind::InvalidJumpTarget(target) => write!(f, "invalid jump target {}", target),
+            VmErrorKind::InvalidTargetKind(kind) => write!(f, "invalid jump target kind {}", kind),
+            VmErrorKind::CallFailed(message) => write!(f, "VM call failed: {}", message),
+            VmErrorKind::Io(message) => write!(f, "guest stream error: {}", message),
+            VmErrorKind::LimitExceeded(limit) => write!(f, "{}", limit),
+        }
+    }
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} at program set: {} program counter: {}",
+            self.kind, self.program_set_counter, self.program_counter
+        )
+    }
+}
+
+impl std::error::Error for VmError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vm_error_display() {
+        let err = VmError::new(VmErrorKind::DivisionByZero, 3, 14);
+        assert_eq!(
+            err.to_string(),
+            "division by zero at program set: 3 program counter: 14"
+        );
+    }
+}
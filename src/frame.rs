@@ -0,0 +1,12 @@
+/// stores information about an active CALL
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FRAME {
+    /// location(program_set_counter value) of the CALL instruction
+    pub call_location: i32,
+
+    /// location RET continues at
+    pub return_location: i32,
+
+    /// location the CALL jumped to
+    pub target: i32,
+}
@@ -0,0 +1,584 @@
+use crate::debug::{instruction_start, DebugEngine, StopReason};
+use crate::error::{VmError, VmErrorKind};
+use crate::instructions::INSTRUCTION_SIZE;
+use crate::vm::VM;
+use crate::watch::WatchTarget;
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+
+/// number of instructions executed between checks for an interrupt from the client
+const INTERRUPT_CHECK_INTERVAL: u64 = 1024;
+
+/// registers exposed after the VM registers, in this order
+const EXTRA_REGISTERS: [&str; 6] = ["pc", "set", "h0", "h1", "h2", "sp"];
+
+/// signals reported to the client
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGABRT: u8 = 6;
+const SIGFPE: u8 = 8;
+const SIGSEGV: u8 = 11;
+
+/// GDB remote serial protocol stub for a single client connection
+///
+/// Registers are every VM register followed by `pc` (byte position of the next instruction),
+/// `set` (its program_set_counter), the hidden registers `h0` to `h2` and `sp` (the number of
+/// values on the stack), all 32 bit big endian. Memory packets access the data memory,
+/// breakpoint addresses are byte positions in the program and `monitor stack` prints the stack.
+pub struct GdbStub {
+    stream: TcpStream,
+    debugger: DebugEngine,
+
+    /// bytes received but not yet handled
+    buffer: Vec<u8>,
+
+    /// exit code of the program once it finished
+    exit_code: Option<i32>,
+}
+
+impl GdbStub {
+    pub fn new(stream: TcpStream) -> GdbStub {
+        // packets are small and answered one at a time, waiting to batch them only adds latency
+        let _ = stream.set_nodelay(true);
+        GdbStub {
+            stream,
+            debugger: DebugEngine::with_output(io::sink()),
+            buffer: vec![],
+            exit_code: None,
+        }
+    }
+
+    /// serves the client until it detaches, kills the program or the program exits. Returns
+    /// the exit code of the program or None if it did not finish
+    pub fn serve(&mut self, vm: &mut VM) -> io::Result<Option<i32>> {
+        vm.debugger_attached = true;
+        let result = self.session(vm);
+        vm.debugger_attached = false;
+        return result;
+    }
+
+    fn session(&mut self, vm: &mut VM) -> io::Result<Option<i32>> {
+        if let Err(e) = vm.resolve_labels() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, e.to_string()));
+        }
+        while let Some(packet) = self.read_packet()? {
+            let reply = match packet.as_bytes().first() {
+                Some(b'c') => self.run(vm, false)?,
+                Some(b's') => self.run(vm, true)?,
+                Some(b'D') => {
+                    self.send("OK")?;
+                    return Ok(None);
+                }
+                Some(b'k') => return Ok(None),
+                _ => self.handle_packet(&packet, vm),
+            };
+            self.send(&reply)?;
+            if self.exit_code.is_some() {
+                return Ok(self.exit_code);
+            }
+        }
+        return Ok(None);
+    }
+
+    /// answers a packet that does not resume execution
+    fn handle_packet(&mut self, packet: &str, vm: &mut VM) -> String {
+        // empty packets and ones starting with a multibyte character are no known command
+        let command = match packet.get(..1) {
+            Some(command) => command,
+            None => return String::new(),
+        };
+        let arguments = &packet[1..];
+        match command {
+            "?" => return format!("S{:02x}", SIGTRAP),
+            "g" => return registers(vm).iter().map(|value| format!("{:08x}", *value as u32)).collect(),
+            "G" => {
+                let values = match parse_words(arguments) {
+                    Some(values) if values.len() == register_count(vm) => values,
+                    _ => return "E01".to_string(),
+                };
+                for (index, value) in values.into_iter().enumerate() {
+                    set_register(vm, index, value);
+                }
+                return "OK".to_string();
+            }
+            "p" => match usize::from_str_radix(arguments, 16).ok().and_then(|i| registers(vm).get(i).copied()) {
+                Some(value) => return format!("{:08x}", value as u32),
+                None => return "E01".to_string(),
+            },
+            "P" => {
+                let written = arguments.split_once('=').and_then(|(index, value)| {
+                    let index = usize::from_str_radix(index, 16).ok()?;
+                    let value = parse_words(value)?;
+                    match value.as_slice() {
+                        [value] => set_register(vm, index, *value).then_some(()),
+                        _ => None,
+                    }
+                });
+                return reply_ok(written.is_some());
+            }
+            "m" => {
+                let range = parse_range(arguments).and_then(|(address, length)| memory_range(vm, address, length));
+                match range {
+                    Some(range) => return vm.memory.content[range].iter().map(|b| format!("{:02x}", b)).collect(),
+                    None => return "E01".to_string(),
+                }
+            }
+            "M" => {
+                let written = arguments.split_once(':').and_then(|(range, data)| {
+                    let (address, length) = parse_range(range)?;
+                    let bytes = parse_hex_bytes(data)?;
+                    let range = memory_range(vm, address, length)?;
+                    if bytes.len() != length {
+                        return None;
+                    }
+                    vm.memory.content[range].copy_from_slice(&bytes);
+                    return Some(());
+                });
+                return reply_ok(written.is_some());
+            }
+            "Z" | "z" => return self.breakpoint_packet(command == "Z", arguments, vm),
+            "H" => return "OK".to_string(),
+            "q" => return self.query(arguments, vm),
+            _ => return String::new(),
+        }
+    }
+
+    /// adds (Z) or removes (z) a software breakpoint (type 0) or a write watchpoint (type 2)
+    fn breakpoint_packet(&mut self, add: bool, arguments: &str, vm: &VM) -> String {
+        let fields: Vec<&str> = arguments.split(',').collect();
+        let (kind, address, length) = match fields.as_slice() {
+            [kind, address, length] => (*kind, address, length),
+            _ => return "E01".to_string(),
+        };
+        let (address, length) = match (i32::from_str_radix(address, 16), usize::from_str_radix(length, 16)) {
+            (Ok(address), Ok(length)) => (address, length),
+            _ => return "E01".to_string(),
+        };
+        match kind {
+            "0" => {
+                if address < 0 || !(address as usize).is_multiple_of(INSTRUCTION_SIZE) {
+                    return "E01".to_string();
+                }
+                let set_index = instruction_start(address / INSTRUCTION_SIZE as i32, vm);
+                match (add, self.debugger.breakpoint_at(set_index)) {
+                    (true, _) => {
+                        self.debugger.add_breakpoint(set_index, None);
+                    }
+                    (false, Some(breakpoint)) => {
+                        self.debugger.delete_breakpoint(breakpoint.id);
+                    }
+                    (false, None) => {}
+                }
+                return "OK".to_string();
+            }
+            "2" => {
+                let target = WatchTarget::Memory { address, width: length };
+                if !matches!(length, 1 | 2 | 4) || target.value(vm).is_none() {
+                    return "E01".to_string();
+                }
+                let existing = self.debugger.watchpoints.iter().find(|w| w.target == target).map(|w| w.id);
+                match (add, existing) {
+                    (true, None) => {
+                        self.debugger.add_watchpoint(target, vm);
+                    }
+                    (false, Some(id)) => {
+                        self.debugger.delete_breakpoint(id);
+                    }
+                    _ => {}
+                }
+                return "OK".to_string();
+            }
+            _ => return String::new(),
+        }
+    }
+
+    /// answers the general queries a client sends while connecting
+    fn query(&mut self, query: &str, vm: &VM) -> String {
+        if query.starts_with("Supported") {
+            return "PacketSize=4000;qXfer:features:read+".to_string();
+        }
+        if let Some(range) = query.strip_prefix("Xfer:features:read:target.xml:") {
+            let xml = target_description(vm);
+            let (offset, length) = match parse_range(range) {
+                Some((offset, length)) if offset >= 0 => (offset as usize, length),
+                _ => return "E01".to_string(),
+            };
+            let start = offset.min(xml.len());
+            let end = start.saturating_add(length).min(xml.len());
+            let marker = if end == xml.len() { 'l' } else { 'm' };
+            return format!("{}{}", marker, &xml[start..end]);
+        }
+        if let Some(command) = query.strip_prefix("Rcmd,") {
+            let command = match parse_hex_bytes(command) {
+                Some(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+                None => return "E01".to_string(),
+            };
+            let output = match command.trim() {
+                "stack" => {
+                    let values: Vec<String> = vm.stack.content.iter().map(|v| v.to_string()).collect();
+                    format!("stack: [{}]\n", values.join(", "))
+                }
+                _ => "monitor commands: stack\n".to_string(),
+            };
+            return output.bytes().map(|b| format!("{:02x}", b)).collect();
+        }
+        match query {
+            "Attached" => return "1".to_string(),
+            "C" => return "QC1".to_string(),
+            "fThreadInfo" => return "m1".to_string(),
+            "sThreadInfo" => return "l".to_string(),
+            _ => return String::new(),
+        }
+    }
+
+    /// steps or continues and returns the stop reply
+    fn run(&mut self, vm: &mut VM, single_step: bool) -> io::Result<String> {
+        let mut executed: u64 = 0;
+        let reason = loop {
+            let reason = self.debugger.step(vm);
+            if single_step || reason != StopReason::Step {
+                break reason;
+            }
+            executed += 1;
+            if executed.is_multiple_of(INTERRUPT_CHECK_INTERVAL) && self.interrupted()? {
+                return Ok(format!("S{:02x}", SIGINT));
+            }
+        };
+        match reason {
+            StopReason::Exit(code) => {
+                // the reply only carries the low byte, serve returns the whole code
+                self.exit_code = Some(code);
+                return Ok(format!("W{:02x}", code as u8));
+            }
+            StopReason::Watchpoint { id, .. } => {
+                let watchpoint = self.debugger.watchpoints.iter().find(|w| w.id == id).unwrap();
+                match watchpoint.target {
+                    WatchTarget::Memory { address, .. } => {
+                        return Ok(format!("T{:02x}watch:{:x};", SIGTRAP, address))
+                    }
+                    _ => return Ok(format!("S{:02x}", SIGTRAP)),
+                }
+            }
+            StopReason::Fault(e) => return Ok(format!("S{:02x}", fault_signal(&e))),
+            _ => return Ok(format!("S{:02x}", SIGTRAP)),
+        }
+    }
+
+    /// returns true if the client sent an interrupt (0x03) while the program was running
+    fn interrupted(&mut self) -> io::Result<bool> {
+        self.stream.set_nonblocking(true)?;
+        let mut byte = [0];
+        let result = self.stream.read(&mut byte);
+        self.stream.set_nonblocking(false)?;
+        match result {
+            Ok(1) => {
+                if byte[0] == 0x03 {
+                    return Ok(true);
+                }
+                self.buffer.push(byte[0]);
+                return Ok(false);
+            }
+            Ok(_) => return Ok(false),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+            Err(e) => return Err(e),
+        }
+    }
+
+    /// reads the next packet, acknowledging it. Returns None when the client disconnects
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            if let Some(start) = self.buffer.iter().position(|b| *b == b'$') {
+                let end = self.buffer[start..].iter().position(|b| *b == b'#').map(|end| start + end);
+                if let Some(end) = end.filter(|end| end + 2 < self.buffer.len()) {
+                    let data = self.buffer[start + 1..end].to_vec();
+                    let checksum = std::str::from_utf8(&self.buffer[end + 1..end + 3])
+                        .ok()
+                        .and_then(|checksum| u8::from_str_radix(checksum, 16).ok());
+                    self.buffer.drain(..end + 3);
+                    if checksum != Some(checksum_of(&data)) {
+                        self.stream.write_all(b"-")?;
+                        continue;
+                    }
+                    self.stream.write_all(b"+")?;
+                    return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+                }
+            } else {
+                // acknowledgements and interrupts outside of packets carry nothing to answer
+                self.buffer.clear();
+            }
+            let mut chunk = [0; 1024];
+            let read = self.stream.read(&mut chunk)?;
+            if read == 0 {
+                return Ok(None);
+            }
+            self.buffer.extend_from_slice(&chunk[..read]);
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+        self.stream.write_all(packet.as_bytes())?;
+        return self.stream.flush();
+    }
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    return data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+}
+
+fn reply_ok(ok: bool) -> String {
+    return if ok { "OK" } else { "E01" }.to_string();
+}
+
+/// signal reported for a fault
+fn fault_signal(e: &VmError) -> u8 {
+    match e.kind {
+        VmErrorKind::DivisionByZero | VmErrorKind::ArithmeticOverflow => return SIGFPE,
+        VmErrorKind::MemoryOutOfBounds { .. } | VmErrorKind::RegisterOutOfRange(_) => return SIGSEGV,
+        VmErrorKind::IllegalOpcode(_) | VmErrorKind::InvalidAddressingMode(_) => return SIGILL,
+        _ => return SIGABRT,
+    }
+}
+
+fn register_count(vm: &VM) -> usize {
+    return vm.registers.len() + EXTRA_REGISTERS.len();
+}
+
+/// returns the values of every exposed register
+fn registers(vm: &VM) -> Vec<i32> {
+    let mut values: Vec<i32> = vm.registers.iter().map(|register| register.content).collect();
+    values.push(vm.program_set_counter.wrapping_mul(INSTRUCTION_SIZE as i32));
+    values.push(vm.program_set_counter);
+    values.push(vm.remainder);
+    values.push(vm.input_status);
+    values.push(vm.flags);
+    values.push(vm.stack.content.len() as i32);
+    return values;
+}
+
+/// sets an exposed register, returning false if it does not exist or can't be written
+fn set_register(vm: &mut VM, index: usize, value: i32) -> bool {
+    if let Some(register) = vm.registers.get_mut(index) {
+        register.content = value;
+        return true;
+    }
+    match EXTRA_REGISTERS.get(index - vm.registers.len()).copied() {
+        Some("pc") => vm.program_set_counter = value / INSTRUCTION_SIZE as i32,
+        Some("set") => vm.program_set_counter = value,
+        Some("h0") => vm.remainder = value,
+        Some("h1") => vm.input_status = value,
+        Some("h2") => vm.flags = value,
+        // the stack depth can't be written
+        _ => return false,
+    }
+    vm.program_counter = (vm.program_set_counter.max(0) as usize) * INSTRUCTION_SIZE;
+    return true;
+}
+
+/// describes the registers to the client
+fn target_description(vm: &VM) -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\"?><!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+         <target><feature name=\"org.perling.core\">",
+    );
+    let names = (0..vm.registers.len()).map(|i| format!("r{}", i));
+    let names = names.chain(EXTRA_REGISTERS.iter().map(|name| name.to_string()));
+    for (number, name) in names.enumerate() {
+        let kind = if name == "pc" { "code_ptr" } else { "int32" };
+        xml.push_str(&format!(
+            "<reg name=\"{}\" bitsize=\"32\" type=\"{}\" regnum=\"{}\"/>",
+            name, kind, number
+        ));
+    }
+    xml.push_str("</feature></target>");
+    return xml;
+}
+
+/// parses `address,length` in hex
+fn parse_range(text: &str) -> Option<(i32, usize)> {
+    let (address, length) = text.split_once(',')?;
+    return Some((i32::from_str_radix(address, 16).ok()?, usize::from_str_radix(length, 16).ok()?));
+}
+
+/// returns the range of the memory accessed by a packet, None when it is out of bounds
+fn memory_range(vm: &VM, address: i32, length: usize) -> Option<std::ops::Range<usize>> {
+    let start = usize::try_from(address).ok()?;
+    let end = start.checked_add(length)?;
+    if end > vm.memory.content.len() {
+        return None;
+    }
+    return Some(start..end);
+}
+
+fn parse_hex_bytes(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    return (0..text.len()).step_by(2).map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok()).collect();
+}
+
+/// parses big endian 32 bit values
+fn parse_words(text: &str) -> Option<Vec<i32>> {
+    let bytes = parse_hex_bytes(text)?;
+    if !bytes.len().is_multiple_of(4) {
+        return None;
+    }
+    return Some(bytes.chunks(4).map(|word| i32::from_be_bytes([word[0], word[1], word[2], word[3]])).collect());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// scripted client sending packets and collecting the replies
+    struct Client {
+        stream: TcpStream,
+    }
+
+    impl Client {
+        fn send_raw(&mut self, bytes: &[u8]) {
+            self.stream.write_all(bytes).unwrap();
+        }
+
+        fn request(&mut self, data: &str) -> String {
+            self.send_raw(format!("${}#{:02x}", data, checksum_of(data.as_bytes())).as_bytes());
+            let mut ack = [0];
+            self.stream.read_exact(&mut ack).unwrap();
+            assert_eq!(ack[0], b'+');
+            if data == "k" {
+                return String::new();
+            }
+            return self.reply();
+        }
+
+        fn reply(&mut self) -> String {
+            let mut packet = vec![];
+            let mut byte = [0];
+            while packet.len() < 3 || packet[packet.len() - 3] != b'#' {
+                self.stream.read_exact(&mut byte).unwrap();
+                if packet.is_empty() && byte[0] != b'$' {
+                    continue;
+                }
+                packet.push(byte[0]);
+            }
+            self.send_raw(b"+");
+            let data = &packet[1..packet.len() - 3];
+            let checksum = u8::from_str_radix(std::str::from_utf8(&packet[packet.len() - 2..]).unwrap(), 16);
+            assert_eq!(checksum, Ok(checksum_of(data)));
+            return String::from_utf8(data.to_vec()).unwrap();
+        }
+    }
+
+    /// serves the program to a client running the script on another thread
+    fn gdb_session<F>(program: Vec<u8>, script: F) -> (io::Result<Option<i32>>, VM)
+    where
+        F: FnOnce(&mut Client) + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut client = Client { stream: TcpStream::connect(address).unwrap() };
+            script(&mut client);
+        });
+        let (stream, _) = listener.accept().unwrap();
+        let mut vm = VM::with_register_count(4);
+        vm.set_memory_size(16).unwrap();
+        vm.load_program(program);
+        let result = GdbStub::new(stream).serve(&mut vm);
+        client.join().unwrap();
+        return (result, vm);
+    }
+
+    #[test]
+    fn test_gdb_registers_and_memory() {
+        let program = assemble("LOAD r1 500\nHLT").unwrap();
+        let (result, vm) = gdb_session(program, |client| {
+            assert!(client.request("qSupported:multiprocess+").contains("qXfer:features:read+"));
+            assert_eq!(client.request("?"), "S05");
+            let xml = client.request("qXfer:features:read:target.xml:0,fff");
+            assert!(xml.starts_with("l<?xml"));
+            assert!(xml.contains("<reg name=\"r3\" bitsize=\"32\" type=\"int32\" regnum=\"3\"/>"));
+            assert!(xml.contains("<reg name=\"sp\" bitsize=\"32\" type=\"int32\" regnum=\"9\"/>"));
+            assert_eq!(client.request("g"), format!("{}00000001", "0".repeat(72)));
+
+            assert_eq!(client.request("s"), "S05");
+            assert_eq!(client.request("p1"), "000001f4");
+            assert_eq!(client.request("p4"), "00000004");
+            assert_eq!(client.request("P2=ffffffff"), "OK");
+            assert_eq!(client.request("P9=00000001"), "E01");
+            assert_eq!(client.request("pa"), "E01");
+
+            assert_eq!(client.request("M2,2:abcd"), "OK");
+            assert_eq!(client.request("m0,4"), "0000abcd");
+            assert_eq!(client.request("m0,20"), "E01");
+            assert_eq!(client.request("qRcmd,737461636b"), "737461636b3a205b305d0a");
+            assert_eq!(client.request("vMustReplyEmpty"), "");
+            assert_eq!(client.request("c"), "W00");
+        });
+        assert_eq!(result.unwrap(), Some(0));
+        assert_eq!(vm.registers[2].content, -1);
+        assert_eq!(vm.memory.content[2..4], [0xab, 0xcd]);
+    }
+
+    #[test]
+    fn test_gdb_breakpoints_and_watchpoints() {
+        let program = assemble("LOAD r1 1\nLOAD r2 2\nST8 r1 r0\nLOAD r3 3\nDIV r1 r0 r2").unwrap();
+        let (result, vm) = gdb_session(program, |client| {
+            assert_eq!(client.request("Z0,4,4"), "OK");
+            assert_eq!(client.request("Z0,5,4"), "E01");
+            assert_eq!(client.request("Z2,0,1"), "OK");
+            assert_eq!(client.request("Z2,10,4"), "E01");
+            assert_eq!(client.request("c"), "S05");
+            assert_eq!(client.request("p5"), "00000001");
+            assert_eq!(client.request("z0,4,4"), "OK");
+            // ST8 writes *r1 (1) to the address in *r0 (0)
+            assert_eq!(client.request("c"), "T05watch:0;");
+            assert_eq!(client.request("m0,1"), "01");
+            assert_eq!(client.request("z2,0,1"), "OK");
+            // DIV reads *r0 (0) as the divisor
+            assert_eq!(client.request("c"), "S08");
+            client.request("k");
+        });
+        assert_eq!(result.unwrap(), None);
+        assert_eq!(vm.program_set_counter, 4);
+        assert_eq!(vm.registers[3].content, 3);
+    }
+
+    #[test]
+    fn test_gdb_interrupt() {
+        // JMP to itself
+        let (result, vm) = gdb_session(vec![5, 0, 0, 0], |client| {
+            client.send_raw(b"$c#63");
+            let mut ack = [0];
+            client.stream.read_exact(&mut ack).unwrap();
+            client.send_raw(&[0x03]);
+            assert_eq!(client.reply(), "S02");
+            assert_eq!(client.request("D"), "OK");
+        });
+        assert_eq!(result.unwrap(), None);
+        assert_eq!(vm.program_set_counter, 0);
+        assert!(!vm.debugger_attached);
+    }
+
+    #[test]
+    fn test_gdb_malformed_packets() {
+        let program = assemble("HLT").unwrap();
+        let (result, _) = gdb_session(program, |client| {
+            assert_eq!(client.request(""), "");
+            client.send_raw(b"$\xff\xfe#fd");
+            let mut ack = [0];
+            client.stream.read_exact(&mut ack).unwrap();
+            assert_eq!(ack[0], b'+');
+            assert_eq!(client.reply(), "");
+            let xml = client.request("qXfer:features:read:target.xml:10,ffffffffffffffff");
+            assert!(xml.starts_with('l'));
+            assert_eq!(client.request("c"), "W00");
+        });
+        assert_eq!(result.unwrap(), Some(0));
+    }
+}
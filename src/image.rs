@@ -0,0 +1,507 @@
+use crate::instructions::INSTRUCTION_SIZE;
+use crate::register::REGISTER;
+use std::convert::TryFrom;
+use std::fmt;
+
+/// magic bytes every perling image starts with
+pub const MAGIC: [u8; 4] = *b"PRLG";
+
+/// newest version of the image format this crate reads, images with a newer version are rejected
+pub const FORMAT_VERSION: u16 = 2;
+
+/// first version of the image format, it has no register count
+const FIRST_VERSION: u16 = 1;
+
+/// size of the fixed header of a version 1 image (magic, version, section count and entry point)
+const HEADER_SIZE: usize = 12;
+
+/// size of the register count version 2 adds to the header
+const REGISTER_COUNT_SIZE: usize = 2;
+
+/// size of the checksum at the end of the image
+const CHECKSUM_SIZE: usize = 4;
+
+/// size of a single entry of the register section
+const REGISTER_ENTRY_SIZE: usize = 7;
+
+/// size of a single line entry of the debug section
+const LINE_ENTRY_SIZE: usize = 8;
+
+/// section ids, sections with an unknown id are skipped by the loader
+pub const SECTION_CODE: u8 = 1;
+pub const SECTION_DATA: u8 = 2;
+pub const SECTION_REGISTERS: u8 = 3;
+pub const SECTION_DEBUG: u8 = 4;
+
+/// a program along with everything needed to start it
+///
+/// The encoded form is big endian and laid out as
+/// ```text
+/// magic "PRLG" | version u16 | section count u16 | entry u32 | register count u16
+/// (section id u8 | length u32 | payload)*
+/// CRC-32 of everything before it u32
+/// ```
+/// Images without a register count are written as version 1, which has no register count field.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Image {
+    /// instructions of the program
+    pub code: Vec<u8>,
+
+    /// constant data of the program, omitted from the image when empty
+    pub data: Vec<u8>,
+
+    /// registers to set before the program starts, omitted from the image when empty
+    pub registers: Vec<(usize, REGISTER)>,
+
+    /// program_set_counter value execution starts at
+    pub entry: i32,
+
+    /// number of registers the program needs, 0 leaves the register count of the VM as it is
+    pub register_count: u16,
+
+    /// source lines of the instructions, omitted from the image when None
+    pub debug_info: Option<DebugInfo>,
+}
+
+/// maps the instructions of a program back to the source it was assembled from
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct DebugInfo {
+    /// path of the source file
+    pub source: String,
+
+    /// set index and line (starting at 1) of every instruction, ordered by set index
+    pub lines: Vec<(i32, u32)>,
+}
+
+impl DebugInfo {
+    /// returns the line of the instruction a set index belongs to
+    pub fn line_of(&self, set_index: i32) -> Option<u32> {
+        let next = self.lines.partition_point(|(index, _)| *index <= set_index);
+        return next.checked_sub(1).map(|entry| self.lines[entry].1);
+    }
+
+    /// returns the set index and line of the first instruction on or after a line
+    pub fn set_index_of(&self, line: u32) -> Option<(i32, u32)> {
+        return self.lines.iter().filter(|(_, l)| *l >= line).min_by_key(|(_, l)| *l).copied();
+    }
+}
+
+/// reasons an image can be rejected by the loader
+#[derive(Debug, Clone, PartialEq)]
+pub enum ImageError {
+    /// the file does not start with the image magic bytes
+    NotAnImage,
+
+    /// the image was written for another version of the format
+    UnsupportedVersion(u16),
+
+    /// the image ended before the header, a section or the checksum was complete
+    Truncated,
+
+    /// the checksum stored in the image does not match its content
+    ChecksumMismatch { stored: u32, computed: u32 },
+
+    /// the image has no code section
+    MissingCode,
+
+    /// a section appears more than once
+    DuplicateSection(u8),
+
+    /// the register section is not made of whole entries
+    MalformedRegisters,
+
+    /// the debug section does not hold a source path followed by whole line entries
+    MalformedDebugInfo,
+
+    /// the entry point is negative or past the end of the code
+    InvalidEntry(u32),
+
+    /// a preloaded register index does not fit in the u16 of its entry
+    RegisterIndexTooLarge(usize),
+
+    /// the source path of the debug section is longer than its u16 length allows
+    SourcePathTooLong(usize),
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageError::NotAnImage => write!(
+                f,
+                "not a perling image (headerless programs have to be loaded as legacy)"
+            ),
+            ImageError::UnsupportedVersion(version) => write!(
+                f,
+                "unsupported image version {}, this VM reads versions {} to {}",
+                version, FIRST_VERSION, FORMAT_VERSION
+            ),
+            ImageError::Truncated => write!(f, "image is truncated"),
+            ImageError::ChecksumMismatch { stored, computed } => write!(
+                f,
+                "image checksum {:08x} does not match its content ({:08x})",
+                stored, computed
+            ),
+            ImageError::MissingCode => write!(f, "image has no code section"),
+            ImageError::DuplicateSection(id) => write!(f, "image has section {} twice", id),
+            ImageError::MalformedRegisters => write!(f, "image register section is malformed"),
+            ImageError::MalformedDebugInfo => write!(f, "image debug section is malformed"),
+            ImageError::InvalidEntry(entry) => {
+                write!(f, "image entry point {} is outside of its code", entry)
+            }
+            ImageError::RegisterIndexTooLarge(index) => write!(
+                f,
+                "register r{} can't be preloaded by an image, the highest is r{}",
+                index,
+                u16::MAX
+            ),
+            ImageError::SourcePathTooLong(length) => write!(
+                f,
+                "source path of {} bytes is too long for the debug section",
+                length
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ImageError {}
+
+impl Image {
+    /// wraps a headerless program, as written before the image format existed
+    pub fn from_legacy(code: Vec<u8>) -> Image {
+        return Image {
+            code,
+            ..Image::default()
+        };
+    }
+
+    /// returns true if the bytes start with the image magic
+    pub fn is_image(bytes: &[u8]) -> bool {
+        return bytes.starts_with(&MAGIC);
+    }
+
+    /// decodes an image, falling back to a headerless program when allow_legacy is set
+    pub fn load(bytes: Vec<u8>, allow_legacy: bool) -> Result<Image, ImageError> {
+        if !Image::is_image(&bytes) && allow_legacy {
+            return Ok(Image::from_legacy(bytes));
+        }
+        return Image::decode(&bytes);
+    }
+
+    /// decodes an image, checking its magic, version and checksum
+    pub fn decode(bytes: &[u8]) -> Result<Image, ImageError> {
+        if !Image::is_image(bytes) {
+            return Err(ImageError::NotAnImage);
+        }
+        if bytes.len() < 6 {
+            return Err(ImageError::Truncated);
+        }
+        let version = read_u16(bytes, 4);
+        if !(FIRST_VERSION..=FORMAT_VERSION).contains(&version) {
+            return Err(ImageError::UnsupportedVersion(version));
+        }
+        let header_size = match version {
+            FIRST_VERSION => HEADER_SIZE,
+            _ => HEADER_SIZE + REGISTER_COUNT_SIZE,
+        };
+        if bytes.len() < header_size + CHECKSUM_SIZE {
+            return Err(ImageError::Truncated);
+        }
+        let body_end = bytes.len() - CHECKSUM_SIZE;
+        let stored = read_u32(bytes, body_end);
+        let computed = crc32(&bytes[..body_end]);
+        if stored != computed {
+            return Err(ImageError::ChecksumMismatch { stored, computed });
+        }
+
+        let section_count = read_u16(bytes, 6);
+        let mut image = Image {
+            entry: read_u32(bytes, 8) as i32,
+            ..Image::default()
+        };
+        if version != FIRST_VERSION {
+            image.register_count = read_u16(bytes, HEADER_SIZE);
+        }
+        let mut seen = vec![];
+        let mut position = header_size;
+        for _ in 0..section_count {
+            if position + 5 > body_end {
+                return Err(ImageError::Truncated);
+            }
+            let id = bytes[position];
+            let length = read_u32(bytes, position + 1) as usize;
+            position += 5;
+            if length > body_end - position {
+                return Err(ImageError::Truncated);
+            }
+            let payload = &bytes[position..position + length];
+            position += length;
+
+            if seen.contains(&id) {
+                return Err(ImageError::DuplicateSection(id));
+            }
+            seen.push(id);
+            match id {
+                SECTION_CODE => image.code = payload.to_vec(),
+                SECTION_DATA => image.data = payload.to_vec(),
+                SECTION_REGISTERS => image.registers = decode_registers(payload)?,
+                SECTION_DEBUG => image.debug_info = Some(decode_debug_info(payload)?),
+                _ => {}
+            }
+        }
+        if !seen.contains(&SECTION_CODE) {
+            return Err(ImageError::MissingCode);
+        }
+        // the end of the code is a valid entry, the program exits right away
+        let entry = read_u32(bytes, 8);
+        if image.entry < 0 || image.entry as usize > image.code.len() / INSTRUCTION_SIZE {
+            return Err(ImageError::InvalidEntry(entry));
+        }
+        return Ok(image);
+    }
+
+    /// encodes the image, failing if a register index or the source path don't fit their fields
+    pub fn encode(&self) -> Result<Vec<u8>, ImageError> {
+        let mut sections = vec![(SECTION_CODE, self.code.clone())];
+        if !self.data.is_empty() {
+            sections.push((SECTION_DATA, self.data.clone()));
+        }
+        if !self.registers.is_empty() {
+            sections.push((SECTION_REGISTERS, encode_registers(&self.registers)?));
+        }
+        if let Some(debug_info) = &self.debug_info {
+            sections.push((SECTION_DEBUG, encode_debug_info(debug_info)?));
+        }
+
+        let version = match self.register_count {
+            0 => FIRST_VERSION,
+            _ => FORMAT_VERSION,
+        };
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&version.to_be_bytes());
+        bytes.extend_from_slice(&(sections.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&(self.entry as u32).to_be_bytes());
+        if version != FIRST_VERSION {
+            bytes.extend_from_slice(&self.register_count.to_be_bytes());
+        }
+        for (id, payload) in sections {
+            bytes.push(id);
+            bytes.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(&payload);
+        }
+        let checksum = crc32(&bytes);
+        bytes.extend_from_slice(&checksum.to_be_bytes());
+        return Ok(bytes);
+    }
+}
+
+/// register entries are a u16 index, the i32 content and a lock byte
+fn encode_registers(registers: &[(usize, REGISTER)]) -> Result<Vec<u8>, ImageError> {
+    let mut payload = Vec::with_capacity(registers.len() * REGISTER_ENTRY_SIZE);
+    for (index, register) in registers {
+        let index = u16::try_from(*index).map_err(|_| ImageError::RegisterIndexTooLarge(*index))?;
+        payload.extend_from_slice(&index.to_be_bytes());
+        payload.extend_from_slice(&register.content.to_be_bytes());
+        payload.push(register.locked as u8);
+    }
+    return Ok(payload);
+}
+
+fn decode_registers(payload: &[u8]) -> Result<Vec<(usize, REGISTER)>, ImageError> {
+    if !payload.len().is_multiple_of(REGISTER_ENTRY_SIZE) {
+        return Err(ImageError::MalformedRegisters);
+    }
+    return Ok(payload
+        .chunks(REGISTER_ENTRY_SIZE)
+        .map(|entry| {
+            let register = REGISTER {
+                content: read_u32(entry, 2) as i32,
+                locked: entry[6] != 0,
+            };
+            (read_u16(entry, 0) as usize, register)
+        })
+        .collect());
+}
+
+/// the debug section is the u16 length of the source path, the path and u32 set index and
+/// u32 line entries
+fn encode_debug_info(debug_info: &DebugInfo) -> Result<Vec<u8>, ImageError> {
+    let source = debug_info.source.as_bytes();
+    let length = match u16::try_from(source.len()) {
+        Ok(length) => length,
+        Err(_) => return Err(ImageError::SourcePathTooLong(source.len())),
+    };
+    let mut payload = Vec::with_capacity(2 + source.len() + debug_info.lines.len() * LINE_ENTRY_SIZE);
+    payload.extend_from_slice(&length.to_be_bytes());
+    payload.extend_from_slice(source);
+    for (set_index, line) in debug_info.lines.iter() {
+        payload.extend_from_slice(&(*set_index as u32).to_be_bytes());
+        payload.extend_from_slice(&line.to_be_bytes());
+    }
+    return Ok(payload);
+}
+
+fn decode_debug_info(payload: &[u8]) -> Result<DebugInfo, ImageError> {
+    if payload.len() < 2 {
+        return Err(ImageError::MalformedDebugInfo);
+    }
+    let lines_start = 2 + read_u16(payload, 0) as usize;
+    if payload.len() < lines_start || !(payload.len() - lines_start).is_multiple_of(LINE_ENTRY_SIZE) {
+        return Err(ImageError::MalformedDebugInfo);
+    }
+    let source = match std::str::from_utf8(&payload[2..lines_start]) {
+        Ok(source) => source.to_string(),
+        Err(_) => return Err(ImageError::MalformedDebugInfo),
+    };
+    let lines = payload[lines_start..]
+        .chunks(LINE_ENTRY_SIZE)
+        .map(|entry| (read_u32(entry, 0) as i32, read_u32(entry, 4)))
+        .collect();
+    return Ok(DebugInfo { source, lines });
+}
+
+fn read_u16(bytes: &[u8], at: usize) -> u16 {
+    return u16::from_be_bytes([bytes[at], bytes[at + 1]]);
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    return u32::from_be_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]]);
+}
+
+/// CRC-32 (IEEE) of the bytes
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    return !crc;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_image() -> Image {
+        return Image {
+            code: vec![1, 0, 1, 244],
+            data: vec![7, 8, 9],
+            registers: vec![(3, REGISTER { content: -2, locked: true })],
+            entry: 0,
+            register_count: 0,
+            debug_info: None,
+        };
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn test_image_round_trip() {
+        let image = test_image();
+        assert_eq!(Image::decode(&image.encode().unwrap()), Ok(image));
+
+        let image = Image::from_legacy(vec![0, 0, 0, 0]);
+        let bytes = image.encode().unwrap();
+        assert_eq!(bytes.len(), HEADER_SIZE + 5 + 4 + CHECKSUM_SIZE);
+        assert_eq!(Image::decode(&bytes), Ok(image));
+    }
+
+    #[test]
+    fn test_image_encode_rejects_oversized_fields() {
+        let image = Image {
+            registers: vec![(70000, REGISTER { content: 1, locked: false })],
+            ..test_image()
+        };
+        assert_eq!(image.encode(), Err(ImageError::RegisterIndexTooLarge(70000)));
+
+        let image = Image {
+            debug_info: Some(DebugInfo { source: "a".repeat(70000), lines: vec![] }),
+            ..test_image()
+        };
+        assert_eq!(image.encode(), Err(ImageError::SourcePathTooLong(70000)));
+    }
+
+    #[test]
+    fn test_image_rejects_bad_input() {
+        let mut bytes = test_image().encode().unwrap();
+
+        assert_eq!(Image::decode(&[1, 0, 1, 244]), Err(ImageError::NotAnImage));
+        assert_eq!(Image::decode(&bytes[..10]), Err(ImageError::Truncated));
+
+        bytes[HEADER_SIZE + 5] ^= 0xFF;
+        assert!(matches!(
+            Image::decode(&bytes),
+            Err(ImageError::ChecksumMismatch { .. })
+        ));
+
+        let mut bytes = test_image().encode().unwrap();
+        bytes[5] = 9;
+        assert_eq!(Image::decode(&bytes), Err(ImageError::UnsupportedVersion(9)));
+        assert_eq!(
+            ImageError::UnsupportedVersion(9).to_string(),
+            "unsupported image version 9, this VM reads versions 1 to 2"
+        );
+
+        for entry in [-1, 2] {
+            let image = Image { entry, ..test_image() };
+            let bytes = image.encode().unwrap();
+            assert_eq!(Image::decode(&bytes), Err(ImageError::InvalidEntry(entry as u32)));
+        }
+        let image = Image { entry: 1, ..test_image() };
+        assert_eq!(Image::decode(&image.encode().unwrap()), Ok(image));
+    }
+
+    #[test]
+    fn test_image_register_count() {
+        let image = Image {
+            register_count: 300,
+            ..test_image()
+        };
+        let bytes = image.encode().unwrap();
+        assert_eq!(read_u16(&bytes, 4), 2);
+        assert_eq!(read_u16(&bytes, HEADER_SIZE), 300);
+        assert_eq!(Image::decode(&bytes), Ok(image));
+
+        // images without a register count are still written and read as version 1
+        let bytes = test_image().encode().unwrap();
+        assert_eq!(read_u16(&bytes, 4), 1);
+        assert_eq!(Image::decode(&bytes).unwrap().register_count, 0);
+    }
+
+    #[test]
+    fn test_image_debug_info() {
+        let debug_info = DebugInfo {
+            source: "examples/loop.pasm".to_string(),
+            lines: vec![(0, 1), (1, 3), (3, 4), (4, 7)],
+        };
+        let image = Image {
+            debug_info: Some(debug_info.clone()),
+            ..test_image()
+        };
+        assert_eq!(Image::decode(&image.encode().unwrap()), Ok(image));
+
+        assert_eq!(debug_info.line_of(0), Some(1));
+        // set 2 is the second slot of the instruction on line 3
+        assert_eq!(debug_info.line_of(2), Some(3));
+        assert_eq!(debug_info.line_of(-1), None);
+        assert_eq!(debug_info.set_index_of(3), Some((1, 3)));
+        assert_eq!(debug_info.set_index_of(5), Some((4, 7)));
+        assert_eq!(debug_info.set_index_of(8), None);
+
+        assert_eq!(decode_debug_info(&[0, 4, b'a']), Err(ImageError::MalformedDebugInfo));
+        assert_eq!(decode_debug_info(&[0, 0, 0, 0, 0]), Err(ImageError::MalformedDebugInfo));
+    }
+
+    #[test]
+    fn test_image_load_legacy() {
+        let legacy = vec![1, 0, 1, 244];
+        assert_eq!(Image::load(legacy.clone(), false), Err(ImageError::NotAnImage));
+        assert_eq!(Image::load(legacy.clone(), true), Ok(Image::from_legacy(legacy)));
+        assert_eq!(Image::load(test_image().encode().unwrap(), true), Ok(test_image()));
+    }
+}
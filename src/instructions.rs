@@ -1,30 +1,234 @@
-#[derive(Debug, PartialEq)]
+use std::fmt;
+
+/// size of an instruction slot in bytes, a program_set_counter step covers one slot
+pub const INSTRUCTION_SIZE: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
 pub enum Opcode {
-    HLT,    // halt
-    IGL,    // ILLEGAL
-    LOAD,   // load
-    ADD,    // addition
-    SUB,    // subtraction
-    DIV,    // division
-    JMP,    // jump
-    RJMP,   // relative jump
-    JMPTL,  // jump to label
-    VMCALL, // run commands in VM
-    EQ,     // checks if equal
-    JEQ,    // jumps if true
-    NEQ,    // checks if not equal
-    JNEQ,   // jumps if not true
-    SWP,    // swaps two register values
-    AND,    // AND
-    OR,     // OR
-    NOT,    // NOT
-    GET,    // mv a value from a hidden register to a normal register
-    LOCKR,  // locks a register similar to a constant
-    PUSHRTS,   // push register to stack
-    POPRFS,    // pop register from stack
-    BREAK,     // breaks the program
-    LABEL,     // creates a label
-    GOTO,      // goto a label
+    HLT = 0,      // halt
+    IGL = 255,    // ILLEGAL
+    LOAD = 1,     // load
+    ADD = 2,      // addition
+    SUB = 3,      // subtraction
+    DIV = 4,      // division
+    JMP = 5,      // jump
+    RJMP = 6,     // relative jump
+    JMPTL = 7,    // jump to label
+    VMCALL = 8,   // run commands in VM
+    EQ = 9,       // checks if equal
+    JEQ = 10,     // jumps if true
+    NEQ = 11,     // checks if not equal
+    JNEQ = 12,    // jumps if not true
+    SWP = 13,     // swaps two register values
+    AND = 14,     // AND
+    OR = 15,      // OR
+    NOT = 16,     // NOT
+    GET = 17,     // mv a value from a hidden register to a normal register
+    LOCKR = 18,   // locks a register similar to a constant
+    PUSHRTS = 19, // push register to stack
+    POPRFS = 20,  // pop register from stack
+    BREAK = 21,   // breaks the program
+    LABEL = 22,   // creates a label
+    GOTO = 23,    // goto a label
+    LOADW = 24,   // load a 32 bit value
+    MUL = 25,     // multiplication
+    MOD = 26,     // remainder of a division
+    NEG = 27,     // negation
+    INC = 28,     // increment
+    DEC = 29,     // decrement
+    ABS = 30,     // absolute value
+    BAND = 31,    // bitwise and
+    BOR = 32,     // bitwise or
+    BXOR = 33,    // bitwise exclusive or
+    BNOT = 34,    // bitwise not
+    SHL = 35,     // shift left
+    SHR = 36,     // logical shift right
+    SAR = 37,     // arithmetic shift right
+    LT = 38,      // checks if less than
+    LE = 39,      // checks if less than or equal
+    GT = 40,      // checks if greater than
+    GE = 41,      // checks if greater than or equal
+    LTU = 42,     // checks if less than (unsigned)
+    LEU = 43,     // checks if less than or equal (unsigned)
+    GTU = 44,     // checks if greater than (unsigned)
+    GEU = 45,     // checks if greater than or equal (unsigned)
+    BEQ = 46,     // branches if equal
+    BNE = 47,     // branches if not equal
+    BLT = 48,     // branches if less than
+    BLE = 49,     // branches if less than or equal
+    BGT = 50,     // branches if greater than
+    BGE = 51,     // branches if greater than or equal
+    BLTU = 52,    // branches if less than (unsigned)
+    BLEU = 53,    // branches if less than or equal (unsigned)
+    BGTU = 54,    // branches if greater than (unsigned)
+    BGEU = 55,    // branches if greater than or equal (unsigned)
+    CALL = 56,    // calls a subroutine
+    RET = 57,     // returns from a subroutine
+    LD8 = 58,     // loads a byte from memory
+    LD16 = 59,    // loads 2 bytes from memory
+    LD32 = 60,    // loads 4 bytes from memory
+    ST8 = 61,     // stores a byte to memory
+    ST16 = 62,    // stores 2 bytes to memory
+    ST32 = 63,    // stores 4 bytes to memory
+    MODE = 64,    // sets the addressing modes of the next instruction
+}
+
+/// the kind byte of a jump target, telling what the Long operand after it holds
+pub const TARGET_SET_INDEX: u8 = 0;
+pub const TARGET_LABEL: u8 = 1;
+
+/// addressing modes MODE can give the Indirect and Pointer operands of the next instruction
+///
+/// the operand register holds the index of the register that is used, as without MODE
+pub const MODE_INDIRECT: u8 = 0;
+/// the operand byte is the value itself, only for Indirect operands
+pub const MODE_IMMEDIATE: u8 = 1;
+/// the operand register is used itself
+pub const MODE_DIRECT: u8 = 2;
+
+/// describes how an operand byte of an instruction is interpreted
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operand {
+    /// register holding the index of the register that is read
+    Indirect,
+
+    /// register holding the index of the register that is written
+    Pointer,
+
+    /// register that is used directly
+    Register,
+
+    /// 8 bit immediate value
+    Byte,
+
+    /// 16 bit immediate value, takes up two operand bytes
+    Word,
+
+    /// 8 bit label id
+    Label,
+
+    /// 32 bit immediate value, held in the slot after the instruction
+    Long,
+
+    /// tells if the Long operand after it is a set index or a label id
+    TargetKind,
+}
+
+impl Operand {
+    /// number of bytes the operand takes up in an instruction
+    pub fn width(self) -> usize {
+        match self {
+            Operand::Word => 2,
+            Operand::Long => 4,
+            _ => 1,
+        }
+    }
+}
+
+impl Opcode {
+    /// returns the byte the opcode is encoded as
+    pub fn code(self) -> u8 {
+        return self as u8;
+    }
+
+    /// returns the opcode with the given mnemonic, ignoring case
+    pub fn from_mnemonic(mnemonic: &str) -> Option<Opcode> {
+        return (0..=u8::MAX)
+            .map(Opcode::from)
+            .filter(|op| *op != Opcode::IGL)
+            .find(|op| op.to_string().eq_ignore_ascii_case(mnemonic));
+    }
+
+    /// returns the operands read by the instruction in order
+    pub fn operands(self) -> &'static [Operand] {
+        use Operand::*;
+        match self {
+            Opcode::HLT | Opcode::IGL | Opcode::BREAK | Opcode::RET => &[],
+            Opcode::LOAD => &[Register, Word],
+            Opcode::ADD | Opcode::SUB => &[Indirect, Indirect, Pointer],
+            Opcode::DIV => &[Indirect, Indirect, Register],
+            Opcode::JMP | Opcode::RJMP | Opcode::JMPTL => &[Indirect],
+            Opcode::VMCALL => &[Indirect, Indirect, Indirect],
+            Opcode::EQ | Opcode::NEQ => &[Indirect, Indirect, Pointer],
+            Opcode::JEQ | Opcode::JNEQ => &[Indirect, Indirect],
+            Opcode::SWP => &[Pointer, Pointer],
+            Opcode::AND | Opcode::OR => &[Indirect, Indirect, Pointer],
+            Opcode::NOT => &[Indirect, Pointer],
+            Opcode::GET => &[Register, Pointer],
+            Opcode::LOCKR | Opcode::PUSHRTS | Opcode::POPRFS => &[Pointer],
+            Opcode::LABEL | Opcode::GOTO => &[Label],
+            Opcode::LOADW => &[Register, Long],
+            Opcode::MUL | Opcode::MOD => &[Indirect, Indirect, Pointer],
+            Opcode::NEG | Opcode::ABS => &[Indirect, Pointer],
+            Opcode::INC | Opcode::DEC => &[Pointer],
+            Opcode::BAND | Opcode::BOR | Opcode::BXOR => &[Indirect, Indirect, Pointer],
+            Opcode::BNOT => &[Indirect, Pointer],
+            Opcode::SHL | Opcode::SHR | Opcode::SAR => &[Indirect, Indirect, Pointer],
+            Opcode::LT | Opcode::LE | Opcode::GT | Opcode::GE => &[Indirect, Indirect, Pointer],
+            Opcode::LTU | Opcode::LEU | Opcode::GTU | Opcode::GEU => &[Indirect, Indirect, Pointer],
+            Opcode::BEQ | Opcode::BNE | Opcode::BLT | Opcode::BLE | Opcode::BGT | Opcode::BGE => {
+                &[Indirect, Indirect, TargetKind, Long]
+            }
+            Opcode::BLTU | Opcode::BLEU | Opcode::BGTU | Opcode::BGEU => {
+                &[Indirect, Indirect, TargetKind, Long]
+            }
+            Opcode::CALL => &[TargetKind, Long],
+            Opcode::LD8 | Opcode::LD16 | Opcode::LD32 => &[Indirect, Pointer],
+            Opcode::ST8 | Opcode::ST16 | Opcode::ST32 => &[Indirect, Indirect],
+            Opcode::MODE => &[Byte, Byte, Byte],
+        }
+    }
+
+    /// returns the operand held by a byte of the first slot of the instruction
+    pub fn operand_at(self, position: usize) -> Option<Operand> {
+        let mut start = 1;
+        for operand in self.operands() {
+            if *operand == Operand::Long {
+                return None;
+            }
+            if (start..start + operand.width()).contains(&position) {
+                return Some(*operand);
+            }
+            start += operand.width();
+        }
+        return None;
+    }
+
+    /// returns true if an operand byte can be given the addressing mode
+    pub fn supports_mode(self, position: usize, mode: u8) -> bool {
+        match (self.operand_at(position), mode) {
+            (_, MODE_INDIRECT) => return true,
+            (Some(Operand::Indirect), MODE_IMMEDIATE | MODE_DIRECT) => return true,
+            (Some(Operand::Pointer), MODE_DIRECT) => return true,
+            _ => return false,
+        }
+    }
+
+    /// number of bytes a memory opcode reads or writes, 0 for other opcodes
+    pub fn access_width(self) -> usize {
+        match self {
+            Opcode::LD8 | Opcode::ST8 => 1,
+            Opcode::LD16 | Opcode::ST16 => 2,
+            Opcode::LD32 | Opcode::ST32 => 4,
+            _ => 0,
+        }
+    }
+
+    /// number of instruction slots the instruction takes up, a Long operand takes up a slot
+    /// of its own
+    pub fn slots(self) -> usize {
+        match self.operands().contains(&Operand::Long) {
+            true => 2,
+            false => 1,
+        }
+    }
+}
+
+impl fmt::Display for Opcode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
 }
 
 #[derive(Debug, PartialEq)]
@@ -75,6 +279,55 @@ impl From<u8> for Opcode {
             22 => Opcode::LABEL,
             23 => Opcode::GOTO,
 
+            24 => Opcode::LOADW,
+
+            25 => Opcode::MUL,
+            26 => Opcode::MOD,
+            27 => Opcode::NEG,
+            28 => Opcode::INC,
+            29 => Opcode::DEC,
+            30 => Opcode::ABS,
+
+            31 => Opcode::BAND,
+            32 => Opcode::BOR,
+            33 => Opcode::BXOR,
+            34 => Opcode::BNOT,
+            35 => Opcode::SHL,
+            36 => Opcode::SHR,
+            37 => Opcode::SAR,
+
+            38 => Opcode::LT,
+            39 => Opcode::LE,
+            40 => Opcode::GT,
+            41 => Opcode::GE,
+            42 => Opcode::LTU,
+            43 => Opcode::LEU,
+            44 => Opcode::GTU,
+            45 => Opcode::GEU,
+
+            46 => Opcode::BEQ,
+            47 => Opcode::BNE,
+            48 => Opcode::BLT,
+            49 => Opcode::BLE,
+            50 => Opcode::BGT,
+            51 => Opcode::BGE,
+            52 => Opcode::BLTU,
+            53 => Opcode::BLEU,
+            54 => Opcode::BGTU,
+            55 => Opcode::BGEU,
+
+            56 => Opcode::CALL,
+            57 => Opcode::RET,
+
+            58 => Opcode::LD8,
+            59 => Opcode::LD16,
+            60 => Opcode::LD32,
+            61 => Opcode::ST8,
+            62 => Opcode::ST16,
+            63 => Opcode::ST32,
+
+            64 => Opcode::MODE,
+
             _ => Opcode::IGL,
         }
     }
@@ -89,4 +342,34 @@ mod tests {
         let instruction = Instruction::new(Opcode::HLT);
         assert_eq!(instruction.opcode, Opcode::HLT);
     }
+
+    #[test]
+    fn test_opcode_code_round_trip() {
+        for code in 0..=u8::MAX {
+            let op = Opcode::from(code);
+            if op != Opcode::IGL {
+                assert_eq!(op.code(), code);
+            }
+        }
+    }
+
+    #[test]
+    fn test_supported_modes() {
+        assert_eq!(Opcode::LOAD.operand_at(2), Some(Operand::Word));
+        assert_eq!(Opcode::LOADW.operand_at(4), None);
+        assert!(Opcode::ADD.supports_mode(1, MODE_IMMEDIATE));
+        assert!(Opcode::ADD.supports_mode(3, MODE_DIRECT));
+        assert!(!Opcode::ADD.supports_mode(3, MODE_IMMEDIATE));
+        assert!(!Opcode::LOAD.supports_mode(1, MODE_DIRECT));
+        assert!(!Opcode::JMP.supports_mode(2, MODE_DIRECT));
+        assert!(!Opcode::ADD.supports_mode(1, 7));
+    }
+
+    #[test]
+    fn test_opcode_from_mnemonic() {
+        assert_eq!(Opcode::from_mnemonic("load"), Some(Opcode::LOAD));
+        assert_eq!(Opcode::from_mnemonic("PUSHRTS"), Some(Opcode::PUSHRTS));
+        assert_eq!(Opcode::from_mnemonic("IGL"), None);
+        assert_eq!(Opcode::from_mnemonic("nope"), None);
+    }
 }
@@ -0,0 +1,42 @@
+//! perling_vm is an interpreter for compiled perling byte code.
+//!
+//! The VM can be embedded by constructing a [`VM`], loading a program into it and calling
+//! [`VM::run`]:
+//!
+//! ```
+//! use perling_vm::VM;
+//!
+//! let mut vm = VM::with_program(vec![1, 0, 1, 244]); // LOAD 500 into R0
+//! vm.run().unwrap();
+//! assert_eq!(vm.register(0).unwrap().content, 500);
+//! ```
+#![allow(clippy::needless_return, clippy::upper_case_acronyms)]
+
+pub mod arithmetic;
+pub mod assembler;
+pub mod dap;
+pub mod debug;
+pub mod disassembler;
+pub mod error;
+pub mod frame;
+pub mod gdb;
+pub mod image;
+pub mod instructions;
+pub mod label;
+pub mod limits;
+pub mod memory;
+pub mod register;
+pub mod stack;
+pub mod streams;
+pub mod vm;
+pub mod vmcall;
+pub mod watch;
+
+pub use arithmetic::ArithmeticMode;
+pub use error::{VmError, VmErrorKind};
+pub use image::{DebugInfo, Image, ImageError};
+pub use limits::Limits;
+pub use register::REGISTER;
+pub use stack::STACK;
+pub use vm::{Step, VM};
+pub use vmcall::VmCall;
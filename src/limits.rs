@@ -0,0 +1,58 @@
+use std::fmt;
+use std::time::Duration;
+
+/// number of instructions executed between checks of the elapsed time
+pub const TIME_CHECK_INTERVAL: u64 = 256;
+
+/// resource limits enforced by VM::run, unset limits are not enforced
+///
+/// ```
+/// use perling_vm::limits::Limits;
+/// use perling_vm::VM;
+///
+/// let mut vm = VM::with_program(vec![5, 0, 0, 0]); // JMP to itself
+/// vm.set_limits(Limits {
+///     max_instructions: Some(1000),
+///     ..Limits::default()
+/// });
+/// assert!(vm.run().unwrap_err().is_limit_exceeded());
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Limits {
+    /// maximum number of instructions a run may execute
+    pub max_instructions: Option<u64>,
+
+    /// maximum wall-clock time a run may take, checked every TIME_CHECK_INTERVAL instructions
+    pub max_duration: Option<Duration>,
+
+    /// maximum number of values on the stack
+    pub max_stack_depth: Option<usize>,
+
+    /// maximum number of nested CALLs
+    pub max_call_depth: Option<usize>,
+
+    /// maximum size of the memory in bytes, checked when the memory is sized
+    pub max_memory: Option<usize>,
+}
+
+/// the limit a program ran into, holding the configured value
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Limit {
+    Instructions(u64),
+    Duration(Duration),
+    StackDepth(usize),
+    CallDepth(usize),
+    Memory(usize),
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Limit::Instructions(max) => write!(f, "instruction budget of {} exceeded", max),
+            Limit::Duration(max) => write!(f, "time limit of {:?} exceeded", max),
+            Limit::StackDepth(max) => write!(f, "stack depth limit of {} exceeded", max),
+            Limit::CallDepth(max) => write!(f, "call depth limit of {} exceeded", max),
+            Limit::Memory(max) => write!(f, "memory limit of {} bytes exceeded", max),
+        }
+    }
+}
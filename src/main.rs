@@ -1,29 +1,27 @@
-use crate::vm::VM;
-use log::info;
+#![allow(clippy::needless_return)]
+
+use perling_vm::dap::DapServer;
+use perling_vm::debug::DebugEngine;
+use perling_vm::gdb::GdbStub;
+use perling_vm::register::{DEFAULT_REGISTER_COUNT, REGISTER};
+use perling_vm::{assembler, disassembler, register, ArithmeticMode, Image, Limits, VM};
+use log::{error, info, warn};
 use simplelog::*;
 use std::fs::File;
 use std::mem;
-use clap::{App, load_yaml};
-use home;
+use std::net::TcpListener;
+use clap::{App, ArgMatches, load_yaml};
 use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
 
-pub mod instructions;
-pub mod register;
-pub mod stack;
-pub mod vm;
-mod debug;
-mod label;
+/// exit status used when the program was stopped by a limit
+const LIMIT_EXIT_STATUS: i32 = 124;
 
 fn main() {
     let yaml = load_yaml!("cli.yaml");
     let matches = App::from(yaml).get_matches();
-    let location = matches.value_of("FILE").unwrap();
-    let mut register_file_location = "";
-
-    if let Some(x) = matches.value_of("reg") {
-        register_file_location = x;
-    }
-    println!("{}",register_file_location);
     let info_log_filter = match matches.is_present("loginfo") {
         true => LevelFilter::Info,
         _ => LevelFilter::Off,
@@ -33,7 +31,6 @@ fn main() {
         Some(path) => format!("{}{}",path.to_str().unwrap(),"/perlingvm/logs"),
         None => "".to_string(),
     };
-    println!("{}{}",log_location,"/perling.info.log");
 
     fs::create_dir_all(&log_location).unwrap();
     CombinedLogger::init(vec![
@@ -42,7 +39,7 @@ fn main() {
         WriteLogger::new(
             info_log_filter,
             Config::default(),
-            File::create(format!("{}{}",log_location,"/perling.info.log")).unwrap(),
+            File::create(format!("{}/perling.info.log", log_location)).unwrap(),
         ),
         WriteLogger::new(
             LevelFilter::Error,
@@ -51,24 +48,242 @@ fn main() {
         ),
     ])
     .unwrap();
+    info!("logging to {}/perling.info.log", log_location);
 
-    use std::io::Read;
-    let file = std::fs::File::open(location)
-        .unwrap()
-        .bytes()
-        .map(|ch| ch.unwrap());
+    match matches.subcommand() {
+        Some(("assemble", sub_matches)) => assemble(sub_matches),
+        Some(("disasm", sub_matches)) => disasm(sub_matches),
+        Some(("dap", _)) => dap(),
+        _ => run(&matches),
+    }
+}
 
-    let mut vm = VM::new();
-    for i in file {
-        vm.program.append(&mut vec![i]);
+/// runs the program given as FILE
+fn run(matches: &ArgMatches) {
+    let location = matches.value_of("FILE").unwrap();
+    let mut register_file_location = "";
+
+    if let Some(x) = matches.value_of("reg") {
+        register_file_location = x;
     }
-    if register_file_location != "" {
-        let mut buffer = String::new();
-        std::fs::File::open(register_file_location).unwrap().read_to_string(&mut buffer).unwrap();
-        println!("{}",buffer);
-        register::register_from_string(&buffer, &mut vm.registers)
+
+    let image = match Image::load(read_or_exit(location), matches.is_present("legacy")) {
+        Ok(image) => image,
+        Err(e) => {
+            error!("unable to load {}: {}", location, e);
+            std::process::exit(1);
+        }
+    };
+    let register_count = parse_or_exit(matches, "registers").unwrap_or(DEFAULT_REGISTER_COUNT);
+    let mut vm = VM::with_register_count(register_count);
+    let max_duration = parse_or_exit::<f64>(matches, "timeout").map(|seconds| {
+        Duration::try_from_secs_f64(seconds).unwrap_or_else(|e| {
+            error!("invalid value for --timeout: {}", e);
+            std::process::exit(1);
+        })
+    });
+    // the debugger enforces the other limits, but a paused program would run out of time
+    if max_duration.is_some() && (matches.is_present("debug") || matches.is_present("gdb")) {
+        warn!("--timeout is not enforced while debugging");
     }
-    vm.run();
+    if let Some(mode) = parse_or_exit::<ArithmeticMode>(matches, "arithmetic") {
+        vm.set_arithmetic_mode(mode);
+    }
+    vm.set_limits(Limits {
+        max_instructions: parse_or_exit(matches, "max-instructions"),
+        max_duration,
+        max_stack_depth: parse_or_exit(matches, "max-stack"),
+        max_call_depth: parse_or_exit(matches, "max-call-depth"),
+        max_memory: parse_or_exit(matches, "max-memory"),
+    });
+    // without --memory the VM keeps its default size, which set_limits clamped to --max-memory
+    let memory_size = parse_or_exit(matches, "memory");
+    let loaded = match memory_size {
+        Some(size) => vm.set_memory_size(size),
+        None => Ok(()),
+    };
+    if let Err(e) = loaded.and_then(|_| vm.load_image(&image)) {
+        error!("unable to load {}: {}", location, e);
+        match e.is_limit_exceeded() {
+            true => std::process::exit(LIMIT_EXIT_STATUS),
+            false => std::process::exit(1),
+        }
+    }
+    if !register_file_location.is_empty() {
+        let buffer = String::from_utf8_lossy(&read_or_exit(register_file_location)).into_owned();
+        info!("loading registers from {}", register_file_location);
+        if let Err(e) = vm.load_registers(&buffer) {
+            error!("{}:{}", register_file_location, e);
+            std::process::exit(1);
+        }
+    }
+    let result = if let Some(port) = parse_or_exit::<u16>(matches, "gdb") {
+        match serve_gdb(&mut vm, port) {
+            Ok(Some(code)) => Ok(code),
+            Ok(None) => std::process::exit(0),
+            Err(e) => {
+                error!("gdb connection failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+    } else if matches.is_present("debug") {
+        match DebugEngine::default().debug(&mut vm, std::io::stdin().lock()) {
+            Ok(Some(code)) => Ok(code),
+            Ok(None) => std::process::exit(0),
+            Err(e) => Err(e),
+        }
+    } else {
+        vm.run()
+    };
     info!("process used {} register(s)", vm.get_register_usage());
-    info!("process was allocated {}B", mem::size_of_val(&vm.registers))
+    info!(
+        "process was allocated {}B for {} register(s)",
+        vm.registers.len() * mem::size_of::<REGISTER>(),
+        vm.registers.len()
+    );
+    info!("process had {}B of memory", vm.memory.content.len());
+    match result {
+        Ok(code) => eprintln!("process exited with code: {}", code),
+        Err(e) if e.is_limit_exceeded() => {
+            error!("process stopped: {}", e);
+            std::process::exit(LIMIT_EXIT_STATUS);
+        }
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// waits for a single GDB client on a local port and serves it
+fn serve_gdb(vm: &mut VM, port: u16) -> std::io::Result<Option<i32>> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    eprintln!("waiting for gdb on {}", listener.local_addr()?);
+    let (stream, address) = listener.accept()?;
+    info!("gdb connected from {}", address);
+    return GdbStub::new(stream).serve(vm);
+}
+
+/// assembles the PASM file given as INPUT into perling byte code
+fn assemble(matches: &ArgMatches) {
+    let input = matches.value_of("INPUT").unwrap();
+    let output = match matches.value_of("output") {
+        Some(output) => output.to_string(),
+        None => default_output_location(input),
+    };
+
+    let source = match fs::read_to_string(input) {
+        Ok(source) => source,
+        Err(e) => {
+            error!("unable to read {}: {}", input, e);
+            std::process::exit(1);
+        }
+    };
+    let assembled = match matches.is_present("debug-info") {
+        true => {
+            // debuggers find the source through this path, so it is stored resolved
+            let source_path = fs::canonicalize(input).unwrap_or_else(|_| Path::new(input).to_path_buf());
+            assembler::assemble_with_debug_info(&source, &source_path.to_string_lossy())
+        }
+        _ => assembler::assemble_image(&source),
+    };
+    let mut image = match assembled {
+        Ok(image) => image,
+        Err(e) => {
+            error!("{}:{}", input, e);
+            std::process::exit(1);
+        }
+    };
+    if let Some(register_file_location) = matches.value_of("reg") {
+        let buffer = String::from_utf8_lossy(&read_or_exit(register_file_location)).into_owned();
+        image.registers = match register::parse_registers(&buffer) {
+            Ok(registers) => registers,
+            Err(e) => {
+                error!("{}:{}", register_file_location, e);
+                std::process::exit(1);
+            }
+        };
+    }
+    let bytes = match matches.is_present("raw") {
+        true => image.code,
+        _ => match image.encode() {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                error!("unable to assemble {}: {}", input, e);
+                std::process::exit(1);
+            }
+        },
+    };
+    if let Err(e) = fs::write(&output, bytes) {
+        error!("unable to write {}: {}", output, e);
+        std::process::exit(1);
+    }
+    info!("assembled {} into {}", input, output);
+}
+
+/// prints the instructions of the perling bin file given as INPUT
+fn disasm(matches: &ArgMatches) {
+    let input = matches.value_of("INPUT").unwrap();
+    let image = match Image::load(read_or_exit(input), true) {
+        Ok(image) => image,
+        Err(e) => {
+            error!("unable to load {}: {}", input, e);
+            std::process::exit(1);
+        }
+    };
+    if image.entry != 0 {
+        println!("entry: {}", image.entry);
+    }
+    if image.register_count != 0 {
+        println!("registers: {}", image.register_count);
+    }
+    for (index, register) in image.registers.iter() {
+        println!("r{}: {}\tlocked:{}", index, register.content, register.locked);
+    }
+    if !image.data.is_empty() {
+        println!("data: {} byte(s)", image.data.len());
+    }
+    if let Some(debug_info) = &image.debug_info {
+        println!("source: {}", debug_info.source);
+    }
+    print!("{}", disassembler::listing(&image.code));
+}
+
+/// serves the Debug Adapter Protocol over stdin and stdout until the client disconnects
+fn dap() {
+    let stdin = std::io::BufReader::new(std::io::stdin());
+    if let Err(e) = DapServer::new(std::io::stdout()).serve(stdin) {
+        error!("debug adapter failed: {}", e);
+        std::process::exit(1);
+    }
+}
+
+/// reads a whole file, exiting with an error if it can't be read
+fn read_or_exit(location: &str) -> Vec<u8> {
+    match fs::read(location) {
+        Ok(bytes) => return bytes,
+        Err(e) => {
+            error!("unable to read {}: {}", location, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// parses the value of an optional argument, exiting with an error if it is malformed
+fn parse_or_exit<T: FromStr>(matches: &ArgMatches, name: &str) -> Option<T> {
+    let value = matches.value_of(name)?;
+    match value.parse() {
+        Ok(value) => return Some(value),
+        Err(_) => {
+            error!("invalid value for --{}: {}", name, value);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// replaces the extension of a source file with .perling.bin
+fn default_output_location(input: &str) -> String {
+    let path = Path::new(input);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    return path.with_file_name(format!("{}.perling.bin", stem)).to_string_lossy().into_owned();
 }
@@ -0,0 +1,77 @@
+use std::convert::TryFrom;
+
+/// size of the memory of a new VM in bytes
+pub const DEFAULT_MEMORY_SIZE: usize = 64 * 1024;
+
+/// byte addressable memory, values wider than a byte are stored big endian
+#[derive(Debug, PartialEq)]
+pub struct MEMORY {
+    pub content: Vec<u8>,
+}
+
+impl MEMORY {
+    /// creates zeroed memory of the given size in bytes
+    pub fn new(size: usize) -> MEMORY {
+        return MEMORY {
+            content: vec![0; size],
+        };
+    }
+
+    /// returns the byte range of a value, None if any of it is out of bounds
+    fn range(&self, address: i32, width: usize) -> Option<std::ops::Range<usize>> {
+        let start = usize::try_from(address).ok()?;
+        let end = start.checked_add(width)?;
+        if end > self.content.len() {
+            return None;
+        }
+        return Some(start..end);
+    }
+
+    /// reads a value of 1, 2 or 4 bytes, values narrower than 4 bytes are zero extended
+    pub fn load(&self, address: i32, width: usize) -> Option<i32> {
+        let range = self.range(address, width)?;
+        let mut value: u32 = 0;
+        for byte in &self.content[range] {
+            value = (value << 8) | *byte as u32;
+        }
+        return Some(value as i32);
+    }
+
+    /// writes the lowest 1, 2 or 4 bytes of a value and returns if it was in bounds
+    pub fn store(&mut self, address: i32, width: usize, value: i32) -> bool {
+        let range = match self.range(address, width) {
+            Some(range) => range,
+            None => return false,
+        };
+        let bytes = value.to_be_bytes();
+        self.content[range].copy_from_slice(&bytes[4 - width..]);
+        return true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_and_store() {
+        let mut memory = MEMORY::new(8);
+        assert!(memory.store(0, 4, -2));
+        assert_eq!(memory.content[..4], [255, 255, 255, 254]);
+        assert_eq!(memory.load(0, 4), Some(-2));
+        assert_eq!(memory.load(2, 2), Some(0xFFFE));
+        assert_eq!(memory.load(3, 1), Some(0xFE));
+
+        assert!(memory.store(6, 2, 0x1234_5678));
+        assert_eq!(memory.load(6, 2), Some(0x5678));
+    }
+
+    #[test]
+    fn test_out_of_bounds() {
+        let mut memory = MEMORY::new(8);
+        assert_eq!(memory.load(5, 4), None);
+        assert_eq!(memory.load(-1, 1), None);
+        assert!(!memory.store(8, 1, 0));
+        assert!(memory.store(7, 1, 0));
+    }
+}
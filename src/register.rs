@@ -1,6 +1,9 @@
 use log::error;
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+/// number of registers a VM has unless it is created with another count
+pub const DEFAULT_REGISTER_COUNT: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
 pub struct REGISTER {
     pub content: i32,
     pub locked: bool,
@@ -35,28 +38,51 @@ impl REGISTER {
     }
 }
 
-pub fn register_from_string(s: &str, reg_array: &mut [REGISTER]) {
-    let key_val_pairs: Vec<&str> = s.split("\n").collect();
-    for key_val_pair in key_val_pairs {
+/// parses a register file made of `index:value:locked` lines
+pub fn parse_registers(s: &str) -> Result<Vec<(usize, REGISTER)>, String> {
+    let mut registers = vec![];
+    for (line, key_val_pair) in s.lines().enumerate() {
         if key_val_pair.is_empty() {
             continue;
         }
-        let sep: Vec<&str> = key_val_pair.split(":").collect();
-        let key = sep[0].parse::<usize>().unwrap();
-        let val = sep[1].parse::<i32>().unwrap();
-        let locked = sep[2];
-        reg_array[key].content = val;
-        if locked == "1" {
-            reg_array[key].locked = true;
-        } else {
-            reg_array[key].locked = false;
+        let sep: Vec<&str> = key_val_pair.split(':').collect();
+        if sep.len() != 3 {
+            return Err(format!("line {}: expected index:value:locked", line + 1));
         }
+        let key = match sep[0].parse::<usize>() {
+            Ok(key) => key,
+            Err(_) => return Err(format!("line {}: invalid register index `{}`", line + 1, sep[0])),
+        };
+        let val = match sep[1].parse::<i32>() {
+            Ok(val) => val,
+            Err(_) => return Err(format!("line {}: invalid register value `{}`", line + 1, sep[1])),
+        };
+        registers.push((key, REGISTER { content: val, locked: sep[2] == "1" }));
     }
+    return Ok(registers);
+}
+
+/// sets the registers of a register file, failing if it refers to a register that does not exist
+pub fn register_from_string(s: &str, reg_array: &mut [REGISTER]) -> Result<(), String> {
+    let registers = parse_registers(s)?;
+    // every index is checked before any register changes, so a bad file leaves them untouched
+    if let Some((key, _)) = registers.iter().find(|(key, _)| *key >= reg_array.len()) {
+        return Err(format!(
+            "register index {} is out of range, there are {} registers",
+            key,
+            reg_array.len()
+        ));
+    }
+    for (key, register) in registers {
+        reg_array[key] = register;
+    }
+    return Ok(());
 }
 
 
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
     use super::*;
 
@@ -112,7 +138,27 @@ mod tests {
     fn test_register_from_string() {
         let s = "0:5:1\n1:10:0";
         let mut m = [REGISTER{ content: 0, locked: false }; 2];
-        register_from_string(s, &mut m);
+        register_from_string(s, &mut m).unwrap();
+        assert_eq!(m[0], REGISTER{ content: 5, locked: true });
+        assert_eq!(
+            register_from_string("2:1:0", &mut m),
+            Err("register index 2 is out of range, there are 2 registers".to_string())
+        );
+        assert!(register_from_string("x", &mut m).is_err());
+
+        // a file with a bad index leaves every register as it was
+        assert!(register_from_string("0:7:0\n5:1:0", &mut m).is_err());
         assert_eq!(m[0], REGISTER{ content: 5, locked: true });
     }
+
+    #[test]
+    fn test_parse_registers() {
+        let registers = parse_registers("0:5:1\n\n3:-10:0\n").unwrap();
+        assert_eq!(registers, vec![
+            (0, REGISTER{ content: 5, locked: true }),
+            (3, REGISTER{ content: -10, locked: false }),
+        ]);
+        assert_eq!(parse_registers("0:5"), Err("line 1: expected index:value:locked".to_string()));
+        assert_eq!(parse_registers("1:1:0\nx:1:0"), Err("line 2: invalid register index `x`".to_string()));
+    }
 }
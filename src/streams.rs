@@ -0,0 +1,95 @@
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Write};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// the input and output streams guest programs use
+pub struct Streams {
+    /// guest standard output
+    pub out: Box<dyn Write + Send>,
+
+    /// guest standard error
+    pub err: Box<dyn Write + Send>,
+
+    /// guest standard input
+    pub input: Box<dyn BufRead + Send>,
+}
+
+impl Default for Streams {
+    /// uses the standard streams of the process
+    fn default() -> Self {
+        Streams {
+            out: Box::new(io::stdout()),
+            err: Box::new(io::stderr()),
+            input: Box::new(BufReader::new(io::stdin())),
+        }
+    }
+}
+
+impl fmt::Debug for Streams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Streams")
+    }
+}
+
+/// an in memory output stream that can still be read after it is handed to a VM
+///
+/// ```
+/// use perling_vm::streams::SharedBuffer;
+/// use perling_vm::VM;
+///
+/// let output = SharedBuffer::default();
+/// let mut vm = VM::new();
+/// vm.set_stdout(output.clone());
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct SharedBuffer {
+    buffer: Arc<Mutex<Vec<u8>>>,
+}
+
+impl SharedBuffer {
+    /// returns a copy of everything written so far
+    pub fn contents(&self) -> Vec<u8> {
+        return self.lock().clone();
+    }
+
+    /// returns everything written so far as text
+    pub fn to_string_lossy(&self) -> String {
+        return String::from_utf8_lossy(&self.lock()).into_owned();
+    }
+
+    /// locks the buffer, a writer that panicked can't leave it half written so poisoning is ignored
+    fn lock(&self) -> MutexGuard<'_, Vec<u8>> {
+        return self.buffer.lock().unwrap_or_else(|e| e.into_inner());
+    }
+
+    /// removes everything written so far and returns it
+    pub fn take(&self) -> Vec<u8> {
+        return self.lock().split_off(0);
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.lock().extend_from_slice(buf);
+        return Ok(buf.len());
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shared_buffer() {
+        let buffer = SharedBuffer::default();
+        let mut writer = buffer.clone();
+        write!(writer, "hello {}", 5).unwrap();
+        assert_eq!(buffer.to_string_lossy(), "hello 5");
+        assert_eq!(buffer.take(), b"hello 5".to_vec());
+        assert!(buffer.contents().is_empty());
+    }
+}
@@ -1,286 +1,677 @@
-use crate::instructions::Opcode;
-use crate::register::REGISTER;
+use crate::arithmetic::{ArithmeticMode, Outcome};
+use crate::debug::{Action, DebugEngine};
+use crate::error::{VmError, VmErrorKind};
+use crate::image::Image;
+use crate::instructions::{
+    Opcode, INSTRUCTION_SIZE, MODE_DIRECT, MODE_IMMEDIATE, MODE_INDIRECT, TARGET_LABEL,
+    TARGET_SET_INDEX,
+};
+use crate::frame::FRAME;
+use crate::label::LABEL;
+use crate::memory::{DEFAULT_MEMORY_SIZE, MEMORY};
+use crate::limits::{Limit, Limits, TIME_CHECK_INTERVAL};
+use crate::register::{DEFAULT_REGISTER_COUNT, REGISTER};
 use crate::stack::STACK;
+use crate::register;
+use crate::streams::Streams;
+use crate::vmcall::{VmCall, VmCallTable};
 use log::{error, info};
-use crate::debug::DebugEngine;
-use crate::label::LABEL;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::time::Instant;
+
+/// outcome of executing a single instruction
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Step {
+    /// execution can carry on with the next instruction
+    Continue,
+
+    /// the program finished with the given exit code
+    Exit(i32),
+
+    /// the program executed BREAK while a debugger is attached
+    Break,
+}
 
 #[derive(Debug)]
 pub struct VM {
-    pub registers: [REGISTER; 32],
+    pub registers: Vec<REGISTER>,
     pub program_counter: usize,     // current byte
     pub program: Vec<u8>,           // program instructions
     pub remainder: i32,             // remainder of div opcode
+    pub input_status: i32,          // status of the last input VM call
+    pub flags: i32,                 // flags of the last arithmetic or comparison opcode
+    pub arithmetic_mode: ArithmeticMode, // what arithmetic does on overflow
     pub program_set_counter: i32,   // current line
     pub stack: STACK,               // stack data
-    pub labels: Vec<LABEL>          // label data
+    pub labels: Vec<LABEL>,         // label data
+    pub call_stack: Vec<FRAME>,     // active CALLs, innermost last
+    pub memory: MEMORY,             // data memory
+    operand_modes: [u8; 3],         // addressing modes of the current instruction
+    pub calls: VmCallTable,         // functions VMCALL can run
+    pub streams: Streams,           // guest input and output
+    pub limits: Limits,             // limits enforced by run
+    pub executed_instructions: u64, // instructions executed by the last run
+    pub debugger_attached: bool,    // BREAK returns Step::Break instead of reading commands
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        VM::new()
+    }
 }
 
 impl VM {
     pub fn new() -> VM {
+        return VM::with_register_count(DEFAULT_REGISTER_COUNT);
+    }
+
+    /// creates a VM with the given number of registers
+    pub fn with_register_count(count: usize) -> VM {
         VM {
-            registers: [REGISTER {
-                content: 0,
-                locked: false,
-            }; 32],
+            registers: vec![REGISTER::default(); count],
             program: vec![],
             program_counter: 0,
             program_set_counter: 0,
             remainder: 0,
+            input_status: 0,
+            flags: 0,
+            arithmetic_mode: ArithmeticMode::default(),
             stack: STACK { content: vec![0] },
             labels: vec![LABEL{ id: 0, location: 0 }],
+            call_stack: vec![],
+            memory: MEMORY::new(DEFAULT_MEMORY_SIZE),
+            operand_modes: [MODE_INDIRECT; 3],
+            calls: VmCallTable::with_builtins(),
+            streams: Streams::default(),
+            limits: Limits::default(),
+            executed_instructions: 0,
+            debugger_attached: false,
+        }
+    }
+
+    /// creates a VM with the program already loaded
+    pub fn with_program(program: Vec<u8>) -> VM {
+        let mut vm = VM::new();
+        vm.load_program(program);
+        return vm;
+    }
+
+    /// replaces the program and moves execution back to the first instruction
+    pub fn load_program(&mut self, program: Vec<u8>) {
+        self.program = program;
+        self.reset_program();
+    }
+
+    /// reads an image from a file and loads it, headerless files are loaded as legacy byte code
+    pub fn load_program_file<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let image = Image::load(std::fs::read(path)?, true)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        return self
+            .load_image(&image)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e));
+    }
+
+    /// loads an image, preloading its registers and moving execution to its entry point. Images
+    /// that give a register count replace the registers of the VM with that many cleared ones
+    pub fn load_image(&mut self, image: &Image) -> Result<(), VmError> {
+        // decoded images are checked already, this covers images built by the host
+        if image.entry < 0 || image.entry as usize > image.code.len() / INSTRUCTION_SIZE {
+            return Err(self.fault(VmErrorKind::InvalidJumpTarget(image.entry)));
+        }
+        self.load_program(image.code.clone());
+        if image.register_count != 0 {
+            self.registers = vec![REGISTER::default(); image.register_count as usize];
+        }
+        // the data section is copied to the start of the memory, which grows to fit it
+        if image.data.len() > self.memory.content.len() {
+            self.set_memory_size(image.data.len())?;
         }
+        self.memory.content[..image.data.len()].copy_from_slice(&image.data);
+        for (index, register) in image.registers.iter() {
+            self.preload_register(*index, register.content, register.locked)?;
+        }
+        self.program_set_counter = image.entry;
+        self.program_counter = (image.entry as usize) * INSTRUCTION_SIZE;
+        return self.resolve_labels();
     }
 
-    pub fn get_register_usage(&mut self) -> i16 {
-        let mut used_reg_count: i16 = 0;
-        let reg_copy = self.registers.clone();
-        for i in reg_copy.iter() {
-            if i.clone().content != 0 as i32 {
+    /// builds the label table from every LABEL instruction of the program, so labels can be
+    /// jumped to before their LABEL instruction is executed. Called by run and load_image,
+    /// programs that are only stepped through with run_once have to call it themselves
+    pub fn resolve_labels(&mut self) -> Result<(), VmError> {
+        let mut labels: Vec<LABEL> = vec![];
+        let mut next_index = 0;
+        while next_index * INSTRUCTION_SIZE < self.program.len() {
+            let set_index = next_index;
+            let slot = &self.program[set_index * INSTRUCTION_SIZE..];
+            let opcode = Opcode::from(slot[0]);
+            // the slots after a wide instruction hold its operands rather than instructions
+            next_index += opcode.slots();
+            if opcode != Opcode::LABEL {
+                continue;
+            }
+            let location = set_index as i32;
+            let id = match slot.get(1) {
+                Some(&id) => id as i32,
+                None => {
+                    let at = set_index * INSTRUCTION_SIZE + 1;
+                    return Err(VmError::new(VmErrorKind::TruncatedInstruction, location, at));
+                }
+            };
+            if labels.iter().any(|label| label.id == id) {
+                let at = set_index * INSTRUCTION_SIZE;
+                return Err(VmError::new(VmErrorKind::DuplicateLabel(id), location, at));
+            }
+            labels.push(LABEL { id, location });
+        }
+        // label 0 points to the start of the program unless the program defines it
+        if labels.iter().all(|label| label.id != 0) {
+            labels.insert(0, LABEL { id: 0, location: 0 });
+        }
+        self.labels = labels;
+        return Ok(());
+    }
+
+    /// returns the location of a label
+    fn label_location(&self, id: i32) -> Result<i32, VmError> {
+        match self.labels.iter().find(|label| label.id == id) {
+            Some(label) => return Ok(label.location),
+            None => return Err(self.fault(VmErrorKind::UnknownLabel(id))),
+        }
+    }
+
+    /// loads register contents from a register file (`index:value:locked` per line)
+    pub fn load_registers(&mut self, s: &str) -> Result<(), String> {
+        return register::register_from_string(s, &mut self.registers);
+    }
+
+    /// sets the content and lock state of a register before the program is run
+    pub fn preload_register(&mut self, index: usize, content: i32, locked: bool) -> Result<(), VmError> {
+        if index >= self.registers.len() {
+            return Err(self.fault(VmErrorKind::RegisterOutOfRange(index as i32)));
+        }
+        self.registers[index] = REGISTER { content, locked };
+        return Ok(());
+    }
+
+    /// returns the register at index
+    pub fn register(&self, index: usize) -> Option<&REGISTER> {
+        return self.registers.get(index);
+    }
+
+    /// returns the content of the stack, top of the stack last
+    pub fn stack(&self) -> &[i32] {
+        return &self.stack.content;
+    }
+
+    pub fn get_register_usage(&mut self) -> usize {
+        let mut used_reg_count = 0;
+        for i in self.registers.iter() {
+            if i.content != 0 {
                 used_reg_count += 1;
             }
         }
         return used_reg_count;
     }
 
+    /// registers a function guest programs can run with VMCALL, replacing any call already
+    /// registered under the same number (including the built in print and exit calls)
+    pub fn register_call<C: VmCall + Send + 'static>(&mut self, number: i32, call: C) {
+        self.calls.register(number, call);
+    }
+
+    /// sets the stream guest programs write their output to
+    pub fn set_stdout<W: Write + Send + 'static>(&mut self, out: W) {
+        self.streams.out = Box::new(out);
+    }
+
+    /// sets the stream guest programs write their errors to
+    pub fn set_stderr<W: Write + Send + 'static>(&mut self, err: W) {
+        self.streams.err = Box::new(err);
+    }
+
+    /// sets the stream guest programs read their input from
+    pub fn set_stdin<R: BufRead + Send + 'static>(&mut self, input: R) {
+        self.streams.input = Box::new(input);
+    }
+
+    /// sets what arithmetic opcodes do when their result overflows
+    pub fn set_arithmetic_mode(&mut self, mode: ArithmeticMode) {
+        self.arithmetic_mode = mode;
+    }
+
+    /// resizes the memory, keeping its content, the memory limit is checked against the new size
+    pub fn set_memory_size(&mut self, size: usize) -> Result<(), VmError> {
+        if let Some(max) = self.limits.max_memory {
+            if size > max {
+                return Err(self.fault(VmErrorKind::LimitExceeded(Limit::Memory(max))));
+            }
+        }
+        self.memory.content.resize(size, 0);
+        return Ok(());
+    }
+
+    /// sets the limits enforced by run, memory larger than max_memory (e.g. the default size)
+    /// shrinks to it
+    pub fn set_limits(&mut self, limits: Limits) {
+        if let Some(max) = limits.max_memory {
+            self.memory.content.truncate(max);
+        }
+        self.limits = limits;
+    }
+
+    /// creates a VmError located at the current program position
+    pub fn fault(&self, kind: VmErrorKind) -> VmError {
+        VmError::new(kind, self.program_set_counter, self.program_counter)
+    }
+
     // byte access
     /// returns the next 8 bits and increments the program counter
-    fn next_8_bits(&mut self) -> u8 {
-        let result = self.program[self.program_counter];
-        self.program_counter += 1;
-        return result;
+    fn next_8_bits(&mut self) -> Result<u8, VmError> {
+        match self.program.get(self.program_counter) {
+            Some(&result) => {
+                self.program_counter += 1;
+                return Ok(result);
+            }
+            None => return Err(self.fault(VmErrorKind::TruncatedInstruction)),
+        }
     }
 
     /// returns the next 16 bits and increments the program counter
-    fn next_16_bits(&mut self) -> u16 {
-        let result = ((self.program[self.program_counter] as u16) << 8)
-            | self.program[self.program_counter + 1] as u16;
-        self.program_counter += 2;
-        return result;
+    fn next_16_bits(&mut self) -> Result<u16, VmError> {
+        let high = self.next_8_bits()? as u16;
+        let low = self.next_8_bits()? as u16;
+        return Ok((high << 8) | low);
+    }
+
+    /// returns the 32 bit value held in the slot after the current instruction
+    fn next_long(&mut self) -> Result<i32, VmError> {
+        self.program_counter = (self.program_set_counter as usize + 1) * INSTRUCTION_SIZE;
+        let high = self.next_16_bits()? as u32;
+        let low = self.next_16_bits()? as u32;
+        return Ok(((high << 16) | low) as i32);
+    }
+
+    /// reads a jump target (a kind byte followed by a Long) and returns its set index
+    fn next_target(&mut self) -> Result<i32, VmError> {
+        let kind = self.next_8_bits()?;
+        let target = self.next_long()?;
+        match kind {
+            TARGET_SET_INDEX => return Ok(target),
+            TARGET_LABEL => return self.label_location(target),
+            _ => return Err(self.fault(VmErrorKind::InvalidTargetKind(kind))),
+        }
+    }
+
+    // register access
+    /// checks that a register index exists and returns it as usize
+    fn register_index(&self, index: i32) -> Result<usize, VmError> {
+        if index < 0 || index as usize >= self.registers.len() {
+            return Err(self.fault(VmErrorKind::RegisterOutOfRange(index)));
+        }
+        return Ok(index as usize);
+    }
+
+    /// reads a register operand and returns its index
+    fn next_register(&mut self) -> Result<usize, VmError> {
+        let register = self.next_8_bits()? as i32;
+        return self.register_index(register);
+    }
+
+    /// returns the addressing mode of the operand byte at the program counter
+    fn operand_mode(&self) -> u8 {
+        let position = self.program_counter - (self.program_set_counter as usize) * INSTRUCTION_SIZE;
+        return self.operand_modes.get(position.wrapping_sub(1)).copied().unwrap_or(MODE_INDIRECT);
+    }
+
+    /// reads a register operand and returns the register index stored in it, or the register
+    /// itself in direct mode
+    fn next_pointer(&mut self) -> Result<usize, VmError> {
+        let mode = self.operand_mode();
+        let register = self.next_register()?;
+        if mode == MODE_DIRECT {
+            return Ok(register);
+        }
+        return self.register_index(self.registers[register].content);
+    }
+
+    /// reads a register operand and returns the content of the register it points to, the
+    /// content of the register itself in direct mode or the operand byte in immediate mode
+    fn next_indirect(&mut self) -> Result<i32, VmError> {
+        if self.operand_mode() == MODE_IMMEDIATE {
+            return Ok(self.next_8_bits()? as i32);
+        }
+        let register = self.next_pointer()?;
+        return Ok(self.registers[register].content);
+    }
+
+    /// sets the content of a register, failing if the register is locked
+    fn set_register(&mut self, index: usize, val: i32) -> Result<(), VmError> {
+        if !self.registers[index].set(val) {
+            return Err(self.fault(VmErrorKind::LockedRegister(index)));
+        }
+        return Ok(());
+    }
+
+    /// returns the result of an arithmetic opcode in the current mode and updates the flags
+    fn arithmetic(&mut self, outcome: Outcome) -> Result<i32, VmError> {
+        let result = match outcome.result(self.arithmetic_mode) {
+            Some(result) => result,
+            None => return Err(self.fault(VmErrorKind::ArithmeticOverflow)),
+        };
+        self.flags = outcome.flags(result);
+        return Ok(result);
+    }
+
+    /// updates the flags as if the second value was subtracted from the first
+    fn compare(&mut self, register1: i32, register2: i32) {
+        let outcome = Outcome::sub(register1, register2);
+        self.flags = outcome.flags(outcome.wrapped);
     }
 
     // internal functions
     /// decodes opcode to Opcode object
-    fn decode_opcode(&mut self) -> (Opcode, u8) {
-        let original_op = self.program[self.program_counter];
-        let opcode = Opcode::from(original_op);
-        self.program_counter += 1;
-        return (opcode, original_op);
+    fn decode_opcode(&mut self) -> Result<(Opcode, u8), VmError> {
+        let original_op = self.next_8_bits()?;
+        return Ok((Opcode::from(original_op), original_op));
     }
 
     /// executes VM call
-    pub fn execute_vm_call(&mut self, call_name: i32, arg1: i32, arg2: i32) -> (bool, i32) {
-        match call_name {
-            // print call
-            0 => {
-                // print mode
-                if arg1 == 0 {
-                    print!("{}", arg2)
-                } else {
-                    println!("{}", arg2)
-                }
+    pub fn execute_vm_call(&mut self, call_name: i32, arg1: i32, arg2: i32) -> Result<Step, VmError> {
+        // the call is taken out of the table while it runs so it can borrow the VM
+        let mut call = match self.calls.unregister(call_name) {
+            Some(call) => call,
+            None => return Err(self.fault(VmErrorKind::InvalidVmCall(call_name))),
+        };
+        let result = call.call(self, arg1, arg2);
+        self.calls.restore(call_name, call);
+        return result;
+    }
+
+    // execution functions
+    /// Loops as long as instructions can be executed and returns the exit code of the program.
+    pub fn run(&mut self) -> Result<i32, VmError> {
+        self.resolve_labels()?;
+        self.executed_instructions = 0;
+        let started = Instant::now();
+        loop {
+            self.check_budget(started)?;
+            let step = self.execute_instruction()?;
+            self.executed_instructions += 1;
+            self.check_stack_depth()?;
+            if let Step::Exit(code) = step {
+                info!("process exited with code: {}", code);
+                self.flush_streams()?;
+                return Ok(code);
+            }
+        }
+    }
+
+    /// checks the instruction budget and the time limit before the next instruction runs,
+    /// running off the end of the program is not counted
+    fn check_budget(&self, started: Instant) -> Result<(), VmError> {
+        if self.at_end() {
+            return Ok(());
+        }
+        self.check_instruction_budget()?;
+        if let Some(max) = self.limits.max_duration {
+            if self.executed_instructions.is_multiple_of(TIME_CHECK_INTERVAL) && started.elapsed() > max {
+                return Err(self.fault(VmErrorKind::LimitExceeded(Limit::Duration(max))));
             }
+        }
+        return Ok(());
+    }
 
-            // exit call
-            1 => {
-                return (false, arg2);
+    /// checks the instruction budget before the next instruction runs, for hosts such as the
+    /// debugger that execute with run_once and count executed_instructions themselves
+    pub fn check_instruction_budget(&self) -> Result<(), VmError> {
+        if let Some(max) = self.limits.max_instructions {
+            if !self.at_end() && self.executed_instructions >= max {
+                return Err(self.fault(VmErrorKind::LimitExceeded(Limit::Instructions(max))));
             }
-            _ => panic!(
-                "Invalid VM call, {}  at program set: {} program counter: {} ",
-                call_name, self.program_set_counter, self.program_counter
-            ),
         }
-        return (true, 0);
+        return Ok(());
     }
 
-    // execution functions
-    /// Loops as long as instructions can be executed.
-    pub fn run(&mut self) {
-        loop {
-            let (dont_kill, code) = self.execute_instruction();
-            if !dont_kill {
-                println!("process exited with code: {}", code);
-                break;
+    /// checks the stack depth limit after an instruction ran
+    pub fn check_stack_depth(&self) -> Result<(), VmError> {
+        if let Some(max) = self.limits.max_stack_depth {
+            if self.stack.content.len() > max {
+                return Err(self.fault(VmErrorKind::LimitExceeded(Limit::StackDepth(max))));
             }
         }
+        return Ok(());
+    }
+
+    /// true when the program_set_counter is past the last instruction
+    fn at_end(&self) -> bool {
+        return self.program_set_counter >= 0
+            && self.program_set_counter as usize * INSTRUCTION_SIZE >= self.program.len();
+    }
+
+    /// flushes the guest output streams
+    pub fn flush_streams(&mut self) -> Result<(), VmError> {
+        if let Err(e) = self.streams.out.flush().and_then(|_| self.streams.err.flush()) {
+            return Err(self.fault(VmErrorKind::Io(e.to_string())));
+        }
+        return Ok(());
     }
 
     /// Executes one instruction. Meant to allow for more controlled execution of the VM
-    pub fn run_once(&mut self) {
-        self.execute_instruction();
+    pub fn run_once(&mut self) -> Result<Step, VmError> {
+        return self.execute_instruction();
     }
 
-    pub fn execute_instruction(&mut self) -> (bool, i32) {
+    pub fn execute_instruction(&mut self) -> Result<Step, VmError> {
+        // jumps only move the program_set_counter, the byte position is derived from it
+        if self.program_set_counter < 0 {
+            return Err(self.fault(VmErrorKind::InvalidJumpTarget(self.program_set_counter)));
+        }
+        self.program_counter = (self.program_set_counter as usize) * INSTRUCTION_SIZE;
         if self.program_counter >= self.program.len() {
             info!("program end reached");
-            return (false, 0);
+            return Ok(Step::Exit(0));
         }
 
-        let (decoded_op, code) = self.decode_opcode();
+        let (mut decoded_op, mut code) = self.decode_opcode()?;
+        self.operand_modes = [MODE_INDIRECT; 3];
+        if decoded_op == Opcode::MODE {
+            // MODE and the instruction it prefixes are executed as a single step
+            let modes = [self.next_8_bits()?, self.next_8_bits()?, self.next_8_bits()?];
+            self.program_set_counter += 1;
+            self.program_counter = (self.program_set_counter as usize) * INSTRUCTION_SIZE;
+            let (prefixed_op, prefixed_code) = self.decode_opcode()?;
+            if prefixed_op == Opcode::MODE {
+                return Err(self.fault(VmErrorKind::IllegalOpcode(prefixed_code)));
+            }
+            for (index, mode) in modes.iter().enumerate() {
+                if !prefixed_op.supports_mode(index + 1, *mode) {
+                    return Err(self.fault(VmErrorKind::InvalidAddressingMode(*mode)));
+                }
+            }
+            self.operand_modes = modes;
+            decoded_op = prefixed_op;
+            code = prefixed_code;
+        }
         info!("got new instruction {}", code);
 
         match decoded_op {
             Opcode::LOAD => {
-                let register = self.next_8_bits() as usize;
-                let number = self.next_16_bits() as u32;
+                let register = self.next_register()?;
+                let number = self.next_16_bits()? as u32;
                 info!("Loading {} to R{}", number, register);
                 // loads the number into the register
-                self.registers[register].do_set(number as i32);
+                self.set_register(register, number as i32)?;
+            }
+            Opcode::LOADW => {
+                let register = self.next_register()?;
+                let number = self.next_long()?;
+                info!("Loading {} to R{}", number, register);
+                self.set_register(register, number)?;
             }
             Opcode::HLT => {
-                println!("HLT encountered");
-                return (false, 0);
+                info!("HLT encountered");
+                return Ok(Step::Exit(0));
             }
             Opcode::ADD => {
-                let register1 = self.registers
-                    [self.registers[self.next_8_bits() as usize].content as usize]
-                    .content;
-                let register2 = self.registers
-                    [self.registers[self.next_8_bits() as usize].content as usize]
-                    .content;
-                let output_register = self.registers[self.next_8_bits() as usize].content;
+                let register1 = self.next_indirect()?;
+                let register2 = self.next_indirect()?;
+                let output_register = self.next_pointer()?;
                 // loads the sum of register 1 & 2 into the
-                self.registers[output_register as usize].do_set(register1 + register2);
+                let result = self.arithmetic(Outcome::add(register1, register2))?;
+                self.set_register(output_register, result)?;
             }
             Opcode::SUB => {
-                let register1 = self.registers
-                    [self.registers[self.next_8_bits() as usize].content as usize]
-                    .content;
-                let register2 = self.registers
-                    [self.registers[self.next_8_bits() as usize].content as usize]
-                    .content;
-                let output_register = self.registers[self.next_8_bits() as usize].content;
+                let register1 = self.next_indirect()?;
+                let register2 = self.next_indirect()?;
+                let output_register = self.next_pointer()?;
                 // loads the subtraction of register 1 & 2 into the
-                self.registers[output_register as usize].do_set(register1 - register2);
+                let result = self.arithmetic(Outcome::sub(register1, register2))?;
+                self.set_register(output_register, result)?;
             }
             Opcode::DIV => {
-                let register1 = self.registers
-                    [self.registers[self.next_8_bits() as usize].content as usize]
-                    .content;
-                let register2 = self.registers
-                    [self.registers[self.next_8_bits() as usize].content as usize]
-                    .content;
-                self.registers[self.next_8_bits() as usize].do_set(register1 / register2);
-                self.remainder = (register1 % register2) as i32;
+                let register1 = self.next_indirect()?;
+                let register2 = self.next_indirect()?;
+                let output_register = self.next_register()?;
+                if register2 == 0 {
+                    return Err(self.fault(VmErrorKind::DivisionByZero));
+                }
+                // i32::MIN / -1 is the only division that overflows, its remainder is 0
+                let result = self.arithmetic(Outcome::div(register1, register2))?;
+                self.set_register(output_register, result)?;
+                self.remainder = register1.wrapping_rem(register2);
+            }
+            Opcode::MUL => {
+                let register1 = self.next_indirect()?;
+                let register2 = self.next_indirect()?;
+                let output_register = self.next_pointer()?;
+                let result = self.arithmetic(Outcome::mul(register1, register2))?;
+                self.set_register(output_register, result)?;
+            }
+            Opcode::MOD => {
+                let register1 = self.next_indirect()?;
+                let register2 = self.next_indirect()?;
+                let output_register = self.next_pointer()?;
+                if register2 == 0 {
+                    return Err(self.fault(VmErrorKind::DivisionByZero));
+                }
+                // the result has the sign of the dividend, like the remainder of DIV
+                let result = self.arithmetic(Outcome::rem(register1, register2))?;
+                self.set_register(output_register, result)?;
+            }
+            Opcode::NEG => {
+                let value = self.next_indirect()?;
+                let output_register = self.next_pointer()?;
+                let result = self.arithmetic(Outcome::neg(value))?;
+                self.set_register(output_register, result)?;
+            }
+            Opcode::ABS => {
+                let value = self.next_indirect()?;
+                let output_register = self.next_pointer()?;
+                let result = self.arithmetic(Outcome::abs(value))?;
+                self.set_register(output_register, result)?;
+            }
+            Opcode::INC => {
+                let target_register = self.next_pointer()?;
+                let value = self.registers[target_register].content;
+                let result = self.arithmetic(Outcome::add(value, 1))?;
+                self.set_register(target_register, result)?;
+            }
+            Opcode::DEC => {
+                let target_register = self.next_pointer()?;
+                let value = self.registers[target_register].content;
+                let result = self.arithmetic(Outcome::sub(value, 1))?;
+                self.set_register(target_register, result)?;
             }
             Opcode::JMP => {
                 let current_pos = self.program_counter;
-                let target = self.registers
-                    [self.registers[self.next_8_bits() as usize].content as usize]
-                    .content;
+                let target = self.next_indirect()?;
                 self.program_set_counter = target;
                 self.program_counter = 0;
 
                 info!("jumped from {} to {}", current_pos, target);
-                return (true, 0);
+                return Ok(Step::Continue);
             }
             Opcode::RJMP => {
                 let current_pos = self.program_counter;
-                let value = self.registers
-                    [self.registers[self.next_8_bits() as usize].content as usize]
-                    .content;
-                self.program_set_counter += value;
+                let value = self.next_indirect()?;
+                self.program_set_counter = match self.program_set_counter.checked_add(value) {
+                    Some(target) => target,
+                    None => {
+                        let target = self.program_set_counter.saturating_add(value);
+                        return Err(self.fault(VmErrorKind::InvalidJumpTarget(target)));
+                    }
+                };
                 self.program_counter = 0;
                 info!("jumped from {} to {}", current_pos, self.program_counter);
-                return (true, 0);
+                return Ok(Step::Continue);
             }
             Opcode::VMCALL => {
-                let call_name = self.registers
-                    [self.registers[self.next_8_bits() as usize].content as usize]
-                    .content;
-                let arg1 = self.registers
-                    [self.registers[self.next_8_bits() as usize].content as usize]
-                    .content;
-                let arg2 = self.registers
-                    [self.registers[self.next_8_bits() as usize].content as usize]
-                    .content;
+                let call_name = self.next_indirect()?;
+                let arg1 = self.next_indirect()?;
+                let arg2 = self.next_indirect()?;
                 info!("executing VMCALL {} {} {}", call_name, arg1, arg2);
-                return self.execute_vm_call(call_name, arg1, arg2); // returns false if kill
+                let step = self.execute_vm_call(call_name, arg1, arg2)?;
+                if step != Step::Continue {
+                    return Ok(step); // returns Exit if kill
+                }
             }
             Opcode::EQ => {
-                let register1 = self.registers
-                    [self.registers[self.next_8_bits() as usize].content as usize]
-                    .content;
-                let register2 = self.registers
-                    [self.registers[self.next_8_bits() as usize].content as usize]
-                    .content;
-                let output_register = self.registers[self.next_8_bits() as usize].content as usize;
-                if register1 == register2 {
-                    self.registers[output_register as usize].do_set(1);
-                } else {
-                    self.registers[output_register as usize].do_set(0);
-                }
+                let register1 = self.next_indirect()?;
+                let register2 = self.next_indirect()?;
+                let output_register = self.next_pointer()?;
+                self.compare(register1, register2);
+                self.set_register(output_register, (register1 == register2) as i32)?;
             }
             Opcode::JEQ => {
                 let current_pos = self.program_counter;
-                let source = self.registers
-                    [self.registers[self.next_8_bits() as usize].content as usize]
-                    .content;
-                let target = self.registers
-                    [self.registers[self.next_8_bits() as usize].content as usize]
-                    .content;
+                let source = self.next_indirect()?;
+                let target = self.next_indirect()?;
                 if source == 1 {
-                    self.program_counter = 0 as usize;
+                    self.program_counter = 0;
                     self.program_set_counter = target;
-                    return (true, 0);
+                    return Ok(Step::Continue);
                 }
                 info!("jumped from {} to {}", current_pos, target);
             }
             Opcode::NEQ => {
-                let register1 = self.registers
-                    [self.registers[self.next_8_bits() as usize].content as usize]
-                    .content;
-                let register2 = self.registers
-                    [self.registers[self.next_8_bits() as usize].content as usize]
-                    .content;
-                let output_register = self.registers[self.next_8_bits() as usize].content as usize;
-                if register1 != register2 {
-                    self.registers[output_register as usize].do_set(1);
-                } else {
-                    self.registers[output_register as usize].do_set(0);
-                }
+                let register1 = self.next_indirect()?;
+                let register2 = self.next_indirect()?;
+                let output_register = self.next_pointer()?;
+                self.compare(register1, register2);
+                self.set_register(output_register, (register1 != register2) as i32)?;
             }
             Opcode::JNEQ => {
                 let current_pos = self.program_counter;
-                let source = self.registers
-                    [self.registers[self.next_8_bits() as usize].content as usize]
-                    .content;
-                let target = self.registers
-                    [self.registers[self.next_8_bits() as usize].content as usize]
-                    .content;
+                let source = self.next_indirect()?;
+                let target = self.next_indirect()?;
 
                 if source == 0 {
                     self.program_set_counter = target;
-                    self.program_counter = 0 as usize;
+                    self.program_counter = 0;
                     info!("jumped from {} to {}", current_pos, target);
-                    return (true, 0);
+                    return Ok(Step::Continue);
                 }
             }
             Opcode::SWP => {
-                let reg1 = self.registers[self.next_8_bits() as usize].content as usize;
-                let reg2 = self.registers[self.next_8_bits() as usize].content as usize;
+                let reg1 = self.next_pointer()?;
+                let reg2 = self.next_pointer()?;
                 let reg1v = self.registers[reg1].content;
                 let reg2v = self.registers[reg2].content;
 
-                self.registers[reg1].do_set(reg2v);
-                self.registers[reg2].do_set(reg1v);
+                self.set_register(reg1, reg2v)?;
+                self.set_register(reg2, reg1v)?;
 
                 info!("swaped R{} with R{}", reg1, reg2)
             }
             Opcode::AND => {
-                let register1 = self.registers
-                    [self.registers[self.next_8_bits() as usize].content as usize]
-                    .content;
-                let register2 = self.registers
-                    [self.registers[self.next_8_bits() as usize].content as usize]
-                    .content;
-                let output_register = self.registers[self.next_8_bits() as usize].content as usize;
+                let register1 = self.next_indirect()?;
+                let register2 = self.next_indirect()?;
+                let output_register = self.next_pointer()?;
                 if register1 == 0 || register1 == 1 || register2 == 1 || register2 == 0 {
-                    if register1 == 1 && register2 == 1 {
-                        self.registers[output_register].do_set(1);
-                    } else {
-                        self.registers[output_register].do_set(0);
-                    }
+                    let result = register1 == 1 && register2 == 1;
+                    self.set_register(output_register, result as i32)?;
                 } else {
                     error!(
                         "AND opcode arguments {} {} are not boolean",
@@ -289,22 +680,12 @@ impl VM {
                 }
             }
             Opcode::OR => {
-                let register1 = self.registers
-                    [self.registers[self.next_8_bits() as usize].content as usize]
-                    .content;
-                let register2 = self.registers
-                    [self.registers[self.next_8_bits() as usize].content as usize]
-                    .content;
-                let output_register = self.registers[self.next_8_bits() as usize].content as usize;
+                let register1 = self.next_indirect()?;
+                let register2 = self.next_indirect()?;
+                let output_register = self.next_pointer()?;
                 if register1 == 0 || register1 == 1 || register2 == 1 || register2 == 0 {
-                    if (register1 == 1 && register2 == 1)
-                        || (register1 == 1 && register2 == 0)
-                        || (register1 == 0 && register2 == 1)
-                    {
-                        self.registers[output_register].do_set(1);
-                    } else {
-                        self.registers[output_register].do_set(0);
-                    }
+                    let result = register1 == 1 || register2 == 1;
+                    self.set_register(output_register, result as i32)?;
                 } else {
                     error!(
                         "OR opcode arguments {} {} are not boolean",
@@ -313,29 +694,130 @@ impl VM {
                 }
             }
             Opcode::NOT => {
-                let register1 = self.registers
-                    [self.registers[self.next_8_bits() as usize].content as usize]
-                    .content;
-                let output_register = self.registers[self.next_8_bits() as usize].content as usize;
+                let register1 = self.next_indirect()?;
+                let output_register = self.next_pointer()?;
                 if register1 == 0 {
-                    self.registers[output_register].do_set(1);
+                    self.set_register(output_register, 1)?;
                 } else if register1 == 1 {
-                    self.registers[output_register].do_set(0);
+                    self.set_register(output_register, 0)?;
                 } else {
                     error!("NOT opcode arguments {} is not boolean", register1)
                 }
             }
+            Opcode::BAND | Opcode::BOR | Opcode::BXOR | Opcode::SHL | Opcode::SHR | Opcode::SAR => {
+                let register1 = self.next_indirect()?;
+                let register2 = self.next_indirect()?;
+                let output_register = self.next_pointer()?;
+                let outcome = match decoded_op {
+                    Opcode::BAND => Outcome::exact(register1 & register2),
+                    Opcode::BOR => Outcome::exact(register1 | register2),
+                    Opcode::BXOR => Outcome::exact(register1 ^ register2),
+                    Opcode::SHL => Outcome::shl(register1, register2),
+                    Opcode::SHR => Outcome::shr(register1, register2),
+                    _ => Outcome::sar(register1, register2),
+                };
+                let result = self.arithmetic(outcome)?;
+                self.set_register(output_register, result)?;
+            }
+            Opcode::BNOT => {
+                let value = self.next_indirect()?;
+                let output_register = self.next_pointer()?;
+                let result = self.arithmetic(Outcome::exact(!value))?;
+                self.set_register(output_register, result)?;
+            }
+            Opcode::LT | Opcode::LE | Opcode::GT | Opcode::GE
+            | Opcode::LTU | Opcode::LEU | Opcode::GTU | Opcode::GEU => {
+                let register1 = self.next_indirect()?;
+                let register2 = self.next_indirect()?;
+                let output_register = self.next_pointer()?;
+                self.compare(register1, register2);
+                let result = condition(decoded_op, register1, register2) as i32;
+                self.set_register(output_register, result)?;
+            }
+            Opcode::BEQ | Opcode::BNE | Opcode::BLT | Opcode::BLE | Opcode::BGT | Opcode::BGE
+            | Opcode::BLTU | Opcode::BLEU | Opcode::BGTU | Opcode::BGEU => {
+                let register1 = self.next_indirect()?;
+                let register2 = self.next_indirect()?;
+                let target = self.next_target()?;
+                self.compare(register1, register2);
+                if condition(decoded_op, register1, register2) {
+                    self.program_set_counter = target;
+                    self.program_counter = 0;
+                    info!("branched to {}", target);
+                    return Ok(Step::Continue);
+                }
+            }
+            Opcode::CALL => {
+                let target = self.next_target()?;
+                if let Some(max) = self.limits.max_call_depth {
+                    if self.call_stack.len() >= max {
+                        return Err(self.fault(VmErrorKind::LimitExceeded(Limit::CallDepth(max))));
+                    }
+                }
+                let slots = decoded_op.slots() as i32;
+                let return_location = match self.program_set_counter.checked_add(slots) {
+                    Some(location) => location,
+                    None => return Err(self.fault(VmErrorKind::InvalidJumpTarget(i32::MAX))),
+                };
+                self.call_stack.push(FRAME {
+                    call_location: self.program_set_counter,
+                    return_location,
+                    target,
+                });
+                self.program_set_counter = target;
+                self.program_counter = 0;
+                info!("called {}", target);
+                return Ok(Step::Continue);
+            }
+            Opcode::RET => {
+                let frame = match self.call_stack.pop() {
+                    Some(frame) => frame,
+                    None => return Err(self.fault(VmErrorKind::CallStackUnderflow)),
+                };
+                self.program_set_counter = frame.return_location;
+                self.program_counter = 0;
+                info!("returned to {}", frame.return_location);
+                return Ok(Step::Continue);
+            }
+            Opcode::LD8 | Opcode::LD16 | Opcode::LD32 => {
+                let address = self.next_indirect()?;
+                let output_register = self.next_pointer()?;
+                let width = decoded_op.access_width();
+                match self.memory.load(address, width) {
+                    Some(value) => self.set_register(output_register, value)?,
+                    None => {
+                        return Err(self.fault(VmErrorKind::MemoryOutOfBounds { address, width }))
+                    }
+                }
+            }
+            Opcode::ST8 | Opcode::ST16 | Opcode::ST32 => {
+                let value = self.next_indirect()?;
+                let address = self.next_indirect()?;
+                let width = decoded_op.access_width();
+                if !self.memory.store(address, width, value) {
+                    return Err(self.fault(VmErrorKind::MemoryOutOfBounds { address, width }));
+                }
+            }
             Opcode::GET => {
-                let hidden_register_id = self.registers[self.next_8_bits() as usize].content;
-                let output_register = self.registers[self.next_8_bits() as usize].content as usize;
+                let hidden_register = self.next_register()?;
+                let hidden_register_id = self.registers[hidden_register].content;
+                let output_register = self.next_pointer()?;
                 match hidden_register_id {
                     // remainder register
                     0 => {
-                        self.registers[output_register].do_set(self.remainder);
+                        self.set_register(output_register, self.remainder)?;
                         self.remainder = 0;
                     }
+                    // input status register
+                    1 => {
+                        self.set_register(output_register, self.input_status)?;
+                    }
+                    // flags register
+                    2 => {
+                        self.set_register(output_register, self.flags)?;
+                    }
                     _ => {
-                        self.registers[output_register].do_set(0);
+                        self.set_register(output_register, 0)?;
                     }
                 }
                 info!(
@@ -344,8 +826,7 @@ impl VM {
                 )
             }
             Opcode::LOCKR => {
-                let register_to_toggle_lock =
-                    self.registers[self.next_8_bits() as usize].content as usize;
+                let register_to_toggle_lock = self.next_pointer()?;
                 self.registers[register_to_toggle_lock].toggle_lock();
                 info!(
                     "R{} is now locked:{}",
@@ -353,50 +834,73 @@ impl VM {
                 )
             }
             Opcode::PUSHRTS => {
-                let target_register = self.registers[self.next_8_bits() as usize].content as usize;
-                self.stack.add_register(self.registers[target_register]);
-                self.registers[target_register].content = 0;
+                let target_register = self.next_pointer()?;
+                let register = self.registers[target_register];
+                self.set_register(target_register, 0)?;
+                self.stack.add_register(register);
             }
             Opcode::POPRFS => {
-                let target_register = self.registers[self.next_8_bits() as usize].content as usize;
-                self.registers[target_register].content = self.stack.content.pop().unwrap();
+                let target_register = self.next_pointer()?;
+                match self.stack.content.pop() {
+                    Some(value) => self.set_register(target_register, value)?,
+                    None => return Err(self.fault(VmErrorKind::StackUnderflow)),
+                }
             }
             Opcode::BREAK => {
-                println!("hit BREAK on line:{}", self.program_set_counter);
-                let mut d = DebugEngine{};
-                d.wait_for_commands(self, std::io::stdin());
+                if self.debugger_attached {
+                    self.program_set_counter += 1;
+                    return Ok(Step::Break);
+                }
+                let location = self.program_set_counter;
+                // the prompt uses the streams of the guest, a host that replaced them never has
+                // the process stdin read or its stdout written
+                let mut streams = std::mem::take(&mut self.streams);
+                if let Err(e) = writeln!(streams.out, "hit BREAK on line:{}", location) {
+                    self.streams = streams;
+                    return Err(self.fault(VmErrorKind::Io(e.to_string())));
+                }
+                let mut debugger = DebugEngine::with_output(streams.out);
+                let action = debugger.wait_for_commands(self, &mut streams.input);
+                streams.out = debugger.into_output();
+                self.streams = streams;
+                if action == Action::Quit {
+                    info!("debugger quit at BREAK");
+                    return Ok(Step::Exit(0));
+                }
+                // the debugger moved execution somewhere else
+                if self.program_set_counter != location {
+                    return Ok(Step::Continue);
+                }
             }
             Opcode::LABEL => {
-                let label_id = self.next_8_bits() as i32;
-                self.labels.append(&mut vec![LABEL{ id: label_id, location: self.program_set_counter }]);
+                // labels are registered by resolve_labels, this only covers programs that are
+                // stepped through without resolving them first
+                let label_id = self.next_8_bits()? as i32;
+                if self.labels.iter().all(|label| label.id != label_id) {
+                    self.labels.push(LABEL{ id: label_id, location: self.program_set_counter });
+                }
             }
             Opcode::GOTO => {
-                let label_id = self.next_8_bits() as i32;
-                let mut found = false;
-                for label in self.labels.iter() {
-                    if label.id == label_id {
-                        self.program_set_counter = label.location;
-                        self.program_counter = 0;
-                        found = true;
-                        break
-                    }
-                }
-                if !found {
-                    error!("GOTO label {} not found", label_id);
-                }
-                return (true, 0)
+                let label_id = self.next_8_bits()? as i32;
+                self.program_set_counter = self.label_location(label_id)?;
+                self.program_counter = 0;
+                info!("jumped to label {}", label_id);
+                return Ok(Step::Continue)
+            }
+            Opcode::JMPTL => {
+                let label_id = self.next_indirect()?;
+                self.program_set_counter = self.label_location(label_id)?;
+                self.program_counter = 0;
+                info!("jumped to label {}", label_id);
+                return Ok(Step::Continue)
             }
             _ => {
-                println!(
-                    "Unknown opcode at program set: {} program counter: {}",
-                    self.program_set_counter, self.program_counter
-                );
-                return (false, 4);
+                return Err(self.fault(VmErrorKind::IllegalOpcode(code)));
             }
         }
-        self.program_set_counter += 1;
-        self.program_counter = (self.program_set_counter as usize) * (4 as usize);
-        return (true, 0);
+        self.program_set_counter += decoded_op.slots() as i32;
+        self.program_counter = (self.program_set_counter as usize) * INSTRUCTION_SIZE;
+        return Ok(Step::Continue);
     }
 
     /// resets the register to original state
@@ -421,9 +925,29 @@ impl VM {
     }
 }
 
+/// evaluates the condition of an ordered comparison or a branch
+fn condition(opcode: Opcode, register1: i32, register2: i32) -> bool {
+    let (unsigned1, unsigned2) = (register1 as u32, register2 as u32);
+    match opcode {
+        Opcode::BEQ => return register1 == register2,
+        Opcode::BNE => return register1 != register2,
+        Opcode::LT | Opcode::BLT => return register1 < register2,
+        Opcode::LE | Opcode::BLE => return register1 <= register2,
+        Opcode::GT | Opcode::BGT => return register1 > register2,
+        Opcode::GE | Opcode::BGE => return register1 >= register2,
+        Opcode::LTU | Opcode::BLTU => return unsigned1 < unsigned2,
+        Opcode::LEU | Opcode::BLEU => return unsigned1 <= unsigned2,
+        Opcode::GTU | Opcode::BGTU => return unsigned1 > unsigned2,
+        Opcode::GEU | Opcode::BGEU => return unsigned1 >= unsigned2,
+        _ => return false,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::arithmetic::{FLAG_CARRY, FLAG_NEGATIVE, FLAG_OVERFLOW, FLAG_ZERO};
+    use crate::streams::SharedBuffer;
 
     #[test]
     fn test_create_vm() {
@@ -437,7 +961,7 @@ mod tests {
         let mut test_vm = VM::new();
         let test_bytes = vec![0, 0, 0, 0];
         test_vm.program = test_bytes;
-        test_vm.run();
+        test_vm.run().unwrap();
         assert_eq!(test_vm.program_counter, 1);
     }
 
@@ -446,7 +970,8 @@ mod tests {
         let mut test_vm = VM::new();
         let test_bytes = vec![200, 0, 0, 0];
         test_vm.program = test_bytes;
-        test_vm.run();
+        let err = test_vm.run().unwrap_err();
+        assert_eq!(err.kind, VmErrorKind::IllegalOpcode(200));
         assert_eq!(test_vm.program_counter, 1);
     }
 
@@ -454,7 +979,7 @@ mod tests {
     fn test_load_opcode() {
         let mut test_vm = VM::new();
         test_vm.program = vec![1, 0, 1, 244]; // this is how we represent 500 using two u8s in little endian format
-        test_vm.run();
+        test_vm.run().unwrap();
         assert_eq!(test_vm.registers[0].content, 500);
     }
 
@@ -467,7 +992,7 @@ mod tests {
         test_vm.registers[3].content = 1;
         test_vm.registers[4].content = 4;
         test_vm.program = vec![2, 2, 3, 4]; // load(opcode: 1) 500 into register 0
-        test_vm.run();
+        test_vm.run().unwrap();
 
         assert_eq!(test_vm.registers[4].content, 1000)
     }
@@ -483,12 +1008,102 @@ mod tests {
 
         test_vm.registers[4].content = 4;
         test_vm.program = vec![4, 2, 3, 4]; // load(opcode: 1) 500 into register 0
-        test_vm.run();
+        test_vm.run().unwrap();
 
         assert_eq!(test_vm.registers[4].content, 2);
         assert_eq!(test_vm.remainder, 1);
     }
 
+    #[test]
+    fn test_div_overflow() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0].content = i32::MIN;
+        test_vm.registers[1].content = -1;
+        test_vm.registers[2].content = 0;
+        test_vm.registers[3].content = 1;
+        test_vm.program = vec![4, 2, 3, 4];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[4].content, i32::MIN);
+        assert_eq!(test_vm.remainder, 0);
+    }
+
+    #[test]
+    fn test_mul_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0].content = -7;
+        test_vm.registers[1].content = 6;
+        test_vm.registers[2].content = 0;
+        test_vm.registers[3].content = 1;
+        test_vm.registers[4].content = 5;
+        test_vm.program = vec![25, 2, 3, 4];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[5].content, -42);
+
+        test_vm.registers[0].content = i32::MAX;
+        test_vm.registers[1].content = 2;
+        test_vm.reset_program();
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[5].content, -2);
+    }
+
+    #[test]
+    fn test_mod_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0].content = -7;
+        test_vm.registers[1].content = 3;
+        test_vm.registers[2].content = 0;
+        test_vm.registers[3].content = 1;
+        test_vm.registers[4].content = 5;
+        test_vm.program = vec![26, 2, 3, 4];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[5].content, -1);
+
+        test_vm.registers[0].content = i32::MIN;
+        test_vm.registers[1].content = -1;
+        test_vm.reset_program();
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[5].content, 0);
+
+        test_vm.registers[1].content = 0;
+        test_vm.reset_program();
+        assert_eq!(test_vm.run().unwrap_err().kind, VmErrorKind::DivisionByZero);
+    }
+
+    #[test]
+    fn test_neg_and_abs_opcodes() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0].content = 5;
+        test_vm.registers[1].content = i32::MIN;
+        test_vm.registers[2].content = 0;
+        test_vm.registers[3].content = 1;
+        test_vm.registers[4].content = 6;
+        test_vm.registers[5].content = 7;
+        test_vm.program = vec![27, 2, 4, 0, 27, 3, 5, 0];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[6].content, -5);
+        assert_eq!(test_vm.registers[7].content, i32::MIN);
+
+        test_vm.registers[0].content = -5;
+        test_vm.program = vec![30, 2, 4, 0, 30, 3, 5, 0];
+        test_vm.reset_program();
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[6].content, 5);
+        assert_eq!(test_vm.registers[7].content, i32::MIN);
+    }
+
+    #[test]
+    fn test_inc_and_dec_opcodes() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0].content = i32::MAX;
+        test_vm.registers[1].content = 0;
+        test_vm.registers[2].content = 3;
+        test_vm.registers[3].content = 10;
+        test_vm.program = vec![28, 1, 0, 0, 29, 2, 0, 0, 29, 2, 0, 0];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[0].content, i32::MIN);
+        assert_eq!(test_vm.registers[3].content, 8);
+    }
+
     #[test]
     fn test_sub_opcode() {
         let mut test_vm = VM::new();
@@ -499,7 +1114,7 @@ mod tests {
         test_vm.registers[4].content = 4;
 
         test_vm.program = vec![3, 2, 3, 4];
-        test_vm.run();
+        test_vm.run().unwrap();
         assert_eq!(test_vm.registers[4].content, 2)
     }
 
@@ -509,7 +1124,7 @@ mod tests {
         test_vm.registers[0].content = 1;
         test_vm.registers[1].content = 0;
         test_vm.program = vec![5, 1, 0, 0];
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.program_set_counter, 1);
     }
 
@@ -519,7 +1134,7 @@ mod tests {
         test_vm.registers[0].content = 2;
         test_vm.registers[1].content = 0;
         test_vm.program = vec![6, 1, 0, 0, 0, 0, 0, 0];
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.program_set_counter, 2);
     }
 
@@ -536,7 +1151,17 @@ mod tests {
         test_vm.registers[5].content = 4; // arg2 ref
 
         test_vm.program = vec![8, 3, 5, 1];
-        test_vm.run();
+        test_vm.run().unwrap();
+    }
+
+    #[test]
+    fn test_vmcall_continues_with_next_instruction() {
+        let mut test_vm = VM::new();
+        test_vm.registers[1].content = 0; // print call and print mode
+        test_vm.program = vec![8, 1, 1, 1,  1, 2, 0, 3];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[2].content, 3);
+        assert_eq!(test_vm.program_set_counter, 2);
     }
 
     #[test]
@@ -549,14 +1174,14 @@ mod tests {
         test_vm.registers[4].content = 0;
         test_vm.registers[5].content = 1;
         test_vm.program = vec![9, 4, 5, 3];
-        test_vm.run();
-        assert_eq!(test_vm.registers[3].content, 1 as i32);
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[3].content, 1);
 
         test_vm.reset_program();
         test_vm.registers[1].content = 25;
         test_vm.registers[3].content = 3;
-        test_vm.run();
-        assert_eq!(test_vm.registers[3].content, 0 as i32);
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[3].content, 0);
     }
 
     #[test]
@@ -568,12 +1193,12 @@ mod tests {
         test_vm.registers[3].content = 1;
         test_vm.registers[4].content = 2;
         test_vm.program = vec![10, 3, 4, 0];
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.program_set_counter, 7);
 
         test_vm.registers[1].content = 0;
         test_vm.reset_program();
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.program_set_counter, 1);
     }
 
@@ -587,13 +1212,13 @@ mod tests {
         test_vm.registers[4].content = 0;
         test_vm.registers[5].content = 1;
         test_vm.program = vec![11, 4, 5, 3];
-        test_vm.run();
-        assert_eq!(test_vm.registers[3].content, 0 as i32);
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[3].content, 0);
         test_vm.reset_program();
         test_vm.registers[1].content = 25;
         test_vm.registers[3].content = 3;
-        test_vm.run();
-        assert_eq!(test_vm.registers[3].content, 1 as i32);
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[3].content, 1);
     }
 
     #[test]
@@ -605,12 +1230,12 @@ mod tests {
         test_vm.registers[3].content = 1;
         test_vm.registers[4].content = 2;
         test_vm.program = vec![12, 3, 4, 0];
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.program_set_counter, 1);
 
         test_vm.registers[1].content = 0;
         test_vm.reset_program();
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.program_set_counter, 7);
     }
 
@@ -624,7 +1249,7 @@ mod tests {
         test_vm.registers[4].content = 2; // bool source register ref
 
         test_vm.program = vec![13, 3, 4, 0];
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.registers[1].content, 7);
         assert_eq!(test_vm.registers[2].content, 1);
     }
@@ -639,7 +1264,7 @@ mod tests {
         test_vm.registers[4].content = 1;
         test_vm.registers[5].content = 2;
         test_vm.program = vec![14, 4, 5, 3];
-        test_vm.run();
+        test_vm.run().unwrap();
         assert_eq!(test_vm.registers[3].content, 1);
 
         test_vm.registers[1].content = 1;
@@ -649,7 +1274,7 @@ mod tests {
         test_vm.registers[4].content = 1;
         test_vm.registers[5].content = 2;
         test_vm.reset_program();
-        test_vm.run();
+        test_vm.run().unwrap();
         assert_eq!(test_vm.registers[3].content, 0);
     }
 
@@ -663,7 +1288,7 @@ mod tests {
         test_vm.registers[4].content = 1;
         test_vm.registers[5].content = 2;
         test_vm.program = vec![15, 4, 5, 3];
-        test_vm.run();
+        test_vm.run().unwrap();
         assert_eq!(test_vm.registers[3].content, 1);
 
         test_vm.registers[1].content = 1;
@@ -673,7 +1298,7 @@ mod tests {
         test_vm.registers[4].content = 1;
         test_vm.registers[5].content = 2;
         test_vm.reset_program();
-        test_vm.run();
+        test_vm.run().unwrap();
         assert_eq!(test_vm.registers[3].content, 1);
 
         test_vm.registers[1].content = 0;
@@ -683,7 +1308,7 @@ mod tests {
         test_vm.registers[4].content = 1;
         test_vm.registers[5].content = 2;
         test_vm.reset_program();
-        test_vm.run();
+        test_vm.run().unwrap();
         assert_eq!(test_vm.registers[3].content, 1);
 
         test_vm.registers[1].content = 0;
@@ -693,7 +1318,7 @@ mod tests {
         test_vm.registers[4].content = 1;
         test_vm.registers[5].content = 2;
         test_vm.reset_program();
-        test_vm.run();
+        test_vm.run().unwrap();
         assert_eq!(test_vm.registers[3].content, 0);
     }
 
@@ -705,7 +1330,7 @@ mod tests {
 
         test_vm.registers[4].content = 1;
         test_vm.program = vec![16, 4, 3, 0];
-        test_vm.run();
+        test_vm.run().unwrap();
         assert_eq!(test_vm.registers[3].content, 0);
 
         test_vm.registers[1].content = 0;
@@ -714,10 +1339,47 @@ mod tests {
         test_vm.registers[4].content = 1;
         test_vm.program = vec![16, 4, 3, 0];
         test_vm.reset_program();
-        test_vm.run();
+        test_vm.run().unwrap();
         assert_eq!(test_vm.registers[3].content, 1);
     }
 
+    #[test]
+    fn test_bitwise_opcodes() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0].content = 0b1100;
+        test_vm.registers[1].content = 0b1010;
+        test_vm.registers[2].content = 0;
+        test_vm.registers[3].content = 1;
+        test_vm.registers[4].content = 10;
+        test_vm.registers[5].content = 11;
+        test_vm.registers[6].content = 12;
+        test_vm.registers[7].content = 13;
+        test_vm.program = vec![31, 2, 3, 4, 32, 2, 3, 5, 33, 2, 3, 6, 34, 2, 7, 0];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[10].content, 0b1000);
+        assert_eq!(test_vm.registers[11].content, 0b1110);
+        assert_eq!(test_vm.registers[12].content, 0b0110);
+        assert_eq!(test_vm.registers[13].content, !0b1100);
+        assert_eq!(test_vm.flags, FLAG_NEGATIVE);
+    }
+
+    #[test]
+    fn test_shift_opcodes() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0].content = -16;
+        test_vm.registers[1].content = 2;
+        test_vm.registers[2].content = 0;
+        test_vm.registers[3].content = 1;
+        test_vm.registers[4].content = 10;
+        test_vm.registers[5].content = 11;
+        test_vm.registers[6].content = 12;
+        test_vm.program = vec![35, 2, 3, 4, 36, 2, 3, 5, 37, 2, 3, 6];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[10].content, -64);
+        assert_eq!(test_vm.registers[11].content, 0x3FFF_FFFC);
+        assert_eq!(test_vm.registers[12].content, -4);
+    }
+
     #[test]
     fn test_get_opcode() {
         let mut test_vm = VM::new();
@@ -725,7 +1387,7 @@ mod tests {
         test_vm.registers[3].content = 3;
         test_vm.remainder = 2;
         test_vm.program = vec![17, 1, 3];
-        test_vm.run();
+        test_vm.run().unwrap();
         assert_eq!(test_vm.registers[3].content, 2);
     }
 
@@ -736,7 +1398,7 @@ mod tests {
         test_vm.registers[3].content = 1;
         test_vm.remainder = 2;
         test_vm.program = vec![19, 3, 0];
-        test_vm.run();
+        test_vm.run().unwrap();
         assert_eq!(test_vm.stack.content.pop(), Some(2));
         assert_eq!(test_vm.registers[1].content, 0)
     }
@@ -748,7 +1410,7 @@ mod tests {
         test_vm.registers[3].content = 1;
         test_vm.remainder = 2;
         test_vm.program = vec![20, 3, 0];
-        test_vm.run();
+        test_vm.run().unwrap();
         assert_eq!(test_vm.registers[1].content, 10)
     }
     #[test]
@@ -757,7 +1419,7 @@ mod tests {
         test_vm.stack.content.push(10);
         test_vm.remainder = 2;
         test_vm.program = vec![1, 1, 1, 1, 22, 3, 0, 0,  1, 1, 1, 1,  1, 1, 1, 1];
-        test_vm.run();
+        test_vm.run().unwrap();
         assert_eq!(test_vm.labels[1].id, 3);
         assert_eq!(test_vm.labels[1].location, 1);
     }
@@ -769,10 +1431,597 @@ mod tests {
         test_vm.program = vec![1, 1, 1, 1,  1, 1, 1, 1,  1, 1, 1, 1,  23, 3, 0, 0];
         test_vm.labels.append(&mut vec![LABEL { id: 3, location: 2 }]);
         for _ in 0..4 {
-            test_vm.run_once();
+            test_vm.run_once().unwrap();
         }
 
         assert_eq!(test_vm.program_counter, 0);
         assert_eq!(test_vm.program_set_counter, 2);
     }
+
+    #[test]
+    fn test_jmp_executes_target() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0].content = 2;
+        test_vm.registers[1].content = 0;
+        test_vm.program = vec![5, 1, 0, 0,  1, 5, 0, 1,  1, 6, 0, 1];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[5].content, 0);
+        assert_eq!(test_vm.registers[6].content, 1);
+    }
+
+    #[test]
+    fn test_goto_forward_label() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![23, 1, 0, 0,  1, 0, 0, 5,  22, 1, 0, 0,  1, 1, 0, 7];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[0].content, 0);
+        assert_eq!(test_vm.registers[1].content, 7);
+    }
+
+    #[test]
+    fn test_jmptl_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.registers[2].content = 9; // label id
+        test_vm.registers[3].content = 2; // label id ref
+        test_vm.program = vec![7, 3, 0, 0,  1, 0, 0, 5,  22, 9, 0, 0,  1, 1, 0, 7];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[0].content, 0);
+        assert_eq!(test_vm.registers[1].content, 7);
+    }
+
+    #[test]
+    fn test_label_errors() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![22, 3, 0, 0,  22, 3, 0, 0];
+        let err = test_vm.run().unwrap_err();
+        assert_eq!(err.kind, VmErrorKind::DuplicateLabel(3));
+        assert_eq!(err.program_set_counter, 1);
+
+        let mut test_vm = VM::new();
+        test_vm.program = vec![1, 1, 0, 1,  23, 4, 0, 0];
+        let err = test_vm.run().unwrap_err();
+        assert_eq!(err.kind, VmErrorKind::UnknownLabel(4));
+        assert_eq!(err.program_set_counter, 1);
+    }
+
+    #[test]
+    fn test_negative_jump_target() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0].content = -3;
+        test_vm.registers[1].content = 0;
+        test_vm.program = vec![6, 1, 0, 0];
+        let err = test_vm.run().unwrap_err();
+        assert_eq!(err.kind, VmErrorKind::InvalidJumpTarget(-3));
+
+        // LOAD r5 0, RJMP by i32::MAX from set 1 overflows the set index
+        test_vm.registers[0].content = i32::MAX;
+        test_vm.program = vec![1, 5, 0, 0,  6, 1, 0, 0];
+        test_vm.reset_program();
+        let err = test_vm.run().unwrap_err();
+        assert_eq!(err.kind, VmErrorKind::InvalidJumpTarget(i32::MAX));
+        assert_eq!(err.program_set_counter, 1);
+    }
+
+    #[test]
+    fn test_vmcall_print_to_stdout_stream() {
+        let output = SharedBuffer::default();
+        let mut test_vm = VM::new();
+        test_vm.set_stdout(output.clone());
+        test_vm.registers[10].content = 0; // print call
+        test_vm.registers[11].content = 1; // print mode with new line
+        test_vm.registers[12].content = 0; // print mode without new line
+        test_vm.registers[13].content = 411;
+        test_vm.registers[14].content = 55;
+        for i in 1..=5 {
+            test_vm.registers[i].content = 9 + i as i32; // refs
+        }
+        test_vm.program = vec![8, 1, 3, 5,  8, 1, 2, 4,  0, 0, 0, 0];
+        test_vm.run().unwrap();
+        assert_eq!(output.to_string_lossy(), "55411\n");
+    }
+
+    #[test]
+    fn test_break_uses_vm_streams() {
+        let output = SharedBuffer::default();
+        let mut test_vm = VM::with_program(vec![21, 0, 0, 0, 0, 0, 0, 0]);
+        test_vm.set_stdout(output.clone());
+        test_vm.set_stdin(&b"set_register r1 5\ncontinue\n"[..]);
+        assert_eq!(test_vm.run().unwrap(), 0);
+        assert_eq!(test_vm.registers[1].content, 5);
+        assert!(output.to_string_lossy().starts_with("hit BREAK on line:0\n"));
+
+        // quit ends the program at the BREAK, LOAD r1 1 never runs
+        let mut test_vm = VM::with_program(vec![21, 0, 0, 0, 1, 1, 0, 1]);
+        test_vm.set_stdout(SharedBuffer::default());
+        test_vm.set_stdin(&b"quit\n"[..]);
+        assert_eq!(test_vm.run().unwrap(), 0);
+        assert_eq!(test_vm.registers[1].content, 0);
+    }
+
+    #[test]
+    fn test_vm_runs_on_another_thread() {
+        let output = SharedBuffer::default();
+        let mut test_vm = VM::with_program(vec![8, 1, 2, 0]);
+        test_vm.set_stdout(output.clone());
+        test_vm.set_stdin(io::empty());
+        test_vm.register_call(20, |vm: &mut VM, arg1: i32, _| {
+            writeln!(vm.streams.out, "{}", arg1).unwrap();
+            return Ok(Step::Continue);
+        });
+        // VMCALL *r1 *r2, r1 points to r3 holding the call number and r2 to r4 holding 7
+        test_vm.registers[1].content = 3;
+        test_vm.registers[2].content = 4;
+        test_vm.registers[3].content = 20;
+        test_vm.registers[4].content = 7;
+        let exit_code = std::thread::spawn(move || test_vm.run()).join().unwrap();
+        assert_eq!(exit_code, Ok(0));
+        assert_eq!(output.to_string_lossy(), "7\n");
+    }
+
+    #[test]
+    fn test_load_program_file() {
+        let output = SharedBuffer::default();
+        let mut test_vm = VM::new();
+        test_vm.set_stdout(output.clone());
+        test_vm.load_program_file("examples/print.perling.bin").unwrap();
+        assert_eq!(test_vm.run(), Ok(0));
+        assert_eq!(output.to_string_lossy(), "411\n");
+
+        let error = test_vm.load_program_file("examples/missing.perling.bin").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_embedding_api() {
+        let mut test_vm = VM::with_program(vec![2, 2, 3, 4]);
+        test_vm.preload_register(0, 20, false).unwrap();
+        test_vm.preload_register(1, 22, true).unwrap();
+        test_vm.load_registers("2:0:0\n3:1:0\n4:4:0").unwrap();
+        assert!(test_vm.preload_register(32, 1, false).is_err());
+
+        assert_eq!(test_vm.run(), Ok(0));
+        assert_eq!(test_vm.register(4).unwrap().content, 42);
+        assert!(test_vm.register(1).unwrap().locked);
+        assert_eq!(test_vm.stack(), &[0]);
+        assert_eq!(test_vm.register(32), None);
+    }
+
+    #[test]
+    fn test_load_image() {
+        let image = Image {
+            code: vec![1, 0, 0, 9, 2, 2, 3, 4],
+            registers: vec![(1, REGISTER { content: 5, locked: true }), (3, REGISTER { content: 1, locked: false })],
+            entry: 1,
+            ..Image::default()
+        };
+        let mut test_vm = VM::new();
+        test_vm.load_image(&image).unwrap();
+        test_vm.registers[4].content = 4;
+        test_vm.run().unwrap();
+        // the LOAD before the entry point is skipped
+        assert_eq!(test_vm.registers[0].content, 0);
+        assert_eq!(test_vm.registers[4].content, 5);
+        assert!(test_vm.registers[1].locked);
+
+        let image = Image {
+            registers: vec![(40, REGISTER { content: 5, locked: false })],
+            ..Image::default()
+        };
+        assert!(VM::new().load_image(&image).is_err());
+    }
+
+    #[test]
+    fn test_div_by_zero_fault() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0].content = 7;
+        test_vm.registers[2].content = 0;
+        test_vm.registers[3].content = 1;
+        test_vm.program = vec![1, 5, 0, 0, 4, 2, 3, 4];
+        let err = test_vm.run().unwrap_err();
+        assert_eq!(err.kind, VmErrorKind::DivisionByZero);
+        assert_eq!(err.program_set_counter, 1);
+        assert_eq!(err.program_counter, 8);
+    }
+
+    #[test]
+    fn test_register_out_of_range_fault() {
+        let mut test_vm = VM::new();
+        test_vm.registers[2].content = 40;
+        test_vm.program = vec![2, 2, 2, 2];
+        let err = test_vm.run().unwrap_err();
+        assert_eq!(err.kind, VmErrorKind::RegisterOutOfRange(40));
+
+        let mut test_vm = VM::new();
+        test_vm.program = vec![1, 32, 0, 1];
+        let err = test_vm.run().unwrap_err();
+        assert_eq!(err.kind, VmErrorKind::RegisterOutOfRange(32));
+    }
+
+    #[test]
+    fn test_stack_underflow_fault() {
+        let mut test_vm = VM::new();
+        test_vm.stack.content.clear();
+        test_vm.registers[3].content = 1;
+        test_vm.program = vec![20, 3, 0, 0];
+        let err = test_vm.run().unwrap_err();
+        assert_eq!(err.kind, VmErrorKind::StackUnderflow);
+    }
+
+    #[test]
+    fn test_locked_register_fault() {
+        let mut test_vm = VM::new();
+        test_vm.registers[1].locked = true;
+        test_vm.program = vec![1, 1, 0, 5];
+        let err = test_vm.run().unwrap_err();
+        assert_eq!(err.kind, VmErrorKind::LockedRegister(1));
+        assert_eq!(test_vm.registers[1].content, 0);
+    }
+
+    #[test]
+    fn test_invalid_vmcall_fault() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0].content = 9;
+        test_vm.program = vec![8, 1, 1, 1];
+        let err = test_vm.run().unwrap_err();
+        assert_eq!(err.kind, VmErrorKind::InvalidVmCall(9));
+    }
+
+    #[test]
+    fn test_truncated_instruction_fault() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![1, 1, 0];
+        let err = test_vm.run().unwrap_err();
+        assert_eq!(err.kind, VmErrorKind::TruncatedInstruction);
+        assert_eq!(err.program_counter, 3);
+    }
+
+    #[test]
+    fn test_instruction_budget() {
+        let mut test_vm = VM::with_program(vec![5, 0, 0, 0]);
+        test_vm.set_limits(Limits {
+            max_instructions: Some(100),
+            ..Limits::default()
+        });
+        let err = test_vm.run().unwrap_err();
+        assert_eq!(err.kind, VmErrorKind::LimitExceeded(Limit::Instructions(100)));
+        assert!(err.is_limit_exceeded());
+        assert_eq!(test_vm.executed_instructions, 100);
+
+        // a program that finishes within its budget is not affected
+        let mut test_vm = VM::with_program(vec![1, 0, 1, 244]);
+        test_vm.set_limits(Limits {
+            max_instructions: Some(1),
+            ..Limits::default()
+        });
+        assert_eq!(test_vm.run(), Ok(0));
+    }
+
+    #[test]
+    fn test_time_limit() {
+        let mut test_vm = VM::with_program(vec![5, 0, 0, 0]);
+        test_vm.set_limits(Limits {
+            max_duration: Some(std::time::Duration::from_millis(20)),
+            ..Limits::default()
+        });
+        let err = test_vm.run().unwrap_err();
+        assert_eq!(
+            err.kind,
+            VmErrorKind::LimitExceeded(Limit::Duration(std::time::Duration::from_millis(20)))
+        );
+    }
+
+    #[test]
+    fn test_stack_depth_limit() {
+        let mut test_vm = VM::with_program(vec![19, 1, 0, 0, 5, 2, 0, 0]);
+        test_vm.registers[2].content = 3;
+        test_vm.set_limits(Limits {
+            max_stack_depth: Some(10),
+            ..Limits::default()
+        });
+        let err = test_vm.run().unwrap_err();
+        assert_eq!(err.kind, VmErrorKind::LimitExceeded(Limit::StackDepth(10)));
+        assert_eq!(test_vm.stack().len(), 11);
+    }
+
+    #[test]
+    fn test_loadw_opcode() {
+        let mut test_vm = VM::with_program(vec![
+            24, 1, 0, 0, 255, 255, 255, 251, // LOADW r1 -5
+            24, 2, 0, 0, 0x7F, 0, 0, 1, // LOADW r2 0x7F000001
+        ]);
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[1].content, -5);
+        assert_eq!(test_vm.registers[2].content, 0x7F00_0001);
+        assert_eq!(test_vm.program_set_counter, 4);
+    }
+
+    #[test]
+    fn test_loadw_value_is_not_a_label() {
+        // the value slot looks like LABEL 1 but must not be registered as one
+        let mut test_vm = VM::with_program(vec![24, 1, 0, 0, 22, 1, 0, 0, 23, 1, 0, 0]);
+        assert_eq!(test_vm.run().unwrap_err().kind, VmErrorKind::UnknownLabel(1));
+        assert_eq!(test_vm.registers[1].content, 0x1601_0000);
+    }
+
+    #[test]
+    fn test_arithmetic_modes() {
+        let program = vec![2, 2, 3, 4, 17, 5, 6, 0];
+        let mut test_vm = VM::with_program(program.clone());
+        test_vm.registers[0].content = i32::MAX;
+        test_vm.registers[1].content = 1;
+        test_vm.registers[2].content = 0;
+        test_vm.registers[3].content = 1;
+        test_vm.registers[4].content = 7;
+        test_vm.registers[5].content = 2;
+        test_vm.registers[6].content = 8;
+        let registers = test_vm.registers.clone();
+
+        // ADD r0 r1 into r7, then GET the flags into r8
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[7].content, i32::MIN);
+        assert_eq!(test_vm.registers[8].content, FLAG_NEGATIVE | FLAG_OVERFLOW);
+
+        let mut test_vm = VM::with_program(program.clone());
+        test_vm.registers = registers.clone();
+        test_vm.set_arithmetic_mode(ArithmeticMode::Saturating);
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[7].content, i32::MAX);
+        assert_eq!(test_vm.registers[8].content, FLAG_OVERFLOW);
+
+        let mut test_vm = VM::with_program(program);
+        test_vm.registers = registers;
+        test_vm.set_arithmetic_mode(ArithmeticMode::Trapping);
+        let err = test_vm.run().unwrap_err();
+        assert_eq!(err.kind, VmErrorKind::ArithmeticOverflow);
+        assert_eq!(test_vm.registers[7].content, 0);
+    }
+
+    #[test]
+    fn test_comparison_flags() {
+        // EQ r0 r1 into r4
+        let mut test_vm = VM::with_program(vec![9, 2, 3, 4]);
+        test_vm.registers[0].content = 3;
+        test_vm.registers[1].content = 5;
+        test_vm.registers[2].content = 0;
+        test_vm.registers[3].content = 1;
+        test_vm.registers[4].content = 6;
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[6].content, 0);
+        assert_eq!(test_vm.flags, FLAG_NEGATIVE | FLAG_CARRY);
+
+        test_vm.registers[1].content = 3;
+        test_vm.reset_program();
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.flags, FLAG_ZERO);
+    }
+
+    #[test]
+    fn test_ordered_comparison_opcodes() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0].content = -1;
+        test_vm.registers[1].content = 1;
+        test_vm.registers[2].content = 0;
+        test_vm.registers[3].content = 1;
+        for (code, expected) in [(38, 1), (39, 1), (40, 0), (41, 0), (42, 0), (43, 0), (44, 1), (45, 1)] {
+            test_vm.registers[4].content = 10;
+            test_vm.program = vec![code, 2, 3, 4];
+            test_vm.reset_program();
+            test_vm.run().unwrap();
+            assert_eq!(test_vm.registers[10].content, expected, "opcode {}", code);
+        }
+    }
+
+    #[test]
+    fn test_branch_opcodes() {
+        // BLT r0 r1 to set index 4, LOAD r5 1, HLT, LOAD r6 1
+        let program = vec![48, 2, 3, 0, 0, 0, 0, 4, 1, 5, 0, 1, 0, 0, 0, 0, 1, 6, 0, 1];
+        let mut test_vm = VM::with_program(program.clone());
+        test_vm.registers[0].content = 2;
+        test_vm.registers[1].content = 7;
+        test_vm.registers[2].content = 0;
+        test_vm.registers[3].content = 1;
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[5].content, 0);
+        assert_eq!(test_vm.registers[6].content, 1);
+
+        // a branch that is not taken continues after both of its slots
+        let mut test_vm = VM::with_program(program);
+        test_vm.registers[0].content = 7;
+        test_vm.registers[1].content = 2;
+        test_vm.registers[2].content = 0;
+        test_vm.registers[3].content = 1;
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[5].content, 1);
+        assert_eq!(test_vm.registers[6].content, 0);
+    }
+
+    #[test]
+    fn test_branch_to_label() {
+        // BEQ r0 r0 to label 4, LOAD r5 1, LABEL 4
+        let mut test_vm = VM::with_program(vec![46, 0, 0, 1, 0, 0, 0, 4, 1, 5, 0, 1, 22, 4, 0, 0]);
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[5].content, 0);
+
+        let mut test_vm = VM::with_program(vec![46, 0, 0, 7, 0, 0, 0, 4]);
+        assert_eq!(test_vm.run().unwrap_err().kind, VmErrorKind::InvalidTargetKind(7));
+    }
+
+    #[test]
+    fn test_call_and_ret_opcodes() {
+        // CALL 4, LOAD r1 1, HLT, LOAD r2 2, RET
+        let mut test_vm = VM::with_program(vec![
+            56, 0, 0, 0, 0, 0, 0, 4, 1, 1, 0, 1, 0, 0, 0, 0, 1, 2, 0, 2, 57, 0, 0, 0,
+        ]);
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[1].content, 1);
+        assert_eq!(test_vm.registers[2].content, 2);
+        assert!(test_vm.call_stack.is_empty());
+
+        let mut test_vm = VM::with_program(vec![57, 0, 0, 0]);
+        assert_eq!(test_vm.run().unwrap_err().kind, VmErrorKind::CallStackUnderflow);
+    }
+
+    #[test]
+    fn test_call_depth_limit() {
+        // CALL 0, recursing forever
+        let mut test_vm = VM::with_program(vec![56, 0, 0, 0, 0, 0, 0, 0]);
+        test_vm.set_limits(Limits {
+            max_call_depth: Some(8),
+            ..Limits::default()
+        });
+        let err = test_vm.run().unwrap_err();
+        assert_eq!(err.kind, VmErrorKind::LimitExceeded(Limit::CallDepth(8)));
+        assert_eq!(test_vm.call_stack.len(), 8);
+        assert_eq!(
+            test_vm.call_stack[0],
+            FRAME { call_location: 0, return_location: 2, target: 0 }
+        );
+    }
+
+    #[test]
+    fn test_memory_opcodes() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0].content = -2;
+        test_vm.registers[1].content = 100;
+        test_vm.registers[2].content = 0;
+        test_vm.registers[3].content = 1;
+        test_vm.registers[4].content = 10;
+        test_vm.registers[5].content = 11;
+        test_vm.registers[6].content = 12;
+        // ST32 r0 at r1, LD8, LD16 and LD32 at r1
+        test_vm.program = vec![63, 2, 3, 0, 58, 3, 4, 0, 59, 3, 5, 0, 60, 3, 6, 0];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.memory.content[100..104], [255, 255, 255, 254]);
+        assert_eq!(test_vm.registers[10].content, 0xFF);
+        assert_eq!(test_vm.registers[11].content, 0xFFFF);
+        assert_eq!(test_vm.registers[12].content, -2);
+
+        // ST8 and ST16 only write the lowest bytes
+        test_vm.registers[0].content = 0x1234;
+        test_vm.program = vec![61, 2, 3, 0, 62, 2, 3, 0];
+        test_vm.reset_program();
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.memory.content[100..104], [0x12, 0x34, 255, 254]);
+    }
+
+    #[test]
+    fn test_memory_out_of_bounds() {
+        let mut test_vm = VM::new();
+        test_vm.set_memory_size(16).unwrap();
+        test_vm.registers[0].content = 14;
+        test_vm.registers[1].content = 0;
+        test_vm.registers[2].content = 3;
+        test_vm.program = vec![60, 1, 2, 0];
+        let err = test_vm.run().unwrap_err();
+        assert_eq!(err.kind, VmErrorKind::MemoryOutOfBounds { address: 14, width: 4 });
+
+        test_vm.registers[0].content = -1;
+        test_vm.program = vec![61, 1, 1, 0];
+        test_vm.reset_program();
+        let err = test_vm.run().unwrap_err();
+        assert_eq!(err.kind, VmErrorKind::MemoryOutOfBounds { address: -1, width: 1 });
+    }
+
+    #[test]
+    fn test_load_image_data() {
+        let image = Image {
+            code: vec![0, 0, 0, 0],
+            data: vec![7, 8, 9],
+            ..Image::default()
+        };
+        let mut test_vm = VM::new();
+        test_vm.set_memory_size(2).unwrap();
+        test_vm.load_image(&image).unwrap();
+        assert_eq!(test_vm.memory.content, vec![7, 8, 9]);
+
+        let mut test_vm = VM::new();
+        test_vm.set_limits(Limits {
+            max_memory: Some(2),
+            ..Limits::default()
+        });
+        assert_eq!(
+            test_vm.set_memory_size(3).unwrap_err().kind,
+            VmErrorKind::LimitExceeded(Limit::Memory(2))
+        );
+        test_vm.set_memory_size(2).unwrap();
+        assert!(test_vm.load_image(&image).unwrap_err().is_limit_exceeded());
+
+        let image = Image { entry: -1, ..image };
+        assert_eq!(
+            VM::new().load_image(&image).unwrap_err().kind,
+            VmErrorKind::InvalidJumpTarget(-1)
+        );
+    }
+
+    #[test]
+    fn test_memory_limit_below_default_size() {
+        let image = Image {
+            code: vec![0, 0, 0, 0],
+            data: vec![7, 8, 9],
+            ..Image::default()
+        };
+        let mut test_vm = VM::new();
+        test_vm.set_limits(Limits {
+            max_memory: Some(1000),
+            ..Limits::default()
+        });
+        assert_eq!(test_vm.memory.content.len(), 1000);
+        test_vm.load_image(&image).unwrap();
+        assert_eq!(test_vm.memory.content[..3], [7, 8, 9]);
+    }
+
+    #[test]
+    fn test_addressing_modes() {
+        // LOAD r1 7, MODE 1 2 2 ADD 5 r1 r2, MODE 0 2 0 ST8 r4 r3
+        let mut test_vm = VM::with_program(vec![
+            1, 1, 0, 7, 64, 1, 2, 2, 2, 5, 1, 2, 64, 0, 2, 0, 61, 4, 3, 0,
+        ]);
+        test_vm.registers[3].content = 100;
+        test_vm.registers[4].content = 1;
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[2].content, 12);
+        // the value is read through r4 as before, the address is read from r3 itself
+        assert_eq!(test_vm.memory.content[100], 7);
+        // MODE counts as part of the instruction it prefixes, the last step reaches the end
+        assert_eq!(test_vm.executed_instructions, 4);
+
+        // immediate output operand
+        let mut test_vm = VM::with_program(vec![64, 0, 0, 1, 2, 1, 2, 3]);
+        let err = test_vm.run().unwrap_err();
+        assert_eq!(err.kind, VmErrorKind::InvalidAddressingMode(1));
+        assert_eq!(err.program_set_counter, 1);
+
+        // MODE in front of MODE
+        let mut test_vm = VM::with_program(vec![64, 0, 0, 0, 64, 0, 0, 0]);
+        assert_eq!(test_vm.run().unwrap_err().kind, VmErrorKind::IllegalOpcode(64));
+
+        // MODE at the end of the program
+        let mut test_vm = VM::with_program(vec![64, 1, 0, 0]);
+        assert_eq!(test_vm.run().unwrap_err().kind, VmErrorKind::TruncatedInstruction);
+    }
+
+    #[test]
+    fn test_register_count() {
+        // LOAD r200 5, LOAD r201 200, LOAD r202 201, ADD r201 r201 r202
+        let program = vec![1, 200, 0, 5, 1, 201, 0, 200, 1, 202, 0, 201, 2, 201, 201, 202];
+        let mut test_vm = VM::with_register_count(256);
+        test_vm.load_program(program.clone());
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[201].content, 10);
+
+        let mut test_vm = VM::with_register_count(8);
+        test_vm.load_program(program);
+        assert_eq!(test_vm.run().unwrap_err().kind, VmErrorKind::RegisterOutOfRange(200));
+        assert!(test_vm.load_registers("8:1:0").is_err());
+
+        let image = Image {
+            code: vec![0, 0, 0, 0],
+            registers: vec![(40, REGISTER { content: 3, locked: false })],
+            register_count: 64,
+            ..Image::default()
+        };
+        test_vm.load_image(&image).unwrap();
+        assert_eq!(test_vm.registers.len(), 64);
+        assert_eq!(test_vm.registers[40].content, 3);
+    }
 }
@@ -0,0 +1,465 @@
+use crate::error::{VmError, VmErrorKind};
+use crate::vm::{Step, VM};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+
+/// call number of the built in print call
+pub const PRINT: i32 = 0;
+
+/// call number of the built in exit call
+pub const EXIT: i32 = 1;
+
+/// call number of the built in read integer call
+pub const READ_INT: i32 = 2;
+
+/// call number of the built in read character call
+pub const READ_CHAR: i32 = 3;
+
+/// call number of the built in read line call
+pub const READ_LINE: i32 = 4;
+
+/// call number of the built in print string call
+pub const PRINT_STRING: i32 = 5;
+
+/// call number of the built in print character call
+pub const PRINT_CHAR: i32 = 6;
+
+/// call number of the built in format integer call
+pub const FORMAT_INT: i32 = 7;
+
+/// input_status after a value was read
+pub const INPUT_OK: i32 = 1;
+
+/// input_status after the input ended
+pub const INPUT_EOF: i32 = 0;
+
+/// input_status after input that could not be read as the requested type
+pub const INPUT_INVALID: i32 = -1;
+
+/// a function guest programs can run with VMCALL
+///
+/// The call gets the VM that executed the VMCALL along with its two arguments, and returns
+/// Step::Exit to stop the program. Closures taking the same arguments implement it as well.
+pub trait VmCall {
+    fn call(&mut self, vm: &mut VM, arg1: i32, arg2: i32) -> Result<Step, VmError>;
+}
+
+impl<F> VmCall for F
+where
+    F: FnMut(&mut VM, i32, i32) -> Result<Step, VmError>,
+{
+    fn call(&mut self, vm: &mut VM, arg1: i32, arg2: i32) -> Result<Step, VmError> {
+        return self(vm, arg1, arg2);
+    }
+}
+
+/// VM calls stored under their call number
+#[derive(Default)]
+pub struct VmCallTable {
+    calls: HashMap<i32, Box<dyn VmCall + Send>>,
+}
+
+impl VmCallTable {
+    /// creates a table holding the built in calls
+    pub fn with_builtins() -> VmCallTable {
+        let mut table = VmCallTable::default();
+        table.register(PRINT, print);
+        table.register(EXIT, exit);
+        table.register(READ_INT, read_int);
+        table.register(READ_CHAR, read_char);
+        table.register(READ_LINE, read_line);
+        table.register(PRINT_STRING, print_string);
+        table.register(PRINT_CHAR, print_char);
+        table.register(FORMAT_INT, format_int);
+        return table;
+    }
+
+    /// registers a call under a call number, returning the call it replaced
+    pub fn register<C: VmCall + Send + 'static>(
+        &mut self,
+        number: i32,
+        call: C,
+    ) -> Option<Box<dyn VmCall + Send>> {
+        return self.calls.insert(number, Box::new(call));
+    }
+
+    /// removes the call registered under a call number
+    pub fn unregister(&mut self, number: i32) -> Option<Box<dyn VmCall + Send>> {
+        return self.calls.remove(&number);
+    }
+
+    /// returns true if a call is registered under the call number
+    pub fn contains(&self, number: i32) -> bool {
+        return self.calls.contains_key(&number);
+    }
+
+    /// puts a call taken out with unregister back, unless it was replaced while it was out
+    pub(crate) fn restore(&mut self, number: i32, call: Box<dyn VmCall + Send>) {
+        self.calls.entry(number).or_insert(call);
+    }
+}
+
+impl fmt::Debug for VmCallTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut numbers: Vec<&i32> = self.calls.keys().collect();
+        numbers.sort();
+        f.debug_struct("VmCallTable").field("calls", &numbers).finish()
+    }
+}
+
+/// prints arg2, followed by a new line unless arg1 is 0
+fn print(vm: &mut VM, arg1: i32, arg2: i32) -> Result<Step, VmError> {
+    // print mode
+    let result = if arg1 == 0 {
+        write!(vm.streams.out, "{}", arg2)
+    } else {
+        writeln!(vm.streams.out, "{}", arg2)
+    };
+    if let Err(e) = result {
+        return Err(vm.fault(VmErrorKind::Io(e.to_string())));
+    }
+    return Ok(Step::Continue);
+}
+
+/// exits the program with arg2 as the exit code
+fn exit(_vm: &mut VM, _arg1: i32, arg2: i32) -> Result<Step, VmError> {
+    return Ok(Step::Exit(arg2));
+}
+
+/// prints arg2 bytes of memory starting at the address in arg1
+fn print_string(vm: &mut VM, arg1: i32, arg2: i32) -> Result<Step, VmError> {
+    let range = memory_range(vm, arg1, arg2)?;
+    if let Err(e) = vm.streams.out.write_all(&vm.memory.content[range]) {
+        return Err(io_fault(vm, e));
+    }
+    return Ok(Step::Continue);
+}
+
+/// prints the unicode character arg2, printing U+FFFD if it is not a valid code point
+fn print_char(vm: &mut VM, _arg1: i32, arg2: i32) -> Result<Step, VmError> {
+    let c = std::char::from_u32(arg2 as u32).unwrap_or(char::REPLACEMENT_CHARACTER);
+    if let Err(e) = write!(vm.streams.out, "{}", c) {
+        return Err(io_fault(vm, e));
+    }
+    return Ok(Step::Continue);
+}
+
+/// writes arg2 as decimal text to the memory at the address in arg1 and pushes its length
+fn format_int(vm: &mut VM, arg1: i32, arg2: i32) -> Result<Step, VmError> {
+    let text = arg2.to_string();
+    let range = memory_range(vm, arg1, text.len() as i32)?;
+    vm.memory.content[range].copy_from_slice(text.as_bytes());
+    vm.stack.content.push(text.len() as i32);
+    return Ok(Step::Continue);
+}
+
+/// returns the bytes of memory a call accesses, faulting if they are out of bounds
+fn memory_range(vm: &VM, address: i32, length: i32) -> Result<std::ops::Range<usize>, VmError> {
+    let fault = || {
+        let width = length.max(0) as usize;
+        vm.fault(VmErrorKind::MemoryOutOfBounds { address, width })
+    };
+    if address < 0 || length < 0 {
+        return Err(fault());
+    }
+    let (start, end) = (address as usize, address as usize + length as usize);
+    if end > vm.memory.content.len() {
+        return Err(fault());
+    }
+    return Ok(start..end);
+}
+
+/// turns a failed stream operation into a VmError
+fn io_fault(vm: &VM, e: io::Error) -> VmError {
+    return vm.fault(VmErrorKind::Io(e.to_string()));
+}
+
+/// reads the next whitespace separated integer and pushes it to the stack
+///
+/// pushes 0 at the end of the input or when the input is not an i32, input_status tells them
+/// apart. A new line right after the number is consumed as well.
+fn read_int(vm: &mut VM, _arg1: i32, _arg2: i32) -> Result<Step, VmError> {
+    let token = read_token(&mut vm.streams.input).map_err(|e| io_fault(vm, e))?;
+    let (value, status) = match token {
+        None => (0, INPUT_EOF),
+        Some(token) => match token.parse::<i32>() {
+            Ok(value) => (value, INPUT_OK),
+            Err(_) => (0, INPUT_INVALID),
+        },
+    };
+    vm.stack.content.push(value);
+    vm.input_status = status;
+    return Ok(Step::Continue);
+}
+
+/// reads a single character and pushes its unicode code point to the stack
+///
+/// pushes -1 at the end of the input and U+FFFD for invalid UTF-8.
+fn read_char(vm: &mut VM, _arg1: i32, _arg2: i32) -> Result<Step, VmError> {
+    let c = read_utf8_char(&mut vm.streams.input).map_err(|e| io_fault(vm, e))?;
+    let (value, status) = match c {
+        None => (-1, INPUT_EOF),
+        Some(Some(c)) => (c as i32, INPUT_OK),
+        Some(None) => (char::REPLACEMENT_CHARACTER as i32, INPUT_INVALID),
+    };
+    vm.stack.content.push(value);
+    vm.input_status = status;
+    return Ok(Step::Continue);
+}
+
+/// reads a line and pushes its characters followed by its length, so the length is popped
+/// first and the characters come off the stack in order
+///
+/// the line ending is not included, only the length 0 is pushed at the end of the input.
+fn read_line(vm: &mut VM, _arg1: i32, _arg2: i32) -> Result<Step, VmError> {
+    let mut line = vec![];
+    let read = vm.streams.input.read_until(b'\n', &mut line).map_err(|e| io_fault(vm, e))?;
+    if read == 0 {
+        vm.stack.content.push(0);
+        vm.input_status = INPUT_EOF;
+        return Ok(Step::Continue);
+    }
+    let line = String::from_utf8_lossy(&line);
+    let line = line.trim_end_matches('\n').trim_end_matches('\r');
+    let chars: Vec<char> = line.chars().collect();
+    for c in chars.iter().rev() {
+        vm.stack.content.push(*c as i32);
+    }
+    vm.stack.content.push(chars.len() as i32);
+    vm.input_status = INPUT_OK;
+    return Ok(Step::Continue);
+}
+
+/// reads the next whitespace separated token, None at the end of the input
+fn read_token(input: &mut dyn BufRead) -> io::Result<Option<String>> {
+    let mut token = vec![];
+    while let Some(&byte) = input.fill_buf()?.first() {
+        if byte.is_ascii_whitespace() && !token.is_empty() {
+            break;
+        }
+        if !byte.is_ascii_whitespace() {
+            token.push(byte);
+        }
+        input.consume(1);
+    }
+    if token.is_empty() {
+        return Ok(None);
+    }
+    // consume the rest of the line if the token ended it
+    if let Some(&b'\r') = input.fill_buf()?.first() {
+        input.consume(1);
+    }
+    if let Some(&b'\n') = input.fill_buf()?.first() {
+        input.consume(1);
+    }
+    return Ok(Some(String::from_utf8_lossy(&token).into_owned()));
+}
+
+/// reads a single UTF-8 encoded character, None at the end of the input and Some(None) for
+/// an invalid encoding
+fn read_utf8_char(input: &mut dyn BufRead) -> io::Result<Option<Option<char>>> {
+    let mut bytes = [0u8; 4];
+    let first = match input.fill_buf()?.first() {
+        Some(&byte) => byte,
+        None => return Ok(None),
+    };
+    input.consume(1);
+    bytes[0] = first;
+    let width = match first {
+        0x00..=0x7F => 1,
+        0xC0..=0xDF => 2,
+        0xE0..=0xEF => 3,
+        0xF0..=0xF7 => 4,
+        _ => return Ok(Some(None)),
+    };
+    for byte in bytes.iter_mut().take(width).skip(1) {
+        match input.fill_buf()?.first() {
+            Some(&next) if next & 0xC0 == 0x80 => {
+                *byte = next;
+                input.consume(1);
+            }
+            _ => return Ok(Some(None)),
+        }
+    }
+    return Ok(Some(std::str::from_utf8(&bytes[..width]).ok().and_then(|s| s.chars().next())));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::VmErrorKind;
+    use crate::streams::SharedBuffer;
+
+    struct Counter {
+        count: i32,
+    }
+
+    impl VmCall for Counter {
+        fn call(&mut self, vm: &mut VM, arg1: i32, _arg2: i32) -> Result<Step, VmError> {
+            self.count += arg1;
+            vm.stack.content.push(self.count);
+            return Ok(Step::Continue);
+        }
+    }
+
+    #[test]
+    fn test_builtin_calls() {
+        let table = VmCallTable::with_builtins();
+        assert!(table.contains(PRINT));
+        assert!(table.contains(EXIT));
+        assert!(table.contains(READ_INT));
+        assert!(table.contains(READ_CHAR));
+        assert!(table.contains(READ_LINE));
+        assert!(table.contains(PRINT_STRING));
+        assert!(table.contains(PRINT_CHAR));
+        assert!(table.contains(FORMAT_INT));
+        assert!(!table.contains(8));
+        assert_eq!(
+            format!("{:?}", table),
+            "VmCallTable { calls: [0, 1, 2, 3, 4, 5, 6, 7] }"
+        );
+    }
+
+    /// runs a single VMCALL of the given call number and returns the VM
+    fn run_call(number: i32, input: &str) -> VM {
+        let mut test_vm = VM::new();
+        test_vm.set_stdin(io::Cursor::new(input.as_bytes().to_vec()));
+        test_vm.stack.content.clear();
+        test_vm.registers[0].content = number;
+        test_vm.registers[1].content = 0;
+        test_vm.program = vec![8, 1, 1, 1];
+        test_vm.run().unwrap();
+        return test_vm;
+    }
+
+    #[test]
+    fn test_read_int_call() {
+        let test_vm = run_call(READ_INT, "  -42\nnext");
+        assert_eq!(test_vm.stack(), &[-42]);
+        assert_eq!(test_vm.input_status, INPUT_OK);
+
+        let test_vm = run_call(READ_INT, "abc\n");
+        assert_eq!(test_vm.stack(), &[0]);
+        assert_eq!(test_vm.input_status, INPUT_INVALID);
+
+        let test_vm = run_call(READ_INT, " \n");
+        assert_eq!(test_vm.stack(), &[0]);
+        assert_eq!(test_vm.input_status, INPUT_EOF);
+    }
+
+    #[test]
+    fn test_read_consecutive_values() {
+        let mut test_vm = VM::new();
+        test_vm.set_stdin(io::Cursor::new(b"12 7\nhi\n".to_vec()));
+        test_vm.stack.content.clear();
+        test_vm.registers[0].content = READ_INT;
+        test_vm.registers[1].content = 0;
+        test_vm.registers[2].content = READ_LINE;
+        test_vm.registers[3].content = 2;
+        test_vm.program = vec![8, 1, 1, 1,  8, 1, 1, 1,  8, 3, 1, 1];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.stack(), &[12, 7, 'i' as i32, 'h' as i32, 2]);
+    }
+
+    #[test]
+    fn test_read_char_call() {
+        let test_vm = run_call(READ_CHAR, "é!");
+        assert_eq!(test_vm.stack(), &['é' as i32]);
+        assert_eq!(test_vm.input_status, INPUT_OK);
+
+        let test_vm = run_call(READ_CHAR, "");
+        assert_eq!(test_vm.stack(), &[-1]);
+        assert_eq!(test_vm.input_status, INPUT_EOF);
+
+        let mut test_vm = VM::new();
+        test_vm.set_stdin(io::Cursor::new(vec![0xC3, b'a']));
+        assert_eq!(read_char(&mut test_vm, 0, 0), Ok(Step::Continue));
+        assert_eq!(test_vm.stack.content.pop(), Some(0xFFFD));
+        assert_eq!(test_vm.input_status, INPUT_INVALID);
+    }
+
+    #[test]
+    fn test_read_line_call() {
+        let test_vm = run_call(READ_LINE, "ab\r\ncd");
+        assert_eq!(test_vm.stack(), &['b' as i32, 'a' as i32, 2]);
+        assert_eq!(test_vm.input_status, INPUT_OK);
+
+        let test_vm = run_call(READ_LINE, "");
+        assert_eq!(test_vm.stack(), &[0]);
+        assert_eq!(test_vm.input_status, INPUT_EOF);
+    }
+
+    #[test]
+    fn test_string_calls() {
+        let output = SharedBuffer::default();
+        let mut test_vm = VM::new();
+        test_vm.set_stdout(output.clone());
+        test_vm.memory.content[10..15].copy_from_slice(b"hello");
+
+        assert_eq!(print_string(&mut test_vm, 10, 5), Ok(Step::Continue));
+        assert_eq!(print_char(&mut test_vm, 0, 'é' as i32), Ok(Step::Continue));
+        assert_eq!(print_char(&mut test_vm, 0, -1), Ok(Step::Continue));
+        assert_eq!(output.to_string_lossy(), "helloé\u{FFFD}");
+
+        let err = print_string(&mut test_vm, 65530, 10).unwrap_err();
+        assert_eq!(err.kind, VmErrorKind::MemoryOutOfBounds { address: 65530, width: 10 });
+    }
+
+    #[test]
+    fn test_format_int_call() {
+        let mut test_vm = VM::new();
+        test_vm.stack.content.clear();
+        assert_eq!(format_int(&mut test_vm, 4, -1250), Ok(Step::Continue));
+        assert_eq!(&test_vm.memory.content[4..9], b"-1250");
+        assert_eq!(test_vm.stack(), &[5]);
+
+        let err = format_int(&mut test_vm, 65534, 100).unwrap_err();
+        assert_eq!(err.kind, VmErrorKind::MemoryOutOfBounds { address: 65534, width: 3 });
+    }
+
+    #[test]
+    fn test_registered_closure() {
+        let mut test_vm = VM::new();
+        test_vm.register_call(7, |vm: &mut VM, arg1: i32, arg2: i32| {
+            vm.registers[10].content = arg1 * arg2;
+            return Ok(Step::Continue);
+        });
+        test_vm.registers[1].content = 7; // call number
+        test_vm.registers[2].content = 6; // arg1
+        test_vm.registers[3].content = 1; // call number ref
+        test_vm.registers[4].content = 2; // arg1 ref
+        test_vm.registers[6].content = 7; // arg2
+        test_vm.registers[5].content = 6; // arg2 ref
+        test_vm.program = vec![8, 3, 4, 5];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[10].content, 42);
+    }
+
+    #[test]
+    fn test_registered_trait_object_keeps_state() {
+        let mut test_vm = VM::new();
+        test_vm.register_call(9, Counter { count: 0 });
+        test_vm.registers[0].content = 9;
+        test_vm.registers[1].content = 0;
+        test_vm.registers[2].content = 3;
+        test_vm.registers[3].content = 2;
+        test_vm.program = vec![8, 1, 3, 1,  8, 1, 3, 1];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.stack(), &[0, 3, 6]);
+    }
+
+    #[test]
+    fn test_replaced_and_unknown_calls() {
+        let mut test_vm = VM::new();
+        test_vm.register_call(EXIT, |_vm: &mut VM, _arg1: i32, _arg2: i32| Ok(Step::Exit(99)));
+        test_vm.registers[0].content = EXIT;
+        test_vm.program = vec![8, 1, 1, 1];
+        assert_eq!(test_vm.run(), Ok(99));
+
+        test_vm.calls.unregister(EXIT);
+        test_vm.reset_program();
+        let err = test_vm.run().unwrap_err();
+        assert_eq!(err.kind, VmErrorKind::InvalidVmCall(EXIT));
+    }
+}
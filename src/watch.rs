@@ -0,0 +1,315 @@
+use crate::vm::VM;
+use std::fmt;
+use std::str::FromStr;
+
+/// a piece of VM state a watchpoint follows
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WatchTarget {
+    /// content of a register
+    Register(usize),
+
+    /// lock state of a register
+    Lock(usize),
+
+    /// value of 1, 2 or 4 bytes of memory
+    Memory { address: i32, width: usize },
+
+    /// number of values on the stack
+    StackDepth,
+}
+
+impl WatchTarget {
+    /// parses a watch target, `r3`, `lock r3`, `mem <address> [width]` or `stack`
+    pub fn parse(words: &[&str], vm: &VM) -> Result<WatchTarget, String> {
+        let target = match words {
+            ["stack"] => WatchTarget::StackDepth,
+            ["lock", register] => WatchTarget::Lock(parse_register(register, vm)?),
+            ["mem", address] | ["mem", address, _] => {
+                let width = match words.get(2) {
+                    Some(width) => match width.parse::<usize>() {
+                        Ok(width @ (1 | 2 | 4)) => width,
+                        _ => return Err(format!("invalid width `{}`, expected 1, 2 or 4", width)),
+                    },
+                    None => 4,
+                };
+                let address = parse_value(address)?;
+                WatchTarget::Memory { address, width }
+            }
+            [register] => WatchTarget::Register(parse_register(register, vm)?),
+            _ => return Err("usage: watch <r3|lock r3|mem address [width]|stack>".to_string()),
+        };
+        if target.value(vm).is_none() {
+            return Err(format!("{} is out of bounds", target));
+        }
+        return Ok(target);
+    }
+
+    /// returns the current value of the target, None if it is out of bounds
+    pub fn value(&self, vm: &VM) -> Option<i32> {
+        match *self {
+            WatchTarget::Register(index) => return vm.registers.get(index).map(|r| r.content),
+            WatchTarget::Lock(index) => return vm.registers.get(index).map(|r| r.locked as i32),
+            WatchTarget::Memory { address, width } => return vm.memory.load(address, width),
+            WatchTarget::StackDepth => return Some(vm.stack.content.len() as i32),
+        }
+    }
+}
+
+impl fmt::Display for WatchTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WatchTarget::Register(index) => write!(f, "r{}", index),
+            WatchTarget::Lock(index) => write!(f, "lock r{}", index),
+            WatchTarget::Memory { address, width } => write!(f, "mem {} {}", address, width),
+            WatchTarget::StackDepth => write!(f, "stack"),
+        }
+    }
+}
+
+/// stops execution when the value of its target changes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Watchpoint {
+    pub id: usize,
+    pub target: WatchTarget,
+
+    /// value of the target when it was last checked
+    pub value: Option<i32>,
+}
+
+impl Watchpoint {
+    /// returns the old and new value when the target changed since the last check
+    pub fn check(&mut self, vm: &VM) -> Option<(Option<i32>, Option<i32>)> {
+        let value = self.target.value(vm);
+        if value == self.value {
+            return None;
+        }
+        let old = self.value;
+        self.value = value;
+        return Some((old, value));
+    }
+}
+
+/// a value a condition compares
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConditionValue {
+    Register(usize),
+
+    /// h0 (remainder), h1 (input status) or h2 (flags)
+    Hidden(u8),
+
+    Constant(i32),
+}
+
+impl ConditionValue {
+    fn parse(text: &str, vm: &VM) -> Result<ConditionValue, String> {
+        if let Some(index) = text.strip_prefix('h') {
+            match index.parse::<u8>() {
+                Ok(index @ 0..=2) => return Ok(ConditionValue::Hidden(index)),
+                _ => return Err(format!("there is no hidden register {}", text)),
+            }
+        }
+        if text.starts_with('r') {
+            return Ok(ConditionValue::Register(parse_register(text, vm)?));
+        }
+        return Ok(ConditionValue::Constant(parse_value(text)?));
+    }
+
+    /// returns the value, None when the register does not exist
+    fn value(&self, vm: &VM) -> Option<i32> {
+        match *self {
+            ConditionValue::Register(index) => return vm.registers.get(index).map(|r| r.content),
+            ConditionValue::Hidden(0) => return Some(vm.remainder),
+            ConditionValue::Hidden(1) => return Some(vm.input_status),
+            ConditionValue::Hidden(_) => return Some(vm.flags),
+            ConditionValue::Constant(value) => return Some(value),
+        }
+    }
+}
+
+impl fmt::Display for ConditionValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConditionValue::Register(index) => write!(f, "r{}", index),
+            ConditionValue::Hidden(index) => write!(f, "h{}", index),
+            ConditionValue::Constant(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+/// how a condition compares its values, as signed numbers
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+impl Comparison {
+    /// compares left to right
+    pub fn compare(&self, left: i32, right: i32) -> bool {
+        match self {
+            Comparison::Equal => return left == right,
+            Comparison::NotEqual => return left != right,
+            Comparison::Less => return left < right,
+            Comparison::LessOrEqual => return left <= right,
+            Comparison::Greater => return left > right,
+            Comparison::GreaterOrEqual => return left >= right,
+        }
+    }
+}
+
+impl FromStr for Comparison {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "==" => return Ok(Comparison::Equal),
+            "!=" => return Ok(Comparison::NotEqual),
+            "<" => return Ok(Comparison::Less),
+            "<=" => return Ok(Comparison::LessOrEqual),
+            ">" => return Ok(Comparison::Greater),
+            ">=" => return Ok(Comparison::GreaterOrEqual),
+            _ => return Err(format!("unknown comparison `{}`", s)),
+        }
+    }
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Comparison::Equal => write!(f, "=="),
+            Comparison::NotEqual => write!(f, "!="),
+            Comparison::Less => write!(f, "<"),
+            Comparison::LessOrEqual => write!(f, "<="),
+            Comparison::Greater => write!(f, ">"),
+            Comparison::GreaterOrEqual => write!(f, ">="),
+        }
+    }
+}
+
+/// a comparison of two values, e.g. `r3 == 10`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Condition {
+    pub left: ConditionValue,
+    pub comparison: Comparison,
+    pub right: ConditionValue,
+}
+
+impl Condition {
+    /// parses a condition written as `<value> <comparison> <value>`, values are registers,
+    /// hidden registers (`h0`) or numbers
+    pub fn parse(words: &[&str], vm: &VM) -> Result<Condition, String> {
+        let (left, comparison, right) = match words {
+            [left, comparison, right] => (left, comparison, right),
+            _ => return Err("expected a condition such as `r3 == 10`".to_string()),
+        };
+        return Ok(Condition {
+            left: ConditionValue::parse(left, vm)?,
+            comparison: comparison.parse()?,
+            right: ConditionValue::parse(right, vm)?,
+        });
+    }
+
+    /// evaluates the condition, it is false when one of its registers does not exist
+    pub fn holds(&self, vm: &VM) -> bool {
+        let (left, right) = match (self.left.value(vm), self.right.value(vm)) {
+            (Some(left), Some(right)) => (left, right),
+            _ => return false,
+        };
+        return self.comparison.compare(left, right);
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {}", self.left, self.comparison, self.right)
+    }
+}
+
+/// parses a register written as `r3` or `3`, checking that it exists
+pub fn parse_register(text: &str, vm: &VM) -> Result<usize, String> {
+    let index = text.strip_prefix('r').unwrap_or(text);
+    match index.parse::<usize>() {
+        Ok(index) if index < vm.registers.len() => return Ok(index),
+        Ok(_) => return Err(format!("there is no register {}", text)),
+        Err(_) => return Err(format!("invalid register `{}`", text)),
+    }
+}
+
+/// parses a decimal or `0x` hexadecimal value
+pub fn parse_value(text: &str) -> Result<i32, String> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let value = match digits.strip_prefix("0x") {
+        Some(hex) => i64::from_str_radix(hex, 16),
+        None => digits.parse::<i64>(),
+    };
+    match value.map(|value| if negative { -value } else { value }) {
+        // values up to u32::MAX are accepted so 0xFFFFFFFF can be written for -1
+        Ok(value) if (i32::MIN as i64..=u32::MAX as i64).contains(&value) => {
+            return Ok(value as i32)
+        }
+        _ => return Err(format!("invalid value `{}`", text)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_condition() {
+        let mut vm = VM::new();
+        vm.registers[3].content = 10;
+        vm.remainder = -1;
+        let condition = Condition::parse(&["r3", "==", "10"], &vm).unwrap();
+        assert!(condition.holds(&vm));
+        assert_eq!(condition.to_string(), "r3 == 10");
+        assert!(Condition::parse(&["h0", "<", "r3"], &vm).unwrap().holds(&vm));
+        assert!(Condition::parse(&["r3", ">=", "0xFFFFFFFF"], &vm).unwrap().holds(&vm));
+
+        assert_eq!(
+            Condition::parse(&["r3", "=", "10"], &vm),
+            Err("unknown comparison `=`".to_string())
+        );
+        assert_eq!(
+            Condition::parse(&["r99", "==", "1"], &vm),
+            Err("there is no register r99".to_string())
+        );
+        assert!(Condition::parse(&["r3"], &vm).is_err());
+    }
+
+    #[test]
+    fn test_comparison() {
+        for text in ["==", "!=", "<", "<=", ">", ">="] {
+            assert_eq!(text.parse::<Comparison>().unwrap().to_string(), text);
+        }
+        assert_eq!("=>".parse::<Comparison>(), Err("unknown comparison `=>`".to_string()));
+        assert!(Comparison::LessOrEqual.compare(-1, -1));
+        assert!(!Comparison::Greater.compare(-1, 0));
+    }
+
+    #[test]
+    fn test_watchpoint() {
+        let mut vm = VM::new();
+        let target = WatchTarget::parse(&["mem", "2", "2"], &vm).unwrap();
+        let mut watchpoint = Watchpoint { id: 1, target, value: target.value(&vm) };
+        assert_eq!(watchpoint.check(&vm), None);
+        vm.memory.store(3, 1, 7);
+        assert_eq!(watchpoint.check(&vm), Some((Some(0), Some(7))));
+        assert_eq!(watchpoint.check(&vm), None);
+
+        assert_eq!(WatchTarget::parse(&["lock", "r2"], &vm), Ok(WatchTarget::Lock(2)));
+        assert_eq!(WatchTarget::parse(&["stack"], &vm), Ok(WatchTarget::StackDepth));
+        assert_eq!(
+            WatchTarget::parse(&["mem", "70000"], &vm),
+            Err("mem 70000 4 is out of bounds".to_string())
+        );
+        assert!(WatchTarget::parse(&["mem", "0", "3"], &vm).is_err());
+    }
+}